@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::{AsyncRead, AsyncWrite, StreamExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::identity::{ed25519, Keypair};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::{SwarmBuilder, SwarmEvent};
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::iter;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::api::{self, Reply};
+
+// The admin channel lets an operator instruct a fleet of nodes over libp2p rather than
+// through the HTTP admin API of every host. Connections are authenticated by the keys of
+// both ends, so a node knows which peer sent a request, and it only acts on requests sent
+// by the operator keys listed in its config.
+
+// MAX_MESSAGE_SIZE bounds the size of an admin request or response.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// AdminProtocol is the request-response protocol operators send admin commands with.
+#[derive(Debug, Clone)]
+pub struct AdminProtocol;
+
+impl ProtocolName for AdminProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mchain/admin/1"
+    }
+}
+
+// AdminRequest is a command for a node, the counterpart of an HTTP admin endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Resync {
+        peer: String,
+    },
+    Ban {
+        peer: String,
+        duration_secs: Option<u64>,
+        reason: Option<String>,
+    },
+    Unban {
+        peer: String,
+    },
+    SetMining {
+        enabled: bool,
+    },
+    ReloadConfig,
+    RotateLogs,
+    RotateKeys,
+}
+
+impl AdminRequest {
+    // into_api turns the command into the request the HTTP admin API would have made of the
+    // event loop, whose outcome is sent to `reply`.
+    pub fn into_api(self, reply: Reply) -> Result<api::Request, String> {
+        let peer = |peer: String| -> Result<PeerId, String> {
+            peer.parse()
+                .map_err(|e| format!("invalid peer id {:?}: {}", peer, e))
+        };
+        Ok(match self {
+            AdminRequest::Resync { peer: p } => api::Request::Resync(peer(p)?, reply),
+            AdminRequest::Ban {
+                peer: p,
+                duration_secs,
+                reason,
+            } => api::Request::Ban(
+                peer(p)?,
+                duration_secs.map(Duration::from_secs),
+                reason.unwrap_or_else(|| "banned by operator".to_string()),
+                reply,
+            ),
+            AdminRequest::Unban { peer: p } => api::Request::Unban(peer(p)?, reply),
+            AdminRequest::SetMining { enabled } => api::Request::SetMining(enabled, reply),
+            AdminRequest::ReloadConfig => api::Request::ReloadConfig(reply),
+            AdminRequest::RotateLogs => api::Request::RotateLogs(reply),
+            AdminRequest::RotateKeys => api::Request::RotateKeys(reply),
+        })
+    }
+}
+
+// AdminResponse is the outcome of a command: Ok, or why it was refused or failed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminResponse(pub Result<(), String>);
+
+// Pending is a command queued on the event loop. It resolves to the response for the
+// operator once the command was carried out.
+pub type Pending = BoxFuture<'static, (ResponseChannel<AdminResponse>, AdminResponse)>;
+
+// pending waits for the outcome of a command queued with the counterpart of `answer`.
+pub fn pending(
+    channel: ResponseChannel<AdminResponse>,
+    answer: oneshot::Receiver<Result<(), String>>,
+) -> Pending {
+    Box::pin(async move {
+        let result = answer
+            .await
+            .unwrap_or_else(|_| Err("the command was dropped".to_string()));
+        (channel, AdminResponse(result))
+    })
+}
+
+// AdminCodec writes requests and responses as length prefixed JSON.
+#[derive(Debug, Clone, Default)]
+pub struct AdminCodec;
+
+#[async_trait]
+impl RequestResponseCodec for AdminCodec {
+    type Protocol = AdminProtocol;
+    type Request = AdminRequest;
+    type Response = AdminResponse;
+
+    async fn read_request<T>(&mut self, _: &AdminProtocol, io: &mut T) -> io::Result<AdminRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &AdminProtocol, io: &mut T) -> io::Result<AdminResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &AdminProtocol,
+        io: &mut T,
+        request: AdminRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&request)?).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &AdminProtocol,
+        io: &mut T,
+        response: AdminResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, serde_json::to_vec(&response)?).await
+    }
+}
+
+// behaviour answers the admin requests of operators on a node.
+pub fn behaviour() -> RequestResponse<AdminCodec> {
+    RequestResponse::new(
+        AdminCodec,
+        iter::once((AdminProtocol, ProtocolSupport::Inbound)),
+        RequestResponseConfig::default(),
+    )
+}
+
+// load_key reads the operator key kept hex encoded at `path`, creating a new one there if
+// there is none yet. Anyone holding the key can instruct the nodes that trust it.
+pub fn load_key(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let mut secret = hex::decode(fs::read_to_string(path)?.trim())?;
+        let secret = ed25519::SecretKey::from_bytes(&mut secret)?;
+        return Ok(Keypair::Ed25519(secret.into()));
+    }
+    let keypair = ed25519::Keypair::generate();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}", hex::encode(keypair.secret()))?;
+    log::info!("Created a new operator key in {}", path.display());
+    Ok(Keypair::Ed25519(keypair))
+}
+
+// send instructs each node, one at a time, with the request signed by the operator key,
+// returning the outcome for every node.
+pub async fn send(
+    keys: Keypair,
+    nodes: &[Multiaddr],
+    request: AdminRequest,
+) -> Result<Vec<Result<(), String>>, Box<dyn Error>> {
+    let peer_id = PeerId::from(keys.public());
+    let transport = libp2p::development_transport(keys).await?;
+    let behaviour = RequestResponse::new(
+        AdminCodec,
+        iter::once((AdminProtocol, ProtocolSupport::Outbound)),
+        RequestResponseConfig::default(),
+    );
+    let mut swarm = SwarmBuilder::new(transport, behaviour, peer_id).build();
+
+    let mut outcomes = vec![];
+    for node in nodes {
+        if let Err(e) = swarm.dial(node.clone()) {
+            outcomes.push(Err(e.to_string()));
+            continue;
+        }
+        // Events are matched to the node being instructed, as those of the previous one, e.g.
+        // its connection closing, may still come in.
+        let (mut connected, mut sent) = (None, None);
+        let outcome = loop {
+            match swarm.select_next_some().await {
+                SwarmEvent::ConnectionEstablished { peer_id, .. } if connected.is_none() => {
+                    connected = Some(peer_id);
+                    sent = Some(
+                        swarm
+                            .behaviour_mut()
+                            .send_request(&peer_id, request.clone()),
+                    );
+                }
+                SwarmEvent::OutgoingConnectionError { error, .. } if connected.is_none() => {
+                    break Err(error.to_string())
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    ..
+                } if connected == Some(peer_id) => {
+                    break Err("the node closed the connection without answering".to_string())
+                }
+                SwarmEvent::Behaviour(RequestResponseEvent::Message {
+                    peer,
+                    message:
+                        RequestResponseMessage::Response {
+                            request_id,
+                            response,
+                        },
+                }) if sent == Some(request_id) => {
+                    let _ = swarm.disconnect_peer_id(peer);
+                    break response.0;
+                }
+                SwarmEvent::Behaviour(RequestResponseEvent::OutboundFailure {
+                    peer,
+                    request_id,
+                    error,
+                }) if sent == Some(request_id) => {
+                    let _ = swarm.disconnect_peer_id(peer);
+                    break Err(error.to_string());
+                }
+                _ => {}
+            }
+        };
+        outcomes.push(outcome);
+    }
+    Ok(outcomes)
+}
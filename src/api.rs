@@ -0,0 +1,810 @@
+use async_std::channel::Sender;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tide::listener::Listener;
+use tide::{Body, Response, StatusCode};
+
+use crate::app::Block;
+use crate::backfill;
+use crate::cid;
+use crate::events;
+use crate::fees::FeeEstimate;
+use crate::fork::{HeldReorg, StaleBlock};
+use crate::history::ChatEntry;
+use crate::mempool;
+use crate::metrics;
+use crate::miner::Template;
+use crate::names::NameRecord;
+use crate::notary::Proof;
+use crate::pages::{self, Cursor, Page, TransactionEntry};
+use crate::payload::{FileManifest, Payload};
+use crate::peers::Ban;
+use crate::receipts::Receipt;
+use crate::rejects::{self, Reject};
+use crate::rpc;
+use crate::snapshot;
+use crate::telemetry::{Kind, Span, SpanContext};
+use crate::versionbits;
+
+// API_VERSION prefixes the path of every endpoint but /metrics.
+pub const API_VERSION: &str = "/v1";
+
+// Reply carries the outcome of an admin action back to the API, with a reason on failure.
+pub type Reply = oneshot::Sender<Result<(), String>>;
+
+// Request is a query the HTTP API forwards to the main event loop, which owns the node
+// state and answers through the enclosed reply channel.
+pub enum Request {
+    StaleBlocks(oneshot::Sender<Vec<StaleBlock>>),
+    State(oneshot::Sender<NodeState>),
+    Health(oneshot::Sender<Health>),
+    Template(oneshot::Sender<Template>),
+    Fees(oneshot::Sender<FeeEstimate>),
+    History(
+        HistoryQuery,
+        oneshot::Sender<Result<Vec<ChatEntry>, String>>,
+    ),
+    // Listings read through the snapshot with the given id, if any, and answer None when it
+    // expired.
+    Blocks(
+        Cursor,
+        usize,
+        Option<String>,
+        oneshot::Sender<Result<Option<Page<Block>>, String>>,
+    ),
+    Transactions(
+        Cursor,
+        usize,
+        Option<String>,
+        oneshot::Sender<Result<Option<Page<TransactionEntry>>, String>>,
+    ),
+    OpenSnapshot(oneshot::Sender<Result<snapshot::Pin, String>>),
+    CloseSnapshot(String, oneshot::Sender<bool>),
+    Submit(
+        Payload,
+        SubmitQuery,
+        Option<String>,
+        oneshot::Sender<Result<Submitted, String>>,
+    ),
+    SubmitBatch(
+        Vec<BatchItem>,
+        oneshot::Sender<Result<BatchSubmitted, String>>,
+    ),
+    Receipt(String, oneshot::Sender<Option<Receipt>>),
+    ResolveName(
+        String,
+        Confirmations,
+        oneshot::Sender<Result<Option<NameRecord>, String>>,
+    ),
+    Prove(
+        String,
+        Confirmations,
+        oneshot::Sender<Result<Option<Proof>, String>>,
+    ),
+    PutChunk(Bytes, oneshot::Sender<Result<String, String>>),
+    GetChunk(String, oneshot::Sender<Result<Option<Bytes>, String>>),
+    Manifest(
+        String,
+        Confirmations,
+        oneshot::Sender<Result<Option<FileManifest>, String>>,
+    ),
+    Resync(PeerId, Reply),
+    Dial(Multiaddr, Reply),
+    Disconnect(Multiaddr, Reply),
+    SetMining(bool, Reply),
+    RotateLogs(Reply),
+    RotateKeys(Reply),
+    ReloadConfig(Reply),
+    Ban(PeerId, Option<Duration>, String, Reply),
+    Unban(PeerId, Reply),
+    Bans(oneshot::Sender<Vec<Ban>>),
+    Rejects(rejects::Query, oneshot::Sender<Result<Vec<Reject>, String>>),
+    HeldReorg(oneshot::Sender<Option<HeldReorg>>),
+    AcceptReorg(Reply),
+    Deployments(oneshot::Sender<Vec<versionbits::Status>>),
+    Rpc(Vec<rpc::Call>, oneshot::Sender<Vec<rpc::Response>>),
+}
+
+// NodeState is a snapshot of the node's internals returned by the admin state dump.
+#[derive(Debug, Serialize)]
+pub struct NodeState {
+    pub peer_id: String,
+    pub chain_id: String,
+    pub height: usize,
+    pub tip: Option<String>,
+    pub mining: bool,
+    pub observer: bool,
+    pub archival: bool,
+    // degraded is set while the tip is stale although peers are ahead.
+    pub degraded: bool,
+    // clock_skew_secs is how far our clock is estimated to be ahead of our peers'.
+    pub clock_skew_secs: Option<i64>,
+    pub listen_addrs: Vec<String>,
+    pub peers: Vec<PeerState>,
+    pub sync_in_flight: usize,
+    pub sync_downloaded: usize,
+    pub stale_blocks: usize,
+    pub mempool_size: usize,
+    pub backfill: backfill::Report,
+}
+
+// Health is what GET /v1/health reports: whether the node is fit to serve, and how the
+// repair of gaps in its stored chain is going.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: events::Health,
+    pub height: usize,
+    pub backfill: backfill::Report,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerState {
+    pub peer_id: String,
+    pub address: Option<String>,
+    pub rtt_ms: Option<u128>,
+    pub score: i64,
+    pub height: Option<usize>,
+    pub archival: bool,
+}
+
+// HistoryQuery selects the chat messages returned by /history.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HistoryQuery {
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    // since is a unix timestamp; older messages are left out.
+    #[serde(default)]
+    pub since: i64,
+    // min_confirmations leaves out messages in the most recent blocks; see Confirmations.
+    #[serde(default)]
+    pub min_confirmations: usize,
+}
+
+// Confirmations restricts a query to the blocks with at least `min_confirmations`
+// confirmations, so callers can choose how deep a reorg they guard against. The default
+// includes the tip.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Confirmations {
+    #[serde(default)]
+    pub min_confirmations: usize,
+}
+
+fn default_topic() -> String {
+    "chat".to_string()
+}
+
+// PageQuery selects a page of a listing: the first one unless a cursor from a previous page
+// is given.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PageQuery {
+    pub cursor: Option<String>,
+    #[serde(default = "default_page_size")]
+    pub limit: usize,
+    // snapshot is the id of a snapshot to read through, so that every page comes from the
+    // same chain however it changes in between; see POST /v1/snapshots.
+    pub snapshot: Option<String>,
+}
+
+fn default_page_size() -> usize {
+    pages::MAX_PAGE_SIZE
+}
+
+impl PageQuery {
+    fn cursor(&self) -> Result<Cursor, String> {
+        self.cursor
+            .as_deref()
+            .map_or(Ok(Cursor::default()), Cursor::decode)
+    }
+}
+
+// SubmitQuery sets the fee of a posted transaction. Giving the nonce of a pending transaction
+// replaces it, provided the fee is sufficiently higher.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SubmitQuery {
+    #[serde(default)]
+    pub fee: u64,
+    pub nonce: Option<u64>,
+}
+
+// CORRELATION_HEADER carries the correlation id of a submission. Its receipt is kept under
+// that id, and it tags every log line about the transaction; the transaction id is used
+// when none is given.
+pub const CORRELATION_HEADER: &str = "X-Correlation-Id";
+
+// Submitted is returned for a transaction accepted into the mempool.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Submitted {
+    pub id: String,
+    pub correlation_id: String,
+}
+
+// BatchItem is a payload of a batch submission, with the fee of its transaction and the
+// correlation id its receipt is kept under, which defaults to the transaction id.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchItem {
+    pub payload: Payload,
+    #[serde(default)]
+    pub fee: u64,
+    pub correlation_id: Option<String>,
+}
+
+// BatchSubmitted is returned for a batch submission. Either every transaction of the batch
+// was accepted into the mempool, or none was and `error` says why for those that were
+// refused.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchSubmitted {
+    pub admitted: bool,
+    pub items: Vec<BatchItemResult>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BatchItemResult {
+    pub id: String,
+    pub correlation_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// ChunkStored is returned for a chunk added to the node's chunk store.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChunkStored {
+    pub hash: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PeerBody {
+    pub peer: String,
+}
+
+#[derive(Deserialize)]
+struct AddrBody {
+    addr: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BanBody {
+    pub peer: String,
+    // duration_secs is how long the ban lasts; bans without one are permanent.
+    pub duration_secs: Option<u64>,
+    pub reason: Option<String>,
+}
+
+// Toggles switch the API, or just its admin endpoints, on and off while it keeps listening.
+#[derive(Debug)]
+pub struct Toggles {
+    pub enabled: AtomicBool,
+    pub admin: AtomicBool,
+}
+
+impl Toggles {
+    pub fn new(enabled: bool, admin: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            admin: AtomicBool::new(admin),
+        }
+    }
+
+    pub fn set(&self, enabled: bool, admin: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        self.admin.store(admin, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    requests: Sender<Request>,
+    toggles: Arc<Toggles>,
+}
+
+// check_toggles answers 404 for the parts of the API that are switched off.
+fn check_toggles<'a>(
+    req: tide::Request<State>,
+    next: tide::Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let toggles = &req.state().toggles;
+        let admin = req
+            .url()
+            .path()
+            .starts_with(&format!("{}/admin/", API_VERSION));
+        if !toggles.enabled.load(Ordering::Relaxed)
+            || (admin && !toggles.admin.load(Ordering::Relaxed))
+        {
+            return Ok(Response::new(StatusCode::NotFound));
+        }
+        Ok(next.run(req).await)
+    })
+}
+
+// trace_requests records a span for every API request, continuing the trace of the caller's
+// traceparent header if it sent one.
+fn trace_requests<'a>(
+    req: tide::Request<State>,
+    next: tide::Next<'a, State>,
+) -> Pin<Box<dyn Future<Output = tide::Result> + Send + 'a>> {
+    Box::pin(async move {
+        let method = req.method().to_string();
+        let (name, kind) = (format!("HTTP {}", method), Kind::Server);
+        let parent = req
+            .header("traceparent")
+            .and_then(|values| SpanContext::parse(values.last().as_str()));
+        let mut span = match parent {
+            Some(parent) => Span::child_of(&parent, name, kind),
+            None => Span::start(name, kind),
+        };
+        span.set("http.request.method", method);
+        span.set("url.path", req.url().path());
+        let res = next.run(req).await;
+        span.set("http.response.status_code", u16::from(res.status()));
+        if res.status().is_server_error() {
+            span.fail(res.status().canonical_reason());
+        }
+        span.end();
+        Ok(res)
+    })
+}
+
+// ask sends a request to the event loop and waits for its answer.
+async fn ask<T>(
+    state: &State,
+    request: impl FnOnce(oneshot::Sender<T>) -> Request,
+) -> tide::Result<T> {
+    let (reply, answer) = oneshot::channel();
+    state
+        .requests
+        .send(request(reply))
+        .await
+        .map_err(|e| tide::Error::new(StatusCode::ServiceUnavailable, e))?;
+    answer
+        .await
+        .map_err(|e| tide::Error::new(StatusCode::ServiceUnavailable, e))
+}
+
+// act runs an admin action on the event loop, answering 204 on success and 400 with the
+// reason otherwise.
+async fn act(state: &State, request: impl FnOnce(Reply) -> Request) -> tide::Result {
+    match ask(state, request).await? {
+        Ok(()) => Ok(Response::new(StatusCode::NoContent)),
+        Err(reason) => Ok(Response::builder(StatusCode::BadRequest)
+            .body(reason)
+            .build()),
+    }
+}
+
+fn bad_request(e: impl std::fmt::Display) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, e.to_string())
+}
+
+// serve runs the HTTP API on `addr` until the listener fails.
+pub async fn serve(
+    addr: String,
+    requests: Sender<Request>,
+    toggles: Arc<Toggles>,
+) -> std::io::Result<()> {
+    let mut app = tide::with_state(State { requests, toggles });
+    app.with(trace_requests);
+    app.with(check_toggles);
+
+    app.at("/metrics").get(|_| async {
+        Ok(Response::builder(StatusCode::Ok)
+            .body(metrics::gather())
+            .content_type("text/plain; version=0.0.4")
+            .build())
+    });
+
+    // Everything but the metrics is versioned, so the API can evolve without breaking
+    // existing consumers.
+    let mut v1 = tide::with_state(app.state().clone());
+
+    v1.at("/stale-blocks")
+        .get(|req: tide::Request<State>| async move {
+            let blocks = ask(req.state(), Request::StaleBlocks).await?;
+            Body::from_json(&blocks)
+        });
+
+    v1.at("/history")
+        .get(|req: tide::Request<State>| async move {
+            let query: HistoryQuery = req.query()?;
+            match ask(req.state(), |reply| Request::History(query, reply)).await? {
+                Ok(entries) => Body::from_json(&entries),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    // Blocks and mined transactions are listed a page at a time, in chain order.
+    v1.at("/blocks")
+        .get(|req: tide::Request<State>| async move {
+            let query: PageQuery = req.query()?;
+            let cursor = query.cursor().map_err(bad_request)?;
+            match ask(req.state(), |reply| {
+                Request::Blocks(cursor, query.limit, query.snapshot, reply)
+            })
+            .await?
+            {
+                Ok(Some(page)) => Ok(Body::from_json(&page)?.into()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    // Readers paging through listings over several requests open a snapshot to read them
+    // through, which pins the chain as it is now. Snapshots expire five minutes after they
+    // were last read through.
+    v1.at("/snapshots")
+        .post(|req: tide::Request<State>| async move {
+            match ask(req.state(), Request::OpenSnapshot).await? {
+                Ok(pin) => Ok(Response::builder(StatusCode::Created)
+                    .body(Body::from_json(&pin)?)
+                    .build()),
+                Err(e) => Err(tide::Error::from_str(StatusCode::ServiceUnavailable, e)),
+            }
+        });
+    v1.at("/snapshots/:id")
+        .delete(|req: tide::Request<State>| async move {
+            let id = req.param("id")?.to_string();
+            match ask(req.state(), |reply| Request::CloseSnapshot(id, reply)).await? {
+                true => Ok(Response::new(StatusCode::NoContent)),
+                false => Ok(Response::new(StatusCode::NotFound)),
+            }
+        });
+
+    // Posted payloads are submitted as transactions sent by this node.
+    v1.at("/transactions")
+        .get(|req: tide::Request<State>| async move {
+            let query: PageQuery = req.query()?;
+            let cursor = query.cursor().map_err(bad_request)?;
+            match ask(req.state(), |reply| {
+                Request::Transactions(cursor, query.limit, query.snapshot, reply)
+            })
+            .await?
+            {
+                Ok(Some(page)) => Ok(Body::from_json(&page)?.into()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        })
+        .post(|mut req: tide::Request<State>| async move {
+            let query: SubmitQuery = req.query()?;
+            let payload: Payload = req.body_json().await?;
+            let correlation_id = req
+                .header(CORRELATION_HEADER)
+                .map(|values| values.last().to_string());
+            match ask(req.state(), |reply| {
+                Request::Submit(payload, query, correlation_id, reply)
+            })
+            .await?
+            {
+                Ok(submitted) => Ok(Response::builder(StatusCode::Ok)
+                    .header(CORRELATION_HEADER, submitted.correlation_id.as_str())
+                    .body(Body::from_json(&submitted)?)
+                    .build()),
+                Err(reason) => Err(bad_request(reason)),
+            }
+        });
+
+    // Batches of payloads are submitted as consecutive transactions, all of them or none, so
+    // pipelines can feed the chain without a round trip per record.
+    v1.at("/data/batch")
+        .post(|mut req: tide::Request<State>| async move {
+            let items: Vec<BatchItem> = req.body_json().await?;
+            if items.is_empty() || items.len() > mempool::MAX_BATCH_SIZE {
+                return Err(bad_request(format!(
+                    "a batch holds between 1 and {} payloads",
+                    mempool::MAX_BATCH_SIZE
+                )));
+            }
+            match ask(req.state(), |reply| Request::SubmitBatch(items, reply)).await? {
+                Ok(submitted) if submitted.admitted => Ok(Body::from_json(&submitted)?.into()),
+                Ok(submitted) => Ok(Response::builder(StatusCode::UnprocessableEntity)
+                    .body(Body::from_json(&submitted)?)
+                    .build()),
+                Err(reason) => Err(bad_request(reason)),
+            }
+        });
+
+    v1.at("/receipts/:correlation_id")
+        .get(|req: tide::Request<State>| async move {
+            let correlation_id = req.param("correlation_id")?.to_string();
+            match ask(req.state(), |reply| Request::Receipt(correlation_id, reply)).await? {
+                Some(receipt) => Ok(Body::from_json(&receipt)?.into()),
+                None => Ok(Response::new(StatusCode::NotFound)),
+            }
+        });
+
+    // Events are streamed as server-sent events, optionally filtered by payload type, sender
+    // or key prefix.
+    v1.at("/events").get(tide::sse::endpoint(
+        |req: tide::Request<State>, sender| async move {
+            let filter: events::Filter = req.query()?;
+            let events = events::subscribe(filter);
+            while let Ok(event) = events.recv().await {
+                sender
+                    .send(event.name(), serde_json::to_string(&event)?, None)
+                    .await?;
+            }
+            Ok(())
+        },
+    ));
+
+    // Rejected blocks and transactions are listed most recent first, with why they were
+    // turned away.
+    v1.at("/rejects")
+        .get(|req: tide::Request<State>| async move {
+            let query: rejects::Query = req.query()?;
+            match ask(req.state(), |reply| Request::Rejects(query, reply)).await? {
+                Ok(rejects) => Body::from_json(&rejects),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    v1.at("/names/:name")
+        .get(|req: tide::Request<State>| async move {
+            let name = req.param("name")?.to_string();
+            let confirmations: Confirmations = req.query()?;
+            match ask(req.state(), |reply| {
+                Request::ResolveName(name, confirmations, reply)
+            })
+            .await?
+            {
+                Ok(Some(record)) => Ok(Body::from_json(&record)?.into()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    v1.at("/proofs/:digest")
+        .get(|req: tide::Request<State>| async move {
+            let digest = req.param("digest")?.to_string();
+            let confirmations: Confirmations = req.query()?;
+            match ask(req.state(), |reply| {
+                Request::Prove(digest, confirmations, reply)
+            })
+            .await?
+            {
+                Ok(Some(proof)) => Ok(Body::from_json(&proof)?.into()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    // Files are anchored by the hashes of their chunks. Chunks are added to the node before
+    // the manifest is submitted, and fetched from peers when the node doesn't have them.
+    v1.at("/chunks")
+        .post(|mut req: tide::Request<State>| async move {
+            let data = Bytes::from(req.body_bytes().await?);
+            match ask(req.state(), |reply| Request::PutChunk(data, reply)).await? {
+                Ok(hash) => Body::from_json(&ChunkStored { hash }),
+                Err(reason) => Err(bad_request(reason)),
+            }
+        });
+
+    v1.at("/chunks/:hash")
+        .get(|req: tide::Request<State>| async move {
+            let hash = req.param("hash")?.to_string();
+            match ask(req.state(), |reply| Request::GetChunk(hash, reply)).await? {
+                Ok(Some(chunk)) => Ok(Response::builder(StatusCode::Ok)
+                    .body(chunk.to_vec())
+                    .content_type("application/octet-stream")
+                    .build()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    // Payloads, and chunks, can be fetched by CID when the node or its peers have content
+    // addressing enabled.
+    v1.at("/cids/:cid")
+        .get(|req: tide::Request<State>| async move {
+            let cid = req.param("cid")?;
+            let hash = cid::to_hash(cid).ok_or_else(|| bad_request("invalid CID"))?;
+            match ask(req.state(), |reply| Request::GetChunk(hash, reply)).await? {
+                Ok(Some(content)) => Ok(Response::builder(StatusCode::Ok)
+                    .body(content.to_vec())
+                    .content_type("application/octet-stream")
+                    .build()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    v1.at("/files/:id")
+        .get(|req: tide::Request<State>| async move {
+            let id = req.param("id")?.to_string();
+            let confirmations: Confirmations = req.query()?;
+            match ask(req.state(), |reply| {
+                Request::Manifest(id, confirmations, reply)
+            })
+            .await?
+            {
+                Ok(Some(manifest)) => Ok(Body::from_json(&manifest)?.into()),
+                Ok(None) => Ok(Response::new(StatusCode::NotFound)),
+                Err(e) => Err(tide::Error::from_str(StatusCode::InternalServerError, e)),
+            }
+        });
+
+    // Deployments are the consensus changes miners signal for, and how close they are to
+    // taking effect.
+    v1.at("/deployments")
+        .get(|req: tide::Request<State>| async move {
+            let deployments = ask(req.state(), Request::Deployments).await?;
+            Body::from_json(&deployments)
+        });
+
+    v1.at("/fees").get(|req: tide::Request<State>| async move {
+        let estimate = ask(req.state(), Request::Fees).await?;
+        Body::from_json(&estimate)
+    });
+
+    // JSON-RPC for Ethereum tooling, which can explore the chain through a read-only subset
+    // of the eth_ methods; see rpc::Call.
+    v1.at("/rpc")
+        .post(|mut req: tide::Request<State>| async move {
+            let body = req.body_string().await?;
+            let calls = match serde_json::from_str(&body) {
+                Ok(rpc::Batch::One(call)) => vec![call],
+                Ok(rpc::Batch::Many(calls)) if !calls.is_empty() => calls,
+                Ok(rpc::Batch::Many(_)) => {
+                    let empty = rpc::Response::error(
+                        serde_json::Value::Null,
+                        rpc::INVALID_REQUEST,
+                        "empty batch",
+                    );
+                    return Ok(Body::from_json(&empty)?.into());
+                }
+                Err(e) => {
+                    let code = match serde_json::from_str::<serde_json::Value>(&body) {
+                        Ok(_) => rpc::INVALID_REQUEST,
+                        Err(_) => rpc::PARSE_ERROR,
+                    };
+                    let error = rpc::Response::error(serde_json::Value::Null, code, e.to_string());
+                    return Ok(Body::from_json(&error)?.into());
+                }
+            };
+            // Notifications, the calls without an id, are answered with nothing.
+            let batch = calls.len() > 1 || body.trim_start().starts_with('[');
+            let notifications: Vec<bool> = calls.iter().map(|call| call.id.is_none()).collect();
+            let responses = ask(req.state(), |reply| Request::Rpc(calls, reply)).await?;
+            let mut responses: Vec<rpc::Response> = responses
+                .into_iter()
+                .zip(notifications)
+                .filter(|(_, notification)| !notification)
+                .map(|(response, _)| response)
+                .collect();
+            match (batch, responses.pop()) {
+                (_, None) => Ok(Response::new(StatusCode::NoContent)),
+                (false, Some(response)) => Ok(Body::from_json(&response)?.into()),
+                (true, Some(last)) => {
+                    responses.push(last);
+                    Ok(Body::from_json(&responses)?.into())
+                }
+            }
+        });
+
+    v1.at("/miner/template")
+        .get(|req: tide::Request<State>| async move {
+            let template = ask(req.state(), Request::Template).await?;
+            Body::from_json(&template)
+        });
+
+    // Health answers 503 unless the node is healthy, so load balancers and orchestrators can
+    // route around nodes that are stuck or still repairing their chain.
+    v1.at("/health")
+        .get(|req: tide::Request<State>| async move {
+            let health = ask(req.state(), Request::Health).await?;
+            let status = match health.status {
+                events::Health::Ok => StatusCode::Ok,
+                _ => StatusCode::ServiceUnavailable,
+            };
+            Ok(Response::builder(status)
+                .body(Body::from_json(&health)?)
+                .build())
+        });
+
+    // Admin endpoints control the running node. The API only listens on loopback by
+    // default, so they are not exposed to the network.
+    v1.at("/admin/state")
+        .get(|req: tide::Request<State>| async move {
+            let state = ask(req.state(), Request::State).await?;
+            Body::from_json(&state)
+        });
+
+    v1.at("/admin/resync")
+        .post(|mut req: tide::Request<State>| async move {
+            let body: PeerBody = req.body_json().await?;
+            let peer: PeerId = body.peer.parse().map_err(bad_request)?;
+            act(req.state(), |reply| Request::Resync(peer, reply)).await
+        });
+
+    v1.at("/admin/dial")
+        .post(|mut req: tide::Request<State>| async move {
+            let body: AddrBody = req.body_json().await?;
+            let addr: Multiaddr = body.addr.parse().map_err(bad_request)?;
+            act(req.state(), |reply| Request::Dial(addr, reply)).await
+        });
+
+    v1.at("/admin/disconnect")
+        .post(|mut req: tide::Request<State>| async move {
+            let body: AddrBody = req.body_json().await?;
+            let addr: Multiaddr = body.addr.parse().map_err(bad_request)?;
+            act(req.state(), |reply| Request::Disconnect(addr, reply)).await
+        });
+
+    v1.at("/admin/mining/pause")
+        .post(|req: tide::Request<State>| async move {
+            act(req.state(), |reply| Request::SetMining(false, reply)).await
+        });
+
+    v1.at("/admin/mining/resume")
+        .post(|req: tide::Request<State>| async move {
+            act(req.state(), |reply| Request::SetMining(true, reply)).await
+        });
+
+    v1.at("/admin/logs/rotate")
+        .post(
+            |req: tide::Request<State>| async move { act(req.state(), Request::RotateLogs).await },
+        );
+
+    v1.at("/admin/keys/rotate")
+        .post(
+            |req: tide::Request<State>| async move { act(req.state(), Request::RotateKeys).await },
+        );
+
+    v1.at("/admin/reload")
+        .post(|req: tide::Request<State>| async move {
+            act(req.state(), Request::ReloadConfig).await
+        });
+
+    v1.at("/admin/peers/bans")
+        .get(|req: tide::Request<State>| async move {
+            let bans = ask(req.state(), Request::Bans).await?;
+            Body::from_json(&bans)
+        });
+
+    v1.at("/admin/peers/ban")
+        .post(|mut req: tide::Request<State>| async move {
+            let body: BanBody = req.body_json().await?;
+            let peer: PeerId = body.peer.parse().map_err(bad_request)?;
+            let duration = body.duration_secs.map(Duration::from_secs);
+            let reason = body
+                .reason
+                .unwrap_or_else(|| "banned by operator".to_string());
+            act(req.state(), |reply| {
+                Request::Ban(peer, duration, reason, reply)
+            })
+            .await
+        });
+
+    v1.at("/admin/peers/unban")
+        .post(|mut req: tide::Request<State>| async move {
+            let body: PeerBody = req.body_json().await?;
+            let peer: PeerId = body.peer.parse().map_err(bad_request)?;
+            act(req.state(), |reply| Request::Unban(peer, reply)).await
+        });
+
+    // A reorg deeper than max_reorg_depth waits here until an operator accepts it.
+    v1.at("/admin/reorg")
+        .get(|req: tide::Request<State>| async move {
+            let held = ask(req.state(), Request::HeldReorg).await?;
+            Body::from_json(&held)
+        });
+
+    v1.at("/admin/reorg/accept")
+        .post(
+            |req: tide::Request<State>| async move { act(req.state(), Request::AcceptReorg).await },
+        );
+
+    app.at(API_VERSION).nest(v1);
+
+    let mut listener = app.bind(addr).await?;
+    for info in listener.info() {
+        log::info!("API listening on {}", info);
+    }
+    listener.accept().await
+}
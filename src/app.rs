@@ -1,50 +1,379 @@
+use bytes::Bytes;
 use chrono::prelude::*;
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p::PeerId;
 use log::{error, info, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
 
-const DIFFICULTY_PREFIX: &str = "00";
+use crate::events::{self, Event};
+use crate::files::ChunkStore;
+use crate::fork::{Branch, Fork, HeldReorg, StaleBlocks};
+use crate::genesis::Genesis;
+use crate::merkle;
+use crate::nonces::Nonces;
+use crate::payload::{Coinbase, Payload};
+use crate::rejects::{self, Subject};
+use crate::snapshot::Snapshots;
+use crate::storage::{self, Storage};
+use crate::validator::Validators;
+use crate::versionbits::{self, Tracker, VERSIONED_HEADERS};
+
+// DIFFICULTY_PREFIX is what the binary representation of a block hash has to start with.
+pub const DIFFICULTY_PREFIX: &str = "00";
+
+// NETWORK is the genesis block of the network this process is on; see set_genesis.
+static NETWORK: OnceCell<Block> = OnceCell::new();
+
+// MINED holds the genesis blocks mined so far by the hash of their parameters, as mining one
+// takes a while.
+static MINED: Lazy<Mutex<HashMap<[u8; 32], Block>>> = Lazy::new(Default::default);
+
+// set_genesis makes `genesis` the parameters of the network this process is on, which the
+// genesis hash and the chain id are derived from. It has to be called before either is first
+// used, or the default parameters are assumed.
+pub fn set_genesis(genesis: &Genesis) -> Result<(), String> {
+    NETWORK
+        .set(genesis_block(genesis))
+        .map_err(|_| "the genesis parameters are already in use".to_string())
+}
+
+// network returns the genesis block of the network this process is on.
+fn network() -> &'static Block {
+    NETWORK.get_or_init(|| genesis_block(&Genesis::default()))
+}
+
+// genesis_hash returns the hash of the genesis block of the network this process is on.
+pub fn genesis_hash() -> &'static str {
+    &network().hash
+}
+
+// genesis_params_hash returns the hash of the genesis parameters of the network this process
+// is on, which its genesis block commits to.
+pub fn genesis_params_hash() -> &'static str {
+    &network().previous_hash
+}
+
+// chain_id identifies the network a node belongs to. It is derived from the genesis block, so
+// nodes with different genesis parameters never share a chain id.
+pub fn chain_id() -> &'static str {
+    &genesis_hash()[..16]
+}
+
+// MAX_BLOCKS_IN_MEMORY is how many of the most recent blocks App keeps in memory. Older
+// blocks are read back from storage.
+pub const MAX_BLOCKS_IN_MEMORY: usize = 1024;
 
 pub struct App {
-    pub blocks: Vec<Block>,
+    // blocks is a window of the most recent blocks of the chain, ending at the tip. Every
+    // block in it has already been written to storage.
+    blocks: VecDeque<Block>,
+    // by_hash maps the hash of every block in the window to its height.
+    by_hash: HashMap<String, usize>,
+    storage: Arc<dyn Storage>,
+    // stale holds recently orphaned blocks for debugging consensus issues.
+    pub stale: StaleBlocks,
+    // held is the latest reorg that went deeper than allowed, waiting for an operator.
+    pub held: Option<HeldReorg>,
+    // validators veto transaction payloads in the blocks we accept.
+    validators: Validators,
+    // deployments follows the consensus changes miners signal for; see versionbits.
+    pub deployments: Tracker,
+    // nonces are the nonces every sender has used on the chain, each of which can only be
+    // mined once.
+    nonces: Nonces,
+    // snapshots pin the chain for readers paging through it; see snapshot.
+    pub snapshots: Snapshots,
+    pub genesis: Genesis,
+    // genesis_hash is the hash of the genesis block `genesis` gives.
+    genesis_hash: String,
+    // payloads keeps the payloads of the blocks added to the chain while content addressing
+    // is on; see keep_payloads_in.
+    payloads: Option<ChunkStore>,
+}
+
+// Transaction is a payload submitted to the network to be included in a block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    // id is the hex encoded hash of every other field.
+    pub id: String,
+    // sender is the peer id of the key that signed the transaction.
+    pub sender: String,
+    pub nonce: u64,
+    pub fee: u64,
+    pub timestamp: i64,
+    // payload is reference counted, so cloning a transaction on its way from gossip to the
+    // mempool, a block and storage doesn't copy it.
+    pub payload: Bytes,
+    // public_key is the hex encoded protobuf encoding of the sender's public key.
+    #[serde(default)]
+    pub public_key: String,
+    // signature is the hex encoded signature of the id by the sender's key.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl Transaction {
+    // new creates a transaction sent and signed by the owner of `keys`.
+    pub fn new(keys: &Keypair, nonce: u64, fee: u64, payload: Bytes) -> Self {
+        let sender = PeerId::from(keys.public()).to_string();
+        let timestamp = Utc::now().timestamp();
+        let id = transaction_id(&sender, nonce, fee, timestamp, &payload);
+        let signature = keys.sign(id.as_bytes()).expect("ed25519 keys can sign");
+        Self {
+            id,
+            sender,
+            nonce,
+            fee,
+            timestamp,
+            payload,
+            public_key: hex::encode(keys.public().to_protobuf_encoding()),
+            signature: hex::encode(signature),
+        }
+    }
+
+    // coinbase returns the amount claimed if this is a coinbase transaction.
+    pub fn coinbase(&self) -> Option<u64> {
+        match Payload::decode(&self.payload) {
+            Some(Payload::Coinbase(Coinbase { amount })) => Some(amount),
+            _ => None,
+        }
+    }
+
+    // is_valid checks that the id matches the contents of the transaction and that it was
+    // signed by the sender, so nobody can send a transaction in someone else's name.
+    pub fn is_valid(&self) -> bool {
+        self.id
+            == transaction_id(
+                &self.sender,
+                self.nonce,
+                self.fee,
+                self.timestamp,
+                &self.payload,
+            )
+            && self.is_signed()
+    }
+
+    // is_signed checks that the public key belongs to the sender and signed the id.
+    fn is_signed(&self) -> bool {
+        let public_key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok());
+        let signature = hex::decode(&self.signature);
+        match (public_key, signature) {
+            (Some(public_key), Ok(signature)) => {
+                PeerId::from(&public_key).to_string() == self.sender
+                    && public_key.verify(self.id.as_bytes(), &signature)
+            }
+            _ => false,
+        }
+    }
+}
+
+// transaction_id hashes the canonical encoding of a transaction:
+//   sender length (4 bytes) | sender | nonce (8 bytes) | fee (8 bytes) | timestamp (8 bytes)
+//   | payload length (4 bytes) | payload
+// All integers are big endian.
+fn transaction_id(sender: &str, nonce: u64, fee: u64, timestamp: i64, payload: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update((sender.len() as u32).to_be_bytes());
+    hasher.update(sender.as_bytes());
+    hasher.update(nonce.to_be_bytes());
+    hasher.update(fee.to_be_bytes());
+    hasher.update(timestamp.to_be_bytes());
+    hasher.update((payload.len() as u32).to_be_bytes());
+    hasher.update(payload);
+    hex::encode(hasher.finalize())
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
+    // height is the number of blocks before this one, so the genesis block is at height 0.
+    pub height: usize,
     pub hash: String,
     pub previous_hash: String,
     pub timestamp: i64,
-    pub data: Vec<u8>,
+    pub transactions: Vec<Transaction>,
     pub nonce: u64,
+    // version carries the bits miners signal readiness for deployments with; see versionbits.
+    #[serde(default)]
+    pub version: u32,
 }
 
 impl Block {
-    pub fn new(previous_hash: String, data: Vec<u8>) -> Self {
+    pub fn new(
+        height: usize,
+        version: u32,
+        previous_hash: String,
+        transactions: Vec<Transaction>,
+    ) -> Self {
         let now = Utc::now();
-        let (nonce, hash) = mine_block(now.timestamp(), &previous_hash, &data);
+        let (timestamp, root) = (now.timestamp(), transactions_root(&transactions));
+        let (nonce, hash) = mine_block(height, version, timestamp, &previous_hash, &root);
         Self {
+            height,
+            version,
             hash,
             timestamp: now.timestamp(),
             previous_hash,
-            data,
+            transactions,
             nonce,
         }
     }
 }
 
-fn calculate_hash(timestamp: i64, previous_hash: &str, data: &Vec<u8>, nonce: u64) -> Vec<u8> {
-    let data = serde_json::json!({
-        "previous_hash": previous_hash,
-        "data": data,
-        "timestamp": timestamp,
-        "nonce": nonce
-    });
-    let mut hasher = Sha256::new();
-    hasher.update(data.to_string().as_bytes());
-    hasher.finalize().as_slice().to_owned()
+// HEADER_VERSION is the first byte of every encoded header, so the encoding can change
+// without old and new headers hashing the same. Blocks that predate version bits, whose
+// version is 0, keep the encoding of LEGACY_HEADER_VERSION, so their hashes still match.
+const HEADER_VERSION: u8 = 4;
+const LEGACY_HEADER_VERSION: u8 = 3;
+
+// HEADER_SIZE and LEGACY_HEADER_SIZE are the lengths of an encoded header in bytes.
+pub const HEADER_SIZE: usize = 1 + 4 + 8 + 32 + 8 + 32 + 8;
+pub const LEGACY_HEADER_SIZE: usize = 1 + 8 + 32 + 8 + 32 + 8;
+
+// encode_header is the canonical encoding of a block header, which is what gets hashed:
+//   header version (1 byte) | block version (4 bytes) | height (8 bytes)
+//   | previous hash (32 bytes) | timestamp (8 bytes) | transactions root (32 bytes)
+//   | nonce (8 bytes)
+// Blocks of version 0 leave the block version out and start with the legacy header version.
+// All integers are big endian. A previous hash that is not a 32 byte hex string, like the
+// hash of the genesis parameters, is hashed to fill its field.
+pub fn encode_header(
+    height: usize,
+    version: u32,
+    timestamp: i64,
+    previous_hash: &str,
+    transactions_root: &[u8; 32],
+    nonce: u64,
+) -> Vec<u8> {
+    let previous: [u8; 32] = match hex::decode(previous_hash) {
+        Ok(bytes) if bytes.len() == 32 => bytes.try_into().expect("length was checked"),
+        _ => Sha256::digest(previous_hash.as_bytes()).into(),
+    };
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    if version == 0 {
+        header.push(LEGACY_HEADER_VERSION);
+    } else {
+        header.push(HEADER_VERSION);
+        header.extend_from_slice(&version.to_be_bytes());
+    }
+    header.extend_from_slice(&(height as u64).to_be_bytes());
+    header.extend_from_slice(&previous);
+    header.extend_from_slice(&timestamp.to_be_bytes());
+    header.extend_from_slice(transactions_root);
+    header.extend_from_slice(&nonce.to_be_bytes());
+    header
 }
 
-fn mine_block(timestamp: i64, previous_hash: &str, data: &Vec<u8>) -> (u64, String) {
+// check_rules returns why the block breaks a consensus rule that a deployment activated, given
+// the state of the deployments for the block, if it does.
+fn check_rules(block: &Block, deployments: &Tracker) -> Result<(), String> {
+    if deployments.is_active(VERSIONED_HEADERS) && !versionbits::is_versioned(block.version) {
+        return Err(format!(
+            "version {:#x} lacks the top bits {} requires",
+            block.version, VERSIONED_HEADERS
+        ));
+    }
+    Ok(())
+}
+
+// transaction_ids returns the raw ids of the transactions, in order.
+pub fn transaction_ids(transactions: &[Transaction]) -> Vec<[u8; 32]> {
+    transactions
+        .iter()
+        .map(|tx| {
+            let mut id = [0; 32];
+            // Transactions with a malformed id fail validation, so any filler will do.
+            let _ = hex::decode_to_slice(&tx.id, &mut id);
+            id
+        })
+        .collect()
+}
+
+// transactions_root is the Merkle root of the transaction ids of a block, which lets a
+// single transaction be proven to be in the block without the rest of it.
+pub fn transactions_root(transactions: &[Transaction]) -> [u8; 32] {
+    merkle::root(&transaction_ids(transactions))
+}
+
+// Header is everything that goes into a block's hash, with the transactions replaced by their
+// Merkle root. It is enough to check a block's proof of work without its transactions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub height: usize,
+    #[serde(default)]
+    pub version: u32,
+    pub hash: String,
+    pub previous_hash: String,
+    pub timestamp: i64,
+    pub transactions_root: String,
+    pub nonce: u64,
+}
+
+impl Header {
+    pub fn of(block: &Block) -> Self {
+        Self {
+            height: block.height,
+            version: block.version,
+            hash: block.hash.clone(),
+            previous_hash: block.previous_hash.clone(),
+            timestamp: block.timestamp,
+            transactions_root: hex::encode(transactions_root(&block.transactions)),
+            nonce: block.nonce,
+        }
+    }
+
+    // is_valid checks that the hash matches the other fields and meets the difficulty.
+    pub fn is_valid(&self) -> bool {
+        let mut root = [0; 32];
+        if hex::decode_to_slice(&self.transactions_root, &mut root).is_err() {
+            return false;
+        }
+        let hash = calculate_hash(
+            self.height,
+            self.version,
+            self.timestamp,
+            &self.previous_hash,
+            &root,
+            self.nonce,
+        );
+        hex::encode(&hash) == self.hash
+            && hash_to_binary_representation(&hash).starts_with(DIFFICULTY_PREFIX)
+    }
+}
+
+fn calculate_hash(
+    height: usize,
+    version: u32,
+    timestamp: i64,
+    previous_hash: &str,
+    transactions_root: &[u8; 32],
+    nonce: u64,
+) -> Vec<u8> {
+    let header = encode_header(
+        height,
+        version,
+        timestamp,
+        previous_hash,
+        transactions_root,
+        nonce,
+    );
+    Sha256::digest(&header).to_vec()
+}
+
+fn mine_block(
+    height: usize,
+    version: u32,
+    timestamp: i64,
+    previous_hash: &str,
+    root: &[u8; 32],
+) -> (u64, String) {
     info!("mining block...");
     let mut nonce = 0;
 
@@ -52,7 +381,7 @@ fn mine_block(timestamp: i64, previous_hash: &str, data: &Vec<u8>) -> (u64, Stri
         if nonce % 100000 == 0 {
             info!("nonce: {}", nonce);
         }
-        let hash = calculate_hash(timestamp, previous_hash, data, nonce);
+        let hash = calculate_hash(height, version, timestamp, previous_hash, root, nonce);
         let binary_hash = hash_to_binary_representation(&hash);
         if binary_hash.starts_with(DIFFICULTY_PREFIX) {
             info!(
@@ -67,6 +396,32 @@ fn mine_block(timestamp: i64, previous_hash: &str, data: &Vec<u8>) -> (u64, Stri
     }
 }
 
+// genesis_block returns the first block of a chain with the given genesis parameters. It
+// commits to the hash of the parameters in place of a previous block and is mined like any
+// other block, while its other fields are fixed, so the same parameters always give the same
+// block and different ones never do.
+pub fn genesis_block(genesis: &Genesis) -> Block {
+    let params = genesis.hash();
+    let mut mined = MINED
+        .lock()
+        .expect("mined genesis blocks lock is not poisoned");
+    let block = mined.entry(params).or_insert_with(|| {
+        let previous_hash = hex::encode(params);
+        let root = transactions_root(&[]);
+        let (nonce, hash) = mine_block(0, 0, 0, &previous_hash, &root);
+        Block {
+            height: 0,
+            timestamp: 0,
+            previous_hash,
+            transactions: vec![],
+            nonce,
+            version: 0,
+            hash,
+        }
+    });
+    block.clone()
+}
+
 fn hash_to_binary_representation(hash: &[u8]) -> String {
     let mut res: String = String::default();
     for c in hash {
@@ -76,83 +431,702 @@ fn hash_to_binary_representation(hash: &[u8]) -> String {
 }
 
 impl App {
-    pub fn new() -> Self {
-        Self { blocks: vec![] }
+    // load creates an app on top of the chain in storage, reading the most recent blocks
+    // into memory. Blocks are only accepted if every validator accepts their transactions.
+    pub async fn load(
+        storage: Arc<dyn Storage>,
+        validators: Validators,
+        genesis: Genesis,
+    ) -> Result<Self, storage::Error> {
+        let mut app = Self {
+            blocks: VecDeque::new(),
+            by_hash: HashMap::new(),
+            storage,
+            stale: StaleBlocks::new(),
+            held: None,
+            validators,
+            deployments: Tracker::new(genesis.signaling.clone()),
+            nonces: Nonces::default(),
+            snapshots: Snapshots::default(),
+            genesis_hash: genesis_block(&genesis).hash,
+            genesis,
+            payloads: None,
+        };
+        app.read().await?;
+        Ok(app)
+    }
+
+    // read reads the nonces used on the chain, the state of the deployments and the most
+    // recent blocks back from storage. The blocks in memory have to follow each other, so if
+    // the stored chain has gaps, only the blocks after the last one are kept; see repair.
+    async fn read(&mut self) -> Result<(), storage::Error> {
+        let height = self.storage.height().await?;
+        let start = height.saturating_sub(MAX_BLOCKS_IN_MEMORY);
+        self.blocks.clear();
+        self.by_hash.clear();
+        self.deployments.reset();
+        self.nonces = Nonces::default();
+        // The nonces and the state of the deployments depend on every block of the chain, so
+        // the whole chain is read.
+        for batch in (0..start).step_by(MAX_BLOCKS_IN_MEMORY) {
+            let end = (batch + MAX_BLOCKS_IN_MEMORY).min(start);
+            for block in self.storage.range(batch, end).await? {
+                self.deployments.observe(&block);
+                self.nonces.apply(&block);
+            }
+        }
+        for block in self.storage.range(start, height).await? {
+            self.deployments.observe(&block);
+            self.nonces.apply(&block);
+            if self.height() != block.height {
+                self.blocks.clear();
+                self.by_hash.clear();
+            }
+            self.remember(block);
+        }
+        Ok(())
+    }
+
+    // genesis creates the first block of the chain, which is the same on every node with the
+    // same genesis parameters.
+    pub async fn genesis(&mut self) -> Result<(), storage::Error> {
+        self.push(genesis_block(&self.genesis)).await
+    }
+
+    // keep_payloads_in makes the chunk store keep the payloads of the blocks added to the
+    // chain from now on, or stops keeping them if it is None.
+    pub fn keep_payloads_in(&mut self, store: Option<ChunkStore>) {
+        self.payloads = store;
     }
 
-    pub fn genesis(&mut self) {
-        let genesis_block = Block {
-            timestamp: Utc::now().timestamp(),
-            previous_hash: String::from("genesis"),
-            data: [0; 0].to_vec(),
-            nonce: 2836,
-            hash: "0000f816a87f806bb0073dcf026a64fb40c946b5abee2573702828694d5b4c43".to_string(),
+    pub fn keeps_payloads(&self) -> bool {
+        self.payloads.is_some()
+    }
+
+    // storage returns the storage the chain is kept in, for work that reads through it in the
+    // background.
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        Arc::clone(&self.storage)
+    }
+
+    // put writes the block to storage, keeping its payloads if content addressing is on.
+    async fn put(&self, block: &Block) -> Result<(), storage::Error> {
+        self.storage.put(block).await?;
+        if let Some(store) = &self.payloads {
+            if let Err(e) = store.keep_payloads(block) {
+                warn!("Could not keep the payloads of block {}: {}", block.hash, e);
+            }
+        }
+        Ok(())
+    }
+
+    // push writes the block to storage and appends it to the chain.
+    async fn push(&mut self, block: Block) -> Result<(), storage::Error> {
+        self.put(&block).await?;
+        events::emit(Event::Block {
+            block: block.clone(),
+        });
+        self.nonces.apply(&block);
+        for (name, state) in self.deployments.observe(&block) {
+            info!(
+                "Deployment {} is {:?} from height {}",
+                name,
+                state,
+                block.height + 1
+            );
+        }
+        self.remember(block);
+        Ok(())
+    }
+
+    // remember appends the block to the in-memory window, evicting the oldest block once the
+    // window is full.
+    fn remember(&mut self, block: Block) {
+        self.by_hash.insert(block.hash.clone(), block.height);
+        self.blocks.push_back(block);
+        if self.blocks.len() > MAX_BLOCKS_IN_MEMORY {
+            if let Some(evicted) = self.blocks.pop_front() {
+                self.by_hash.remove(&evicted.hash);
+            }
+        }
+    }
+
+    // recent returns the blocks kept in memory, oldest first.
+    pub fn recent(&self) -> impl DoubleEndedIterator<Item = &Block> {
+        self.blocks.iter()
+    }
+
+    // first_height returns the height of the oldest block kept in memory.
+    fn first_height(&self) -> usize {
+        self.blocks.front().map_or(0, |block| block.height)
+    }
+
+    // recent_by_height returns the block at the given height if it is kept in memory.
+    fn recent_by_height(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height.checked_sub(self.first_height())?)
+    }
+
+    pub async fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, storage::Error> {
+        match self.by_hash.get(hash) {
+            Some(&height) => Ok(self.recent_by_height(height).cloned()),
+            None => self.storage.get_by_hash(hash).await,
+        }
+    }
+
+    pub async fn get_by_height(&self, height: usize) -> Result<Option<Block>, storage::Error> {
+        match self.recent_by_height(height) {
+            Some(block) => Ok(Some(block.clone())),
+            None if height >= self.height() => Ok(None),
+            None => self.storage.get_by_height(height).await,
+        }
+    }
+
+    pub async fn contains(&self, hash: &str) -> Result<bool, storage::Error> {
+        if self.by_hash.contains_key(hash) {
+            return Ok(true);
+        }
+        Ok(self.storage.get_by_hash(hash).await?.is_some())
+    }
+
+    // range returns the blocks with a height in start..end that we have.
+    pub async fn range(&self, start: usize, end: usize) -> Result<Vec<Block>, storage::Error> {
+        let end = end.min(self.height());
+        if start < self.first_height() {
+            return self.storage.range(start, end).await;
+        }
+        Ok((start..end)
+            .filter_map(|height| self.recent_by_height(height).cloned())
+            .collect())
+    }
+
+    // nonces returns the nonces every sender has used on the chain.
+    pub fn nonces(&self) -> &Nonces {
+        &self.nonces
+    }
+
+    // tip returns the last block of the chain, if we have one.
+    pub fn tip(&self) -> Option<&Block> {
+        self.blocks.back()
+    }
+
+    // height returns the number of blocks in the chain, which is also the height the next
+    // block will have.
+    pub fn height(&self) -> usize {
+        self.tip().map_or(0, |tip| tip.height + 1)
+    }
+
+    // confirmed_height returns the number of blocks that have at least `min_confirmations`
+    // confirmations. The tip has one confirmation, its parent two, and so on.
+    pub fn confirmed_height(&self, min_confirmations: usize) -> usize {
+        (self.height() + 1).saturating_sub(min_confirmations.max(1))
+    }
+
+    // try_add_block appends the block to the chain if it extends the tip, returning whether
+    // it was added. Invalid blocks are recorded as rejects.
+    pub async fn try_add_block(&mut self, block: Block) -> Result<bool, storage::Error> {
+        let latest_block = self.tip().expect("there is at least one block");
+        let checked = self
+            .check_block(&block, latest_block)
+            .and_then(|()| check_rules(&block, &self.deployments))
+            .and_then(|()| self.nonces.check(&block));
+        match checked {
+            Ok(()) => {
+                info!("block is valid");
+                self.push(block).await?;
+                Ok(true)
+            }
+            Err(reason) => {
+                error!("could not add block {} - {}", block.hash, reason);
+                rejects::record(Subject::Block(block), reason);
+                Ok(false)
+            }
+        }
+    }
+
+    // gaps returns the ranges of heights below the tip that are missing from storage.
+    pub async fn gaps(&self) -> Result<Vec<Range<usize>>, storage::Error> {
+        self.storage.gaps().await
+    }
+
+    // repair stores blocks downloaded to fill a gap in storage, e.g. one left by a crash
+    // while the chain was written, returning whether they were stored. The blocks have to
+    // lead up to the stored block right after them, which ties them to our chain, and follow
+    // the stored block right before them if there is one. Invalid blocks are recorded as
+    // rejects.
+    pub async fn repair(&mut self, blocks: Vec<Block>) -> Result<bool, storage::Error> {
+        let (Some(first), Some(last)) = (blocks.first(), blocks.last()) else {
+            return Ok(false);
         };
-        self.blocks.push(genesis_block);
+        let before = match first.height.checked_sub(1) {
+            Some(height) => self.storage.get_by_height(height).await?,
+            None => None,
+        };
+        let after = self.storage.get_by_height(last.height + 1).await?;
+        if let Err((index, reason)) = self.check_repair(&blocks, before.as_ref(), after.as_ref()) {
+            let block = blocks[index].clone();
+            error!("could not repair with block {} - {}", block.hash, reason);
+            rejects::record(Subject::Block(block), reason);
+            return Ok(false);
+        }
+        for block in &blocks {
+            self.put(block).await?;
+        }
+        self.read().await?;
+        Ok(true)
     }
 
-    pub fn try_add_block(&mut self, block: Block) {
-        let latest_block = self.blocks.last().expect("there is at least one block");
-        if self.is_block_valid(&block, latest_block) {
-            log::info!("block is valid");
-            self.blocks.push(block);
-        } else {
-            log::error!("could not add block - invalid");
+    // check_repair returns the index of the first block that can't fill the gap between
+    // `before` and `after`, and why, if there is one.
+    fn check_repair(
+        &self,
+        blocks: &[Block],
+        before: Option<&Block>,
+        after: Option<&Block>,
+    ) -> Result<(), (usize, String)> {
+        let last = blocks.len() - 1;
+        match after {
+            Some(after) if after.previous_hash == blocks[last].hash => {}
+            Some(after) => {
+                return Err((last, format!("is not followed by {}", after.hash)));
+            }
+            None => {
+                let height = blocks[last].height + 1;
+                return Err((last, format!("no block is stored at height {}", height)));
+            }
         }
+        let first = &blocks[0];
+        let checked = match before {
+            Some(before) => self.check_block(first, before),
+            None if first.height == 0 && first.hash != self.genesis_hash => {
+                Err("is not the genesis block".to_string())
+            }
+            None if first.height == 0 => Ok(()),
+            None => self.check_contents(first),
+        };
+        checked.map_err(|reason| (0, reason))?;
+        for (i, pair) in blocks.windows(2).enumerate() {
+            self.check_block(&pair[1], &pair[0])
+                .map_err(|reason| (i + 1, reason))?;
+        }
+        Ok(())
     }
 
-    fn is_block_valid(&self, block: &Block, previous_block: &Block) -> bool {
+    // check_block returns why the block can't follow the previous one, if it can't.
+    fn check_block(&self, block: &Block, previous_block: &Block) -> Result<(), String> {
         if block.previous_hash != previous_block.hash {
-            return false;
-        } else if !hash_to_binary_representation(
-            &hex::decode(&block.hash).expect("can decode from hex"),
-        )
-        .starts_with(DIFFICULTY_PREFIX)
-        {
-            return false;
-        } else if hex::encode(calculate_hash(
+            return Err(format!("does not follow {}", previous_block.hash));
+        }
+        if block.height != previous_block.height + 1 {
+            return Err(format!(
+                "height {} does not follow {}",
+                block.height, previous_block.height
+            ));
+        }
+        self.check_contents(block)
+    }
+
+    // check_contents returns why the block is invalid on its own, if it is.
+    fn check_contents(&self, block: &Block) -> Result<(), String> {
+        let Ok(hash) = hex::decode(&block.hash) else {
+            return Err("hash is not hex encoded".to_string());
+        };
+        if !hash_to_binary_representation(&hash).starts_with(DIFFICULTY_PREFIX) {
+            return Err("hash does not meet the difficulty".to_string());
+        }
+        if let Some(tx) = block.transactions.iter().find(|tx| !tx.is_valid()) {
+            return Err(format!("transaction {} does not match its id", tx.id));
+        }
+        if hex::encode(calculate_hash(
+            block.height,
+            block.version,
             block.timestamp,
             &block.previous_hash,
-            &block.data,
+            &transactions_root(&block.transactions),
             block.nonce,
         )) != block.hash
         {
-            return false;
+            return Err("hash does not match contents".to_string());
         }
-        true
+        self.check_emission(block)?;
+        for tx in &block.transactions {
+            if let Err(reason) = self.validators.check(tx) {
+                return Err(format!("invalid transaction {}: {}", tx.id, reason));
+            }
+        }
+        Ok(())
     }
 
-    fn is_chain_valid(&self, chain: &[Block]) -> bool {
+    // check_emission checks that the block starts with a coinbase that claims no more than
+    // the emission schedule allows, and has no other coinbase.
+    fn check_emission(&self, block: &Block) -> Result<(), String> {
+        let Some((first, rest)) = block.transactions.split_first() else {
+            return Err("has no coinbase".to_string());
+        };
+        let Some(amount) = first.coinbase() else {
+            return Err("does not start with a coinbase".to_string());
+        };
+        if rest.iter().any(|tx| tx.coinbase().is_some()) {
+            return Err("has more than one coinbase".to_string());
+        }
+        let fees = rest.iter().map(|tx| tx.fee).sum::<u64>();
+        let allowed = self
+            .genesis
+            .emission
+            .reward(block.height)
+            .saturating_add(fees);
+        if amount > allowed {
+            return Err(format!(
+                "claims {} but at most {} is allowed",
+                amount, allowed
+            ));
+        }
+        Ok(())
+    }
+
+    // is_chain_valid checks every block of the chain after the first, given the state of the
+    // deployments and the nonces used before the first.
+    fn is_chain_valid(
+        &self,
+        chain: &[Block],
+        mut deployments: Tracker,
+        mut nonces: Nonces,
+    ) -> bool {
+        let first = chain.first().filter(|first| first.height == 0);
+        if let Some(first) = first.filter(|first| first.hash != self.genesis_hash) {
+            warn!(
+                "chain starts at block {}, not our genesis block",
+                first.hash
+            );
+            return false;
+        }
         for i in 0..chain.len() {
             if i == 0 {
+                deployments.observe(&chain[0]);
+                nonces.apply(&chain[0]);
                 continue;
             }
             let first = chain.get(i - 1).expect("has to exist");
             let second = chain.get(i).expect("has to exist");
-            if !self.is_block_valid(second, first) {
+            let checked = self
+                .check_block(second, first)
+                .and_then(|()| check_rules(second, &deployments))
+                .and_then(|()| nonces.check(second));
+            if let Err(reason) = checked {
+                warn!("chain is invalid at block {}: {}", second.hash, reason);
                 return false;
             }
+            deployments.observe(second);
+            nonces.apply(second);
         }
         true
     }
 
-    // We always choose the longest valid chain
-    pub fn choose_chain(&mut self, local: Vec<Block>, remote: Vec<Block>) -> Vec<Block> {
-        let is_local_valid = self.is_chain_valid(&local);
-        let is_remote_valid = self.is_chain_valid(&remote);
+    // replace_chain switches to the remote chain if choose_chain prefers it, returning the
+    // fork between the two chains if they diverged. A remote chain that would rewind more than
+    // `max_depth` blocks is held instead; see accept_held.
+    //
+    // The remote chain may be just the most recent blocks of a peer's chain, which then have to
+    // follow one of our blocks. Only our blocks from there on are read, and no more of them than
+    // it takes to tell whether the remote chain is longer.
+    pub async fn replace_chain(
+        &mut self,
+        mut remote: Vec<Block>,
+        max_depth: Option<usize>,
+    ) -> Result<Option<Fork>, storage::Error> {
+        let Some(first) = remote.first() else {
+            return Ok(None);
+        };
+        let from = first.height.saturating_sub(1);
+        let end = self.height().min(from + remote.len() + 1);
+        let local = self.range(from, end).await?;
+        if first.height > 0 {
+            match local.first() {
+                Some(anchor) if anchor.height == from => remote.insert(0, anchor.clone()),
+                _ => {
+                    warn!(
+                        "Ignoring chain starting at height {}, past our tip",
+                        first.height
+                    );
+                    return Ok(None);
+                }
+            }
+        }
+        let remote_tip = remote.last().cloned();
+        let deployments = self.deployments_before(from).await?;
+        let nonces = self.nonces_before(from).await?;
+        let chosen = self.choose_chain(local.clone(), remote, max_depth, &deployments, &nonces);
+        let (chain, fork) = match chosen {
+            Ok(chosen) => chosen,
+            Err(reason) => {
+                error!("Keeping the local chain: {}", reason);
+                if let Some(block) = remote_tip {
+                    rejects::record(Subject::Block(block), reason);
+                }
+                return Ok(None);
+            }
+        };
+
+        // Only the blocks past the point where the chains agree need to be rewritten.
+        let common = local
+            .iter()
+            .zip(&chain)
+            .take_while(|(l, c)| l.hash == c.hash)
+            .count();
+        if common == local.len() && common == chain.len() {
+            return Ok(fork);
+        }
+        self.snapshots.preserve(&local[common..]);
+        self.storage.truncate(from + common).await?;
+        for block in &chain[common..] {
+            self.put(block).await?;
+            events::emit(Event::Block {
+                block: block.clone(),
+            });
+        }
+        self.read().await?;
+        Ok(fork)
+    }
+
+    // deployments_before returns the state of the deployments after the blocks of our chain
+    // below `height`, to judge the blocks of a chain that branches off there.
+    async fn deployments_before(&self, height: usize) -> Result<Tracker, storage::Error> {
+        let mut deployments = self.deployments.clone();
+        if !deployments.is_empty() {
+            let before = self.range(deployments.window_start(height), height).await?;
+            deployments.rewind(height, &before);
+        }
+        Ok(deployments)
+    }
+
+    // nonces_before returns the nonces used by the blocks of our chain below `height`, to
+    // judge the blocks of a chain that branches off there.
+    async fn nonces_before(&self, height: usize) -> Result<Nonces, storage::Error> {
+        let mut nonces = self.nonces.clone();
+        for start in (height..self.height()).step_by(MAX_BLOCKS_IN_MEMORY) {
+            for block in self.range(start, start + MAX_BLOCKS_IN_MEMORY).await? {
+                nonces.forget(&block);
+            }
+        }
+        Ok(nonces)
+    }
+
+    // accept_held switches to the held remote chain regardless of how deep the reorg is. It
+    // is still validated against the current chain, which may have grown since.
+    pub async fn accept_held(&mut self) -> Result<Option<Fork>, String> {
+        let held = self.held.take().ok_or("no reorg is held")?;
+        warn!(
+            "Accepting held reorg rewinding {} blocks to height {}",
+            held.fork.depth, held.fork.height
+        );
+        self.replace_chain(held.chain, None)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // We always choose the longest valid chain. If the chains diverged, the losing branch
+    // is kept in the stale block store and the fork is returned alongside the winner. A
+    // remote chain that would rewind more than `max_depth` local blocks is held rather than
+    // chosen, so a freshly fabricated longer chain can't rewind a long-running node. If
+    // neither chain is valid, there is nothing to choose and an error is returned.
+    fn choose_chain(
+        &mut self,
+        local: Vec<Block>,
+        remote: Vec<Block>,
+        max_depth: Option<usize>,
+        deployments: &Tracker,
+        nonces: &Nonces,
+    ) -> Result<(Vec<Block>, Option<Fork>), String> {
+        let is_local_valid = self.is_chain_valid(&local, deployments.clone(), nonces.clone());
+        let is_remote_valid = self.is_chain_valid(&remote, deployments.clone(), nonces.clone());
 
-        if is_local_valid && is_remote_valid {
-            if local.len() >= remote.len() {
-                local
-            } else {
-                remote
+        let winner = match (is_local_valid, is_remote_valid) {
+            (true, true) if remote.len() > local.len() => Branch::Remote,
+            (true, _) => Branch::Local,
+            (false, true) => Branch::Remote,
+            (false, false) => return Err("local and remote chains are both invalid".to_string()),
+        };
+
+        // An invalid remote chain is not a fork, just a bad peer.
+        let fork = Fork::between(&local, &remote, winner).filter(|_| is_remote_valid);
+        match winner {
+            Branch::Local => {
+                if let Some(fork) = &fork {
+                    self.stale.record(fork, &remote);
+                }
+                Ok((local, fork))
+            }
+            Branch::Remote => {
+                if let Some(fork) = &fork {
+                    if max_depth.is_some_and(|max| fork.depth > max) {
+                        warn!(
+                            "Holding reorg to {} rewinding {} blocks to height {}",
+                            fork.remote_tip, fork.depth, fork.height
+                        );
+                        self.held = Some(HeldReorg::new(fork.clone(), remote));
+                        return Ok((local, None));
+                    }
+                    self.stale.record(fork, &local);
+                }
+                Ok((remote, fork))
             }
-        } else if is_remote_valid && !is_local_valid {
-            remote
-        } else if !is_remote_valid && is_local_valid {
-            local
-        } else {
-            panic!("local and remote chains are both invalid");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::versionbits::{Deployment, Signaling, TOP_BITS};
+
+    const PREVIOUS: &str = "00004fe98736f69e54e1896fd7f0b2bdc691a680774c23f831dbe6935b872490";
+
+    #[test]
+    fn encode_header_matches_golden_vector() {
+        let header = encode_header(1, 0x2000_0001, 1_700_000_000, PREVIOUS, &[0x11; 32], 42);
+        assert_eq!(header.len(), HEADER_SIZE);
+        assert_eq!(
+            hex::encode(header),
+            "04\
+             20000001\
+             0000000000000001\
+             00004fe98736f69e54e1896fd7f0b2bdc691a680774c23f831dbe6935b872490\
+             000000006553f100\
+             1111111111111111111111111111111111111111111111111111111111111111\
+             000000000000002a"
+        );
+    }
+
+    #[test]
+    fn calculate_hash_matches_golden_vector() {
+        let hash = calculate_hash(1, 0x2000_0001, 1_700_000_000, PREVIOUS, &[0x11; 32], 42);
+        assert_eq!(
+            hex::encode(hash),
+            "e513baea5dad73d9bddd00333446c1e608bac00a41a35b7af0b2f58a39434363"
+        );
+    }
+
+    #[test]
+    fn encode_header_keeps_the_legacy_encoding_for_version_0() {
+        let header = encode_header(1, 0, 1_700_000_000, PREVIOUS, &[0x11; 32], 42);
+        assert_eq!(header.len(), LEGACY_HEADER_SIZE);
+        assert_eq!(
+            hex::encode(header),
+            "03\
+             0000000000000001\
+             00004fe98736f69e54e1896fd7f0b2bdc691a680774c23f831dbe6935b872490\
+             000000006553f100\
+             1111111111111111111111111111111111111111111111111111111111111111\
+             000000000000002a"
+        );
+    }
+
+    #[test]
+    fn calculate_hash_matches_legacy_golden_vector() {
+        let hash = calculate_hash(1, 0, 1_700_000_000, PREVIOUS, &[0x11; 32], 42);
+        assert_eq!(
+            hex::encode(hash),
+            "755c23783c4abfd5e1f67cba6e9d0335b457f65005304edd1ca8d3ae5744f5d9"
+        );
+    }
+
+    #[test]
+    fn encode_header_hashes_a_previous_hash_that_is_not_hex() {
+        let header = encode_header(0, 0, 0, "genesis", &[0; 32], 0);
+        assert_eq!(header[9..41], Sha256::digest(b"genesis")[..]);
+        let header = encode_header(0, TOP_BITS, 0, "genesis", &[0; 32], 0);
+        assert_eq!(header[13..45], Sha256::digest(b"genesis")[..]);
+    }
+
+    #[test]
+    fn genesis_block_hashes_to_its_hash() {
+        let genesis = genesis_block(&Genesis::default());
+        assert!(Header::of(&genesis).is_valid());
+        assert_eq!(genesis.hash, genesis_block(&Genesis::default()).hash);
+    }
+
+    #[test]
+    fn genesis_block_depends_on_the_parameters() {
+        let mut genesis = Genesis::default();
+        genesis.signaling.window += 1;
+        assert_ne!(
+            genesis_block(&genesis).hash,
+            genesis_block(&Genesis::default()).hash
+        );
+    }
+
+    #[test]
+    fn signed_transaction_is_valid() {
+        let tx = Transaction::new(&Keypair::generate_ed25519(), 0, 1, Bytes::from("hello"));
+        assert!(tx.is_valid());
+    }
+
+    #[test]
+    fn transaction_in_someone_elses_name_is_invalid() {
+        let (keys, other) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let mut tx = Transaction::new(&keys, 0, 1, Bytes::from("hello"));
+        tx.sender = PeerId::from(other.public()).to_string();
+        tx.id = transaction_id(&tx.sender, tx.nonce, tx.fee, tx.timestamp, &tx.payload);
+        assert!(!tx.is_valid());
+
+        // Signing the new id with a key that isn't the sender's doesn't help either.
+        tx.signature = hex::encode(keys.sign(tx.id.as_bytes()).expect("ed25519 keys can sign"));
+        assert!(!tx.is_valid());
+    }
+
+    #[test]
+    fn unsigned_transaction_is_invalid() {
+        let mut tx = Transaction::new(&Keypair::generate_ed25519(), 0, 1, Bytes::from("hello"));
+        tx.signature.clear();
+        assert!(!tx.is_valid());
+    }
+
+    fn block(height: usize, version: u32) -> Block {
+        Block {
+            height,
+            hash: String::new(),
+            previous_hash: String::new(),
+            timestamp: 0,
+            transactions: vec![],
+            nonce: 0,
+            version,
+        }
+    }
+
+    #[test]
+    fn versioned_headers_are_required_once_active() {
+        let mut deployments = Tracker::new(Signaling {
+            window: 1,
+            deployments: vec![Deployment {
+                name: VERSIONED_HEADERS.to_string(),
+                bit: 0,
+                start_height: 0,
+                timeout_height: 100,
+                threshold: 1,
+            }],
+        });
+        for height in 0..3 {
+            assert_eq!(check_rules(&block(height, 0), &deployments), Ok(()));
+            deployments.observe(&block(height, TOP_BITS | 1));
+        }
+        assert!(deployments.is_active(VERSIONED_HEADERS));
+        assert!(check_rules(&block(3, 0), &deployments).is_err());
+        assert_eq!(check_rules(&block(3, TOP_BITS), &deployments), Ok(()));
+
+        // A chain branching off before the deployment activated isn't held to it.
+        deployments.rewind(1, &[]);
+        assert!(!deployments.is_active(VERSIONED_HEADERS));
+        assert_eq!(check_rules(&block(1, 0), &deployments), Ok(()));
+    }
+
+    #[async_std::test]
+    async fn blocks_reusing_a_nonce_are_rejected() {
+        let mut node = crate::node::NodeBuilder::new().build().await.unwrap();
+        node.submit(10, Bytes::from_static(b"once")).await.unwrap();
+        let mined = node.mine().await.unwrap().unwrap();
+
+        let mut template = crate::miner::Template::new(&node.app, &crate::mempool::Mempool::new());
+        template.transactions.push(mined.transactions[1].clone());
+        assert!(!node.app.try_add_block(template.mine()).await.unwrap());
+        assert_eq!(node.app.tip().map(|tip| &tip.hash), Some(&mined.hash));
+    }
+}
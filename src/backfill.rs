@@ -0,0 +1,176 @@
+use libp2p::PeerId;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::Instant;
+
+use crate::peers::PeerManager;
+use crate::sync::{MAX_PARALLEL_REQUESTS, RANGE_LIMIT, REQUEST_TIMEOUT};
+
+// Backfill repairs the gaps in the stored chain: heights below the tip without a block, which
+// a node that crashed while writing blocks may have left behind. The missing blocks are
+// downloaded from peers, the last ones of every gap first, so that each downloaded range can
+// be checked against the stored block right after it; see App::repair.
+
+// Report summarizes the repair of the stored chain.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    // detected is how many blocks were missing at startup, in `ranges` gaps.
+    pub detected: usize,
+    pub ranges: usize,
+    pub repaired: usize,
+    // missing is how many blocks are still missing.
+    pub missing: usize,
+}
+
+// InFlight is a request for the blocks of a gap, which has to be answered with the blocks up
+// to `end`.
+#[derive(Debug)]
+struct InFlight {
+    peer: PeerId,
+    end: usize,
+    sent: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Backfill {
+    // gaps maps the start of every range of missing heights to its end.
+    gaps: BTreeMap<usize, usize>,
+    // in_flight maps the start of every requested range to the request.
+    in_flight: BTreeMap<usize, InFlight>,
+    report: Report,
+    // finished is set once the completed repair was reported.
+    finished: bool,
+}
+
+impl Backfill {
+    // new starts repairing the gaps found in storage at startup.
+    pub fn new(gaps: Vec<Range<usize>>) -> Self {
+        let report = Report {
+            detected: gaps.iter().map(ExactSizeIterator::len).sum(),
+            ranges: gaps.len(),
+            ..Report::default()
+        };
+        Self {
+            finished: gaps.is_empty(),
+            gaps: gaps.into_iter().map(|gap| (gap.start, gap.end)).collect(),
+            in_flight: BTreeMap::new(),
+            report,
+        }
+    }
+
+    // is_done returns whether the stored chain has no gaps left.
+    pub fn is_done(&self) -> bool {
+        self.gaps.is_empty()
+    }
+
+    pub fn report(&self) -> Report {
+        Report {
+            missing: self.gaps.iter().map(|(start, end)| end - start).sum(),
+            ..self.report.clone()
+        }
+    }
+
+    // finish returns the report of the repair the first time it is called once every gap was
+    // filled.
+    pub fn finish(&mut self) -> Option<Report> {
+        if self.finished || !self.is_done() {
+            return None;
+        }
+        self.finished = true;
+        Some(self.report())
+    }
+
+    // update replaces the gaps with those in storage now, as switching to another chain may
+    // have filled them in the meantime. Requests for ranges that are no longer missing are
+    // dropped.
+    pub fn update(&mut self, gaps: Vec<Range<usize>>) {
+        self.gaps = gaps.into_iter().map(|gap| (gap.start, gap.end)).collect();
+        let gaps = &self.gaps;
+        self.in_flight.retain(|start, req| {
+            let gap = gaps.range(..=*start).next_back();
+            gap.is_some_and(|(_, &end)| end == req.end)
+        });
+    }
+
+    // next_requests assigns the last range of every gap that isn't being downloaded yet to
+    // the best idle peer that has it, returning each peer along with the start of the range
+    // to request from it.
+    pub fn next_requests(&mut self, peers: &PeerManager) -> Vec<(PeerId, usize)> {
+        let mut requests = vec![];
+        for (&start, &end) in &self.gaps {
+            if self.in_flight.len() >= MAX_PARALLEL_REQUESTS {
+                break;
+            }
+            if self.in_flight.values().any(|req| req.end == end) {
+                continue;
+            }
+            let busy: Vec<PeerId> = self.in_flight.values().map(|req| req.peer).collect();
+            let idle = peers
+                .sync_peers(end - 1, MAX_PARALLEL_REQUESTS + busy.len())
+                .into_iter()
+                .find(|peer| !busy.contains(peer));
+            let Some(peer) = idle else {
+                break;
+            };
+            let start = start.max(end.saturating_sub(RANGE_LIMIT));
+            requests.push((peer, start));
+            self.in_flight.insert(
+                start,
+                InFlight {
+                    peer,
+                    end,
+                    sent: Instant::now(),
+                },
+            );
+        }
+        requests
+    }
+
+    // complete marks the request to `peer` for the range at `start` as answered, returning
+    // the heights it asked for, or None if we were not waiting on that peer for that range.
+    pub fn complete(&mut self, peer: &PeerId, start: usize) -> Option<Range<usize>> {
+        match self.in_flight.get(&start) {
+            Some(req) if req.peer == *peer => {
+                let req = self.in_flight.remove(&start)?;
+                Some(start..req.end)
+            }
+            _ => None,
+        }
+    }
+
+    // repaired records that the blocks at `heights`, which ended a gap, are stored now.
+    pub fn repaired(&mut self, heights: Range<usize>) {
+        let Some((&start, &end)) = self.gaps.range(..=heights.start).next_back() else {
+            return;
+        };
+        if end != heights.end {
+            return;
+        }
+        self.report.repaired += heights.len();
+        if start < heights.start {
+            self.gaps.insert(start, heights.start);
+        } else {
+            self.gaps.remove(&start);
+        }
+    }
+
+    // expire drops the requests that have timed out and returns the peers that failed to
+    // answer them.
+    pub fn expire(&mut self) -> Vec<PeerId> {
+        let mut expired = vec![];
+        self.in_flight.retain(|_, req| {
+            let timed_out = req.sent.elapsed() >= REQUEST_TIMEOUT;
+            if timed_out {
+                expired.push(req.peer);
+            }
+            !timed_out
+        });
+        expired
+    }
+
+    // forget drops the outstanding requests sent to a peer that went away.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.in_flight.retain(|_, req| req.peer != *peer);
+    }
+}
@@ -0,0 +1,113 @@
+use sha2::{Digest, Sha256};
+
+// CIDs name content the way IPFS does, so payloads can be referenced and retrieved by hash
+// regardless of the block they are in. mchain only makes CIDv1 of raw bytes hashed with
+// SHA-256, written in lowercase base32, e.g. "bafkrei...". Such a CID carries the same
+// digest the chunk store keys its content with.
+
+// VERSION, RAW, SHA2_256 and DIGEST_SIZE are the varint prefixes of a CIDv1 of raw bytes
+// hashed with SHA-256; all of them fit in a single byte.
+const VERSION: u8 = 0x01;
+const RAW: u8 = 0x55;
+const SHA2_256: u8 = 0x12;
+const DIGEST_SIZE: u8 = 0x20;
+
+// BASE32 is the multibase prefix of lowercase base32 without padding.
+const BASE32: char = 'b';
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// of returns the CID of the data.
+pub fn of(data: &[u8]) -> String {
+    from_digest(&Sha256::digest(data))
+}
+
+fn from_digest(digest: &[u8]) -> String {
+    let mut bytes = vec![VERSION, RAW, SHA2_256, DIGEST_SIZE];
+    bytes.extend_from_slice(digest);
+    let mut cid = String::with_capacity(60);
+    cid.push(BASE32);
+    cid.push_str(&encode_base32(&bytes));
+    cid
+}
+
+// to_hash returns the hex encoded SHA-256 digest a CID names, or None if it isn't a CID
+// mchain makes.
+pub fn to_hash(cid: &str) -> Option<String> {
+    let bytes = decode_base32(cid.strip_prefix(BASE32)?)?;
+    let digest = bytes.strip_prefix(&[VERSION, RAW, SHA2_256, DIGEST_SIZE])?;
+    (digest.len() == DIGEST_SIZE as usize).then(|| hex::encode(digest))
+}
+
+fn encode_base32(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for c in text.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u16;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cid_matches_ipfs() {
+        assert_eq!(
+            of(b""),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+    }
+
+    #[test]
+    fn cid_round_trips_to_its_hash() {
+        for data in [&b""[..], b"a", b"hello world", &[0xff; 1000]] {
+            let hash = hex::encode(Sha256::digest(data));
+            assert_eq!(to_hash(&of(data)), Some(hash));
+        }
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        for length in 0..20 {
+            let data: Vec<u8> = (0..length).map(|i| (i * 37) as u8).collect();
+            assert_eq!(decode_base32(&encode_base32(&data)), Some(data));
+        }
+    }
+
+    #[test]
+    fn other_cids_are_refused() {
+        let cid = of(b"hello world");
+        assert_eq!(to_hash(&cid[1..]), None);
+        assert_eq!(to_hash(&cid.to_uppercase()), None);
+        assert_eq!(to_hash(&cid[..cid.len() - 2]), None);
+        // A CIDv0 is base58, and doesn't start with the base32 prefix.
+        assert_eq!(
+            to_hash("QmbWqxBEKC3P8tqsKc98xmWNzrzDtRLMiMPL8wBuTGsMnR"),
+            None
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use clap::{Parser, Subcommand};
+use libp2p::{Multiaddr, PeerId};
+use mchain::{export, state};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Args are the command line options of a node. When a subcommand is given, mchain acts as a
+// client of a running node's API instead of starting a node.
+#[derive(Debug, Parser)]
+#[command(
+    name = "mchain",
+    about = "A minimal proof-of-work blockchain node",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Args {
+    /// Multiaddr of a node to dial on startup
+    pub dial: Option<Multiaddr>,
+
+    /// Address of the HTTP API; use port 0 to let the OS pick one when running several nodes
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    pub api_addr: String,
+
+    /// Multiaddr to listen on for peers
+    #[arg(long, value_name = "ADDR", default_value = "/ip4/0.0.0.0/tcp/0")]
+    pub listen: Multiaddr,
+
+    /// URI of the MongoDB server the chain is stored in
+    #[arg(long, value_name = "URI", default_value = "mongodb://localhost:27017")]
+    pub mongo_uri: String,
+
+    /// MongoDB database the chain is stored in; nodes sharing a server need distinct ones
+    #[arg(long, value_name = "NAME", default_value = "app")]
+    pub mongo_db: String,
+
+    /// TOML file with the consensus parameters of the network, e.g. its emission schedule
+    #[arg(long, value_name = "FILE")]
+    pub genesis: Option<PathBuf>,
+
+    /// Directory the node keeps its files in; defaults to the platform's data directory
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// TOML config file; it is reloaded whenever it changes [default: config.toml in the data
+    /// directory, if it exists]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Directory the chunks of anchored files are kept in [default: chunks in the data
+    /// directory; older releases kept them in ./chunks, move them over when upgrading]
+    #[arg(long, value_name = "DIR")]
+    pub chunk_dir: Option<PathBuf>,
+
+    /// Sync, validate and serve the chain without ever mining or submitting transactions
+    #[arg(long)]
+    pub observer: bool,
+
+    #[arg(long, help = format!(
+        "Keep the history of the chain's state at every height, and let peers know this node \
+         can answer name lookups older than the last {} blocks",
+        state::HISTORY_BLOCKS
+    ))]
+    pub archival: bool,
+
+    /// Derive the node's keys from this seed, for reproducible test networks; anyone who
+    /// knows the seed holds the keys
+    #[arg(long, value_name = "SEED")]
+    pub seed: Option<String>,
+
+    /// Dump every inbound and outbound pubsub message to a rotating file [default:
+    /// debug-wire.log in the data directory]
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    pub debug_wire: Option<Option<PathBuf>>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the peers of a running node
+    Peer {
+        /// URL of the node's HTTP API
+        #[arg(long, global = true, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        #[command(subcommand)]
+        action: PeerCommand,
+    },
+    /// Print the chat messages recorded on the chain of a running node
+    History {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        /// Chat topic to print
+        #[arg(long, default_value = "chat")]
+        topic: String,
+
+        /// Only print messages sent at or after this unix timestamp
+        #[arg(long, value_name = "TS", default_value_t = 0)]
+        since: i64,
+
+        /// Only include blocks with at least this many confirmations; the tip has one
+        #[arg(long, value_name = "K", default_value_t = 0)]
+        min_confirmations: usize,
+    },
+    /// Anchor the digest of a file on the chain of a running node
+    Notarize {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        file: PathBuf,
+
+        /// Text stored alongside the digest
+        #[arg(long)]
+        metadata: Option<String>,
+    },
+    /// Print a proof that a notarized file was anchored on the chain
+    Prove {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        file: PathBuf,
+
+        /// Only include blocks with at least this many confirmations; the tip has one
+        #[arg(long, value_name = "K", default_value_t = 0)]
+        min_confirmations: usize,
+    },
+    /// Anchor files on the chain of a running node and fetch them from its peers
+    File {
+        /// URL of the node's HTTP API
+        #[arg(long, global = true, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        #[command(subcommand)]
+        action: FileCommand,
+    },
+    /// Register and resolve names on the chain of a running node
+    Name {
+        /// URL of the node's HTTP API
+        #[arg(long, global = true, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        #[command(subcommand)]
+        action: NameCommand,
+    },
+    /// Suggest a fee for getting a transaction into the next block
+    Fees {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+    },
+    /// Show the consensus changes miners signal for, and how close they are to taking effect
+    Deployments {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+    },
+    /// Start a local network of nodes that bootstrap from the first one
+    Testnet {
+        /// Number of nodes to start
+        #[arg(long, default_value_t = 3)]
+        nodes: u16,
+
+        /// Directory the genesis file and the files of each node are kept in
+        #[arg(long, value_name = "DIR", default_value = "testnet")]
+        dir: PathBuf,
+
+        /// Peer port of the first node; the others use the ports after it
+        #[arg(long, value_name = "PORT", default_value_t = 4000)]
+        p2p_port: u16,
+
+        /// API port of the first node; the others use the ports after it
+        #[arg(long, value_name = "PORT", default_value_t = 8080)]
+        api_port: u16,
+
+        /// URI of the MongoDB server; every node gets a database of its own on it
+        #[arg(long, value_name = "URI", default_value = "mongodb://localhost:27017")]
+        mongo_uri: String,
+
+        /// Seed the genesis block and the keys of the nodes, so they get the same genesis
+        /// hash and peer ids on every run
+        #[arg(long, value_name = "SEED")]
+        seed: Option<String>,
+    },
+    /// Export the stored chain to a CSV or Parquet file, one row per block
+    Export {
+        /// URI of the MongoDB server the chain is stored in
+        #[arg(long, value_name = "URI", default_value = "mongodb://localhost:27017")]
+        mongo_uri: String,
+
+        /// MongoDB database the chain is stored in
+        #[arg(long, value_name = "NAME", default_value = "app")]
+        mongo_db: String,
+
+        /// Format of the export: csv or parquet
+        #[arg(long, default_value = "csv")]
+        format: export::Format,
+
+        /// Columns to export, out of height, hash, previous_hash, timestamp, size,
+        /// transactions, fees, miner and nonce
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "height,timestamp,size,miner"
+        )]
+        fields: Vec<export::Field>,
+
+        /// File to write the export to; defaults to stdout
+        #[arg(long, short, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Inspect the miner of a running node
+    Miner {
+        /// URL of the node's HTTP API
+        #[arg(long, global = true, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        #[command(subcommand)]
+        action: MinerCommand,
+    },
+    /// Print the blocks and transactions a running node rejected, most recent first
+    Rejects {
+        /// URL of the node's HTTP API
+        #[arg(long, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        /// Only print rejects of this kind: block or transaction
+        #[arg(long)]
+        kind: Option<String>,
+
+        /// Only print the rejects of this block hash or transaction id
+        #[arg(long)]
+        id: Option<String>,
+
+        /// Only print the transactions sent by this peer id
+        #[arg(long)]
+        sender: Option<String>,
+    },
+    /// Inspect or accept a reorg deeper than the node accepts on its own
+    Reorg {
+        /// URL of the node's HTTP API
+        #[arg(long, global = true, default_value = "http://127.0.0.1:8080")]
+        api: String,
+
+        #[command(subcommand)]
+        action: ReorgCommand,
+    },
+    /// Instruct nodes over libp2p as their operator, without going through their HTTP API
+    Remote {
+        /// File holding the operator key; a new key is created there if it doesn't exist
+        #[arg(
+            long,
+            global = true,
+            value_name = "FILE",
+            default_value = "operator.key"
+        )]
+        key: PathBuf,
+
+        /// Peer address of a node to instruct; repeat it to instruct a fleet
+        #[arg(long = "node", global = true, value_name = "ADDR")]
+        nodes: Vec<Multiaddr>,
+
+        #[command(subcommand)]
+        action: RemoteCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FileCommand {
+    /// Split a file into chunks, hand them to the node and anchor their hashes
+    Add { path: PathBuf },
+    /// Download an anchored file, checking every chunk against the chain
+    Get {
+        /// Id of the transaction that anchored the file
+        id: String,
+        /// Where to write the file
+        out: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NameCommand {
+    /// Claim a free name, or renew one the node already owns
+    Register {
+        name: String,
+        /// Address or peer id the name resolves to
+        address: String,
+    },
+    /// Show who owns a name and what it resolves to
+    Resolve { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MinerCommand {
+    /// Show the block the miner will try to seal next
+    Template,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ReorgCommand {
+    /// Show the reorg that is held, if any
+    Show,
+    /// Switch to the held chain, rewinding the blocks it replaces
+    Accept,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemoteCommand {
+    /// Print the peer id of the operator key, which nodes list in `operators` to trust it
+    Id,
+    /// Ask a peer for its chain and switch to it if it is better
+    Resync { peer_id: PeerId },
+    /// Disconnect a peer and refuse its connections
+    Ban {
+        peer_id: PeerId,
+
+        /// How long the ban lasts, e.g. 30m or 2h; permanent if omitted
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+
+        /// Why the peer is banned
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lift the ban on a peer
+    Unban { peer_id: PeerId },
+    /// Stop mining; pending transactions wait in the mempool
+    PauseMining,
+    /// Start mining again
+    ResumeMining,
+    /// Reload the config file
+    Reload,
+    /// Rotate the --debug-wire log
+    RotateLogs,
+    /// Replace the node's keys with new ones; the node takes them up, and with them a new peer
+    /// id, when it is restarted
+    RotateKeys,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PeerCommand {
+    /// Disconnect a peer and refuse its connections
+    Ban {
+        peer_id: PeerId,
+
+        /// How long the ban lasts, e.g. 30m or 2h; permanent if omitted
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+
+        /// Why the peer is banned
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lift the ban on a peer
+    Unban { peer_id: PeerId },
+    /// List the bans in effect
+    Bans,
+}
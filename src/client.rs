@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::error::Error;
+
+use crate::cli::{
+    Command, FileCommand, MinerCommand, NameCommand, PeerCommand, RemoteCommand, ReorgCommand,
+};
+use chrono::prelude::*;
+use libp2p::PeerId;
+use mchain::admin::{self, AdminRequest};
+use mchain::api;
+use mchain::files;
+use mchain::history::ChatEntry;
+use mchain::notary::{self, Proof};
+use mchain::payload::{FileManifest, NameClaim, Notarization, Payload};
+use mchain::rejects::{self, Reject, Subject};
+use std::fs;
+
+// run executes a subcommand against the API of a running node.
+pub async fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Peer { api, action } => match action {
+            PeerCommand::Ban {
+                peer_id,
+                duration,
+                reason,
+            } => {
+                let body = api::BanBody {
+                    peer: peer_id.to_string(),
+                    duration_secs: duration.map(|d| d.as_secs()),
+                    reason,
+                };
+                post(&api, "/admin/peers/ban", &body).await?;
+                println!("Banned {}", peer_id);
+            }
+            PeerCommand::Unban { peer_id } => {
+                let body = api::PeerBody {
+                    peer: peer_id.to_string(),
+                };
+                post(&api, "/admin/peers/unban", &body).await?;
+                println!("Unbanned {}", peer_id);
+            }
+            PeerCommand::Bans => println!("{}", get(&api, "/admin/peers/bans").await?),
+        },
+        Command::History {
+            api,
+            topic,
+            since,
+            min_confirmations,
+        } => {
+            let query = api::HistoryQuery {
+                topic,
+                since,
+                min_confirmations,
+            };
+            let path = format!("/history?{}", serde_urlencoded::to_string(&query)?);
+            let entries: Vec<ChatEntry> = serde_json::from_str(&get(&api, &path).await?)?;
+            for entry in entries {
+                let sent = Utc
+                    .timestamp_opt(entry.timestamp, 0)
+                    .single()
+                    .map_or_else(|| entry.timestamp.to_string(), |t| t.to_rfc3339());
+                println!("{} {}: {}", sent, entry.sender, entry.text);
+            }
+        }
+        Command::Notarize {
+            api,
+            file,
+            metadata,
+        } => {
+            let digest = notary::digest_file(&file)?;
+            let payload = Payload::Notary(Notarization { digest, metadata });
+            let body = post(&api, "/transactions", &payload).await?;
+            let submitted: api::Submitted = serde_json::from_str(&body)?;
+            println!("Submitted transaction {}", submitted.id);
+        }
+        Command::Prove {
+            api,
+            file,
+            min_confirmations,
+        } => {
+            let digest = notary::digest_file(&file)?;
+            let query = api::Confirmations { min_confirmations };
+            let path = format!(
+                "/proofs/{}?{}",
+                digest,
+                serde_urlencoded::to_string(&query)?
+            );
+            let body = get(&api, &path).await?;
+            let proof: Proof = serde_json::from_str(&body)?;
+            proof.verify(&digest)?;
+            println!("{}", serde_json::to_string_pretty(&proof)?);
+        }
+        Command::File { api, action } => match action {
+            FileCommand::Add { path } => {
+                let data = fs::read(&path)?;
+                let mut chunks = vec![];
+                for chunk in data.chunks(files::CHUNK_SIZE) {
+                    let body = post_bytes(&api, "/chunks", chunk).await?;
+                    let stored: api::ChunkStored = serde_json::from_str(&body)?;
+                    chunks.push(stored.hash);
+                }
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let manifest = FileManifest {
+                    name,
+                    size: data.len() as u64,
+                    chunks,
+                };
+                let body = post(&api, "/transactions", &Payload::File(manifest)).await?;
+                let submitted: api::Submitted = serde_json::from_str(&body)?;
+                println!("Submitted transaction {}", submitted.id);
+            }
+            FileCommand::Get { id, out } => {
+                let manifest: FileManifest =
+                    serde_json::from_str(&get(&api, &format!("/files/{}", id)).await?)?;
+                let mut data = Vec::with_capacity(manifest.size as usize);
+                for hash in &manifest.chunks {
+                    let chunk = get_bytes(&api, &format!("/chunks/{}", hash)).await?;
+                    if files::chunk_hash(&chunk) != *hash {
+                        return Err(format!("chunk {} does not match the chain", hash).into());
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                if data.len() as u64 != manifest.size {
+                    return Err("file size does not match the chain".into());
+                }
+                fs::write(&out, data)?;
+                println!("Wrote {} to {}", manifest.name, out.display());
+            }
+        },
+        Command::Name { api, action } => match action {
+            NameCommand::Register { name, address } => {
+                let payload = Payload::Name(NameClaim { name, address });
+                let body = post(&api, "/transactions", &payload).await?;
+                let submitted: api::Submitted = serde_json::from_str(&body)?;
+                println!("Submitted transaction {}", submitted.id);
+            }
+            NameCommand::Resolve { name } => {
+                println!("{}", get(&api, &format!("/names/{}", name)).await?)
+            }
+        },
+        Command::Fees { api } => println!("{}", get(&api, "/fees").await?),
+        Command::Deployments { api } => println!("{}", get(&api, "/deployments").await?),
+        Command::Miner { api, action } => match action {
+            MinerCommand::Template => println!("{}", get(&api, "/miner/template").await?),
+        },
+        Command::Rejects {
+            api,
+            kind,
+            id,
+            sender,
+        } => {
+            let query = rejects::Query {
+                kind,
+                id,
+                sender,
+                limit: rejects::MAX_LISTED,
+            };
+            let path = format!("/rejects?{}", serde_urlencoded::to_string(&query)?);
+            let rejects: Vec<Reject> = serde_json::from_str(&get(&api, &path).await?)?;
+            for reject in rejects {
+                let when = Utc
+                    .timestamp_opt(reject.rejected_at, 0)
+                    .single()
+                    .map_or_else(|| reject.rejected_at.to_string(), |t| t.to_rfc3339());
+                let kind = match reject.subject {
+                    Subject::Block(_) => "block",
+                    Subject::Transaction(_) => "transaction",
+                };
+                println!("{} {} {}: {}", when, kind, reject.id, reject.reason);
+            }
+        }
+        Command::Reorg { api, action } => match action {
+            ReorgCommand::Show => println!("{}", get(&api, "/admin/reorg").await?),
+            ReorgCommand::Accept => {
+                post(&api, "/admin/reorg/accept", &()).await?;
+                println!("Accepted the held reorg");
+            }
+        },
+        Command::Remote { key, nodes, action } => {
+            let keys = admin::load_key(&key)?;
+            let request = match action {
+                RemoteCommand::Id => {
+                    println!("{}", PeerId::from(keys.public()));
+                    return Ok(());
+                }
+                RemoteCommand::Resync { peer_id } => AdminRequest::Resync {
+                    peer: peer_id.to_string(),
+                },
+                RemoteCommand::Ban {
+                    peer_id,
+                    duration,
+                    reason,
+                } => AdminRequest::Ban {
+                    peer: peer_id.to_string(),
+                    duration_secs: duration.map(|d| d.as_secs()),
+                    reason,
+                },
+                RemoteCommand::Unban { peer_id } => AdminRequest::Unban {
+                    peer: peer_id.to_string(),
+                },
+                RemoteCommand::PauseMining => AdminRequest::SetMining { enabled: false },
+                RemoteCommand::ResumeMining => AdminRequest::SetMining { enabled: true },
+                RemoteCommand::Reload => AdminRequest::ReloadConfig,
+                RemoteCommand::RotateLogs => AdminRequest::RotateLogs,
+                RemoteCommand::RotateKeys => AdminRequest::RotateKeys,
+            };
+            if nodes.is_empty() {
+                return Err("no node to instruct, give at least one --node".into());
+            }
+            let outcomes = admin::send(keys, &nodes, request).await?;
+            let mut failed = 0;
+            for (node, outcome) in nodes.iter().zip(outcomes) {
+                match outcome {
+                    Ok(()) => println!("{}: done", node),
+                    Err(e) => {
+                        println!("{}: {}", node, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if failed > 0 {
+                return Err(format!("{} of {} nodes failed", failed, nodes.len()).into());
+            }
+        }
+        Command::Testnet { .. } => unreachable!("testnet starts nodes rather than calling one"),
+        Command::Export { .. } => unreachable!("export reads storage rather than calling a node"),
+    }
+    Ok(())
+}
+
+// get fetches the path from the node's API and returns the response body.
+async fn get(api: &str, path: &str) -> Result<String, Box<dyn Error>> {
+    let mut res = surf::get(format!(
+        "{}{}{}",
+        api.trim_end_matches('/'),
+        api::API_VERSION,
+        path
+    ))
+    .await
+    .map_err(|e| e.to_string())?;
+    let body = res.body_string().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", res.status(), body).into());
+    }
+    Ok(body)
+}
+
+// get_bytes fetches the path from the node's API and returns the raw response body.
+async fn get_bytes(api: &str, path: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut res = surf::get(format!(
+        "{}{}{}",
+        api.trim_end_matches('/'),
+        api::API_VERSION,
+        path
+    ))
+    .await
+    .map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", res.status(), path).into());
+    }
+    Ok(res.body_bytes().await.map_err(|e| e.to_string())?)
+}
+
+// post_bytes sends raw bytes to the node's API and returns the response body.
+async fn post_bytes(api: &str, path: &str, data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let mut res = surf::post(format!(
+        "{}{}{}",
+        api.trim_end_matches('/'),
+        api::API_VERSION,
+        path
+    ))
+    .body_bytes(data)
+    .await
+    .map_err(|e| e.to_string())?;
+    let body = res.body_string().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", res.status(), body).into());
+    }
+    Ok(body)
+}
+
+// post sends the body as JSON to the node's API and returns the response body, failing
+// unless the request succeeded.
+async fn post<T: Serialize>(api: &str, path: &str, body: &T) -> Result<String, Box<dyn Error>> {
+    let mut res = surf::post(format!(
+        "{}{}{}",
+        api.trim_end_matches('/'),
+        api::API_VERSION,
+        path
+    ))
+    .body_json(body)
+    .map_err(|e| e.to_string())?
+    .await
+    .map_err(|e| e.to_string())?;
+    let body = res.body_string().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("{}: {}", res.status(), body).into());
+    }
+    Ok(body)
+}
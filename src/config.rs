@@ -0,0 +1,232 @@
+use libp2p::swarm::ConnectionLimits;
+use libp2p::Multiaddr;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::miner;
+
+// Config holds the settings read from the config file. Every field but the connection limits
+// and telemetry can be changed while the node is running; apply_config in main.rs switches a
+// running node over to a new config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // log_level is the most verbose level that is logged, e.g. "info" or "debug". Without it,
+    // RUST_LOG decides what is logged as usual.
+    pub log_level: Option<String>,
+    // max_messages_per_sec is how many gossip messages we accept relayed by a single peer each
+    // second; 0 disables the limit.
+    pub max_messages_per_sec: u32,
+    // bootstrap lists nodes that are dialed on startup and whenever they are added.
+    pub bootstrap: Vec<Multiaddr>,
+    // target_block_secs is how often we expect new blocks. When none arrives for
+    // stale_tip_multiple times as long while peers are ahead, the node resyncs on its own.
+    pub target_block_secs: u64,
+    pub stale_tip_multiple: u32,
+    // max_clock_drift_secs is how far our clock may be off from our peers' before we stop
+    // mining, since the blocks we seal would carry wrong timestamps; 0 disables the check. It
+    // only applies once enough connected peers reported their time; see MIN_CLOCK_PEERS.
+    pub max_clock_drift_secs: u64,
+    // max_reorg_depth is how many of our blocks a competing chain may rewind before it has to
+    // be accepted by an operator; 0 disables the limit.
+    pub max_reorg_depth: usize,
+    // cids enables content addressing: listed transactions carry the CID of their payload,
+    // and the payloads of new blocks are kept in the chunk store, so they can be fetched by
+    // CID from us and our peers.
+    pub cids: bool,
+    // signal names the deployments of the genesis our miner signals readiness for, i.e. the
+    // consensus changes this node is ready to enforce.
+    pub signal: Vec<String>,
+    // mining chooses which pending transactions the miner picks up: sources is "local" for
+    // those submitted to this node, "remote" for those gossiped by peers, or "all", and
+    // local_quota_bytes and remote_quota_bytes bound how much of a block each may fill.
+    pub mining: miner::Policy,
+    // operators lists the peer ids of the operator keys whose admin commands the node acts
+    // on over libp2p; see `mchain remote`.
+    pub operators: Vec<String>,
+    pub api: ApiConfig,
+    pub limits: LimitsConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+// ApiConfig toggles parts of the HTTP API without restarting the listener.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub admin: bool,
+}
+
+// LimitsConfig bounds the connections and streams peers can open, so a flood of them can't
+// exhaust the node's resources. They only take effect on startup; 0 disables a limit.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // max_connections bounds the established connections, incoming and outgoing together.
+    pub max_connections: u32,
+    pub max_connections_per_peer: u32,
+    // max_pending_incoming and max_pending_outgoing bound the connections being negotiated,
+    // i.e. accepted but not yet established, and dials in flight.
+    pub max_pending_incoming: u32,
+    pub max_pending_outgoing: u32,
+    // max_negotiating_streams bounds the inbound streams being negotiated on each connection.
+    pub max_negotiating_streams: usize,
+}
+
+// TelemetryConfig ships traces of sync rounds and API requests, along with every metric, to
+// an OpenTelemetry backend. It only takes effect on startup.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // otlp_endpoint is the base URL of an OTLP over HTTP receiver, e.g.
+    // "http://localhost:4318"; telemetry is off without one.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    pub export_interval_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: None,
+            max_messages_per_sec: 100,
+            bootstrap: vec![],
+            target_block_secs: 10,
+            stale_tip_multiple: 6,
+            max_clock_drift_secs: 120,
+            max_reorg_depth: 100,
+            cids: false,
+            signal: vec![],
+            mining: miner::Policy::default(),
+            operators: vec![],
+            api: ApiConfig::default(),
+            limits: LimitsConfig::default(),
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            admin: true,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 128,
+            max_connections_per_peer: 2,
+            max_pending_incoming: 32,
+            max_pending_outgoing: 32,
+            max_negotiating_streams: 128,
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "mchain".to_string(),
+            export_interval_secs: 10,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn export_interval(&self) -> Duration {
+        Duration::from_secs(self.export_interval_secs.max(1))
+    }
+}
+
+impl LimitsConfig {
+    // connection_limits returns the limits in the form the swarm takes them.
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits::default()
+            .with_max_established(limit(self.max_connections))
+            .with_max_established_per_peer(limit(self.max_connections_per_peer))
+            .with_max_pending_incoming(limit(self.max_pending_incoming))
+            .with_max_pending_outgoing(limit(self.max_pending_outgoing))
+    }
+
+    // negotiating_streams returns max_negotiating_streams, or no limit at all for 0.
+    pub fn negotiating_streams(&self) -> usize {
+        match self.max_negotiating_streams {
+            0 => usize::MAX,
+            max => max,
+        }
+    }
+}
+
+fn limit(max: u32) -> Option<u32> {
+    (max > 0).then_some(max)
+}
+
+impl Config {
+    // load reads the config file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let config: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        config.log_level()?;
+        Ok(config)
+    }
+
+    // stale_tip_after is how long the tip may stay put while peers are ahead.
+    pub fn stale_tip_after(&self) -> Duration {
+        Duration::from_secs(self.target_block_secs) * self.stale_tip_multiple
+    }
+
+    // reorg_limit returns max_reorg_depth, or None if reorgs of any depth are accepted.
+    pub fn reorg_limit(&self) -> Option<usize> {
+        (self.max_reorg_depth > 0).then_some(self.max_reorg_depth)
+    }
+
+    pub fn log_level(&self) -> Result<Option<LevelFilter>, Box<dyn Error>> {
+        let Some(level) = &self.log_level else {
+            return Ok(None);
+        };
+        match level.parse() {
+            Ok(level) => Ok(Some(level)),
+            Err(_) => Err(format!("invalid log level {:?}", level).into()),
+        }
+    }
+}
+
+// Watcher notices when the config file is modified by polling its modification time.
+#[derive(Debug)]
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl Watcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified(&path);
+        Self { path, modified }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // changed returns true once after each modification of the file.
+    pub fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
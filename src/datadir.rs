@@ -0,0 +1,55 @@
+use directories::ProjectDirs;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// DataDir is where a node keeps its files. It defaults to the platform's data directory,
+// e.g. ~/.local/share/mchain on Linux, ~/Library/Application Support/mchain on macOS and
+// %APPDATA%\mchain\data on Windows.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+    root: PathBuf,
+}
+
+impl DataDir {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    // platform returns the platform's data directory for mchain, falling back to the current
+    // directory on systems without a home directory.
+    pub fn platform() -> Self {
+        let root = ProjectDirs::from("", "", "mchain")
+            .map_or_else(|| PathBuf::from("."), |dirs| dirs.data_dir().to_path_buf());
+        Self::new(root)
+    }
+
+    // create makes sure the directory exists.
+    pub fn create(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // keys is where the node's identity is kept.
+    pub fn keys(&self) -> PathBuf {
+        self.root.join("keys")
+    }
+
+    // config is the config file read when --config isn't given, if it exists.
+    pub fn config(&self) -> PathBuf {
+        self.root.join("config.toml")
+    }
+
+    // chunks is where the chunks of anchored files are kept.
+    pub fn chunks(&self) -> PathBuf {
+        self.root.join("chunks")
+    }
+
+    // wire_log is where --debug-wire writes when no file is given.
+    pub fn wire_log(&self) -> PathBuf {
+        self.root.join("debug-wire.log")
+    }
+}
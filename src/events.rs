@@ -0,0 +1,190 @@
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use crate::app::{Block, Transaction};
+use crate::fork::{self, Branch};
+use crate::metrics;
+use crate::payload::Payload;
+
+// SUBSCRIPTION_BUFFER is how many events a subscriber can fall behind before it misses some.
+const SUBSCRIPTION_BUFFER: usize = 1024;
+
+// SUBSCRIBERS are the open event streams.
+static SUBSCRIBERS: Lazy<Mutex<Vec<Subscriber>>> = Lazy::new(|| Mutex::new(vec![]));
+
+// Subscriber is an open event stream along with the filter it asked for.
+struct Subscriber {
+    filter: Filter,
+    sender: Sender<Event>,
+}
+
+// Event is a notable change in the node's view of the chain.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    // Fork is emitted when a peer's chain diverges from ours, whichever branch won.
+    Fork { peer: String, fork: fork::Fork },
+    // Block is emitted for every block added to the chain, including those of a chain we
+    // switched to.
+    Block { block: Block },
+    // Health is emitted when the node gets stuck on a stale tip while peers are ahead, when
+    // it starts repairing gaps in the stored chain, and when it recovers.
+    Health { status: Health, height: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    Degraded,
+    // Repairing means blocks below the tip are missing from storage and being backfilled.
+    Repairing,
+    Ok,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Fork { .. } => "fork",
+            Event::Block { .. } => "block",
+            Event::Health { .. } => "health",
+        }
+    }
+}
+
+// Filter narrows the block events a subscriber receives to the transactions it cares about.
+// Blocks keep only the matching transactions and are left out if none match. Fork events
+// are always delivered.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Filter {
+    // kind keeps the transactions whose payload has this type, e.g. "chat" or "name".
+    pub kind: Option<String>,
+    pub sender: Option<String>,
+    // prefix keeps the transactions whose payload key starts with it; see Payload::key.
+    pub prefix: Option<String>,
+}
+
+impl Filter {
+    fn is_empty(&self) -> bool {
+        self.kind.is_none() && self.sender.is_none() && self.prefix.is_none()
+    }
+
+    fn matches(&self, tx: &Transaction) -> bool {
+        if self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| *sender != tx.sender)
+        {
+            return false;
+        }
+        if self.kind.is_none() && self.prefix.is_none() {
+            return true;
+        }
+        let Some(payload) = Payload::decode(&tx.payload) else {
+            return false;
+        };
+        if self
+            .kind
+            .as_ref()
+            .is_some_and(|kind| kind != payload.kind())
+        {
+            return false;
+        }
+        match &self.prefix {
+            Some(prefix) => payload.key().is_some_and(|key| key.starts_with(prefix)),
+            None => true,
+        }
+    }
+
+    // apply returns the event as the subscriber should see it, or None if nothing in it
+    // matches.
+    fn apply(&self, event: &Event) -> Option<Event> {
+        match event {
+            Event::Block { block } if !self.is_empty() => {
+                let transactions: Vec<Transaction> = block
+                    .transactions
+                    .iter()
+                    .filter(|tx| self.matches(tx))
+                    .cloned()
+                    .collect();
+                if transactions.is_empty() {
+                    return None;
+                }
+                let block = Block {
+                    transactions,
+                    ..block.clone()
+                };
+                Some(Event::Block { block })
+            }
+            _ => Some(event.clone()),
+        }
+    }
+}
+
+// subscribe opens a stream of the events that pass the filter. The subscription ends when
+// the receiver is dropped.
+pub fn subscribe(filter: Filter) -> Receiver<Event> {
+    let (sender, receiver) = channel::bounded(SUBSCRIPTION_BUFFER);
+    SUBSCRIBERS
+        .lock()
+        .expect("subscribers lock is not poisoned")
+        .push(Subscriber { filter, sender });
+    receiver
+}
+
+// emit reports the event in the logs, updates the related metrics and hands it to the
+// subscribers.
+pub fn emit(event: Event) {
+    match &event {
+        Event::Fork { fork, .. } => {
+            let winner = match fork.winner {
+                Branch::Local => "local",
+                Branch::Remote => "remote",
+            };
+            metrics::FORKS.with_label_values(&[winner]).inc();
+            metrics::FORK_DEPTH.observe(fork.depth as f64);
+            if fork.winner == Branch::Remote {
+                log::warn!(
+                    "Reorg: dropped {} blocks after height {}, new tip {}",
+                    fork.depth,
+                    fork.height,
+                    fork.remote_tip
+                );
+            }
+            match serde_json::to_string(&event) {
+                Ok(json) => log::info!("event: {}", json),
+                Err(e) => log::error!("could not serialize event: {}", e),
+            }
+        }
+        Event::Health { status, height } => {
+            metrics::DEGRADED.set(i64::from(*status == Health::Degraded));
+            match status {
+                Health::Degraded => log::warn!("Tip is stale at height {}, resyncing", height),
+                Health::Repairing => {
+                    log::warn!("Stored chain has gaps below height {}, backfilling", height)
+                }
+                Health::Ok => log::info!("Healthy again at height {}", height),
+            }
+        }
+        // Blocks are logged where they are added.
+        Event::Block { .. } => {}
+    }
+
+    let mut subscribers = SUBSCRIBERS
+        .lock()
+        .expect("subscribers lock is not poisoned");
+    subscribers.retain(|subscriber| {
+        let Some(event) = subscriber.filter.apply(&event) else {
+            return !subscriber.sender.is_closed();
+        };
+        match subscriber.sender.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("Event subscriber is falling behind, dropping an event");
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    });
+}
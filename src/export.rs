@@ -0,0 +1,271 @@
+use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::app::Block;
+use crate::miner;
+use crate::storage::Storage;
+
+// BATCH_SIZE is how many blocks are read from storage at a time. Each batch becomes a row
+// group of a Parquet file.
+const BATCH_SIZE: usize = 1000;
+
+// Format is the file format the chain is exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(format!("unknown format {:?}, expected csv or parquet", s)),
+        }
+    }
+}
+
+// Field is a column of the export, with one row per block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Height,
+    Hash,
+    PreviousHash,
+    Timestamp,
+    // Size is the serialized size of the block's transactions, as the miner counts it.
+    Size,
+    Transactions,
+    Fees,
+    // Miner is the sender of the block's coinbase.
+    Miner,
+    Nonce,
+}
+
+// FIELDS lists every field, in the order they are documented.
+pub const FIELDS: [Field; 9] = [
+    Field::Height,
+    Field::Hash,
+    Field::PreviousHash,
+    Field::Timestamp,
+    Field::Size,
+    Field::Transactions,
+    Field::Fees,
+    Field::Miner,
+    Field::Nonce,
+];
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Height => "height",
+            Field::Hash => "hash",
+            Field::PreviousHash => "previous_hash",
+            Field::Timestamp => "timestamp",
+            Field::Size => "size",
+            Field::Transactions => "transactions",
+            Field::Fees => "fees",
+            Field::Miner => "miner",
+            Field::Nonce => "nonce",
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        matches!(self, Field::Hash | Field::PreviousHash | Field::Miner)
+    }
+
+    fn value(&self, block: &Block) -> Value {
+        match self {
+            Field::Height => Value::Int(block.height as i64),
+            Field::Hash => Value::Text(block.hash.clone()),
+            Field::PreviousHash => Value::Text(block.previous_hash.clone()),
+            Field::Timestamp => Value::Int(block.timestamp),
+            Field::Size => Value::Int(miner::block_size(block) as i64),
+            Field::Transactions => Value::Int(block.transactions.len() as i64),
+            Field::Fees => Value::Int(block.transactions.iter().map(|tx| tx.fee as i64).sum()),
+            Field::Miner => Value::Text(
+                block
+                    .transactions
+                    .first()
+                    .filter(|tx| tx.coinbase().is_some())
+                    .map(|tx| tx.sender.clone())
+                    .unwrap_or_default(),
+            ),
+            Field::Nonce => Value::Int(block.nonce as i64),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FIELDS
+            .into_iter()
+            .find(|field| field.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = FIELDS.iter().map(Field::name).collect();
+                format!("unknown field {:?}, expected one of {}", s, names.join(","))
+            })
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+enum Value {
+    Int(i64),
+    Text(String),
+}
+
+// export streams the chain from storage into `out`, one row per block with the given
+// fields, and returns how many blocks were written.
+pub async fn export<W: Write + Send>(
+    storage: &dyn Storage,
+    format: Format,
+    fields: &[Field],
+    out: W,
+) -> Result<usize, Box<dyn Error>> {
+    let mut sink: Box<dyn Sink + '_> = match format {
+        Format::Csv => Box::new(CsvSink::new(fields, out)?),
+        Format::Parquet => Box::new(ParquetSink::new(fields, out)?),
+    };
+    let height = storage.height().await?;
+    let mut start = 0;
+    while start < height {
+        let blocks = storage.range(start, start + BATCH_SIZE).await?;
+        sink.write(&blocks)?;
+        start += BATCH_SIZE;
+    }
+    sink.finish()?;
+    Ok(height)
+}
+
+// Sink writes batches of blocks in one of the formats.
+trait Sink {
+    fn write(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>>;
+    fn finish(self: Box<Self>) -> Result<(), Box<dyn Error>>;
+}
+
+struct CsvSink<W: Write> {
+    fields: Vec<Field>,
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvSink<W> {
+    fn new(fields: &[Field], out: W) -> Result<Self, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(out);
+        writer.write_record(fields.iter().map(Field::name))?;
+        Ok(Self {
+            fields: fields.to_vec(),
+            writer,
+        })
+    }
+}
+
+impl<W: Write> Sink for CsvSink<W> {
+    fn write(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        for block in blocks {
+            let record = self.fields.iter().map(|field| match field.value(block) {
+                Value::Int(n) => n.to_string(),
+                Value::Text(s) => s,
+            });
+            self.writer.write_record(record)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct ParquetSink<W: Write + Send> {
+    fields: Vec<Field>,
+    writer: SerializedFileWriter<W>,
+}
+
+impl<W: Write + Send> ParquetSink<W> {
+    fn new(fields: &[Field], out: W) -> Result<Self, Box<dyn Error>> {
+        let mut columns = vec![];
+        for field in fields {
+            let column = if field.is_text() {
+                Type::primitive_type_builder(field.name(), PhysicalType::BYTE_ARRAY)
+                    .with_converted_type(ConvertedType::UTF8)
+            } else {
+                Type::primitive_type_builder(field.name(), PhysicalType::INT64)
+            };
+            columns.push(Arc::new(
+                column.with_repetition(Repetition::REQUIRED).build()?,
+            ));
+        }
+        let schema = Type::group_type_builder("block")
+            .with_fields(columns)
+            .build()?;
+        let writer = SerializedFileWriter::new(
+            out,
+            Arc::new(schema),
+            Arc::new(WriterProperties::builder().build()),
+        )?;
+        Ok(Self {
+            fields: fields.to_vec(),
+            writer,
+        })
+    }
+}
+
+impl<W: Write + Send> Sink for ParquetSink<W> {
+    fn write(&mut self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        let mut row_group = self.writer.next_row_group()?;
+        for field in &self.fields {
+            let mut column = row_group
+                .next_column()?
+                .expect("the schema has a column per field");
+            if field.is_text() {
+                let values: Vec<ByteArray> = blocks
+                    .iter()
+                    .map(|block| match field.value(block) {
+                        Value::Text(s) => ByteArray::from(s.into_bytes()),
+                        Value::Int(n) => ByteArray::from(n.to_string().into_bytes()),
+                    })
+                    .collect();
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            } else {
+                let values: Vec<i64> = blocks
+                    .iter()
+                    .map(|block| match field.value(block) {
+                        Value::Int(n) => n,
+                        Value::Text(_) => 0,
+                    })
+                    .collect();
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            column.close()?;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), Box<dyn Error>> {
+        self.writer.close()?;
+        Ok(())
+    }
+}
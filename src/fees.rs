@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::mempool::Mempool;
+use crate::miner::Template;
+
+// FEE_WINDOW is how many recent blocks the fee estimate looks back on.
+const FEE_WINDOW: usize = 20;
+
+// FeeEstimate is the fee suggested for a transaction to be mined in the next block, along
+// with what it was derived from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeEstimate {
+    pub fee: u64,
+    // recent_median is the median of the lowest fee paid in each of the recent blocks.
+    pub recent_median: u64,
+    // mempool_cutoff is the fee needed to outbid the pending transactions that would not make
+    // it into the next block, if there are any.
+    pub mempool_cutoff: Option<u64>,
+    // blocks is how many recent blocks the median was taken over.
+    pub blocks: usize,
+}
+
+// estimate suggests a fee for next block inclusion. It is the higher of what recently got
+// into blocks and what it takes to beat the current backlog.
+pub fn estimate(app: &App, mempool: &Mempool) -> FeeEstimate {
+    let mut minimums: Vec<u64> = app
+        .recent()
+        .rev()
+        .take(FEE_WINDOW)
+        .filter_map(|block| {
+            block
+                .transactions
+                .iter()
+                .filter(|tx| tx.coinbase().is_none())
+                .map(|tx| tx.fee)
+                .min()
+        })
+        .collect();
+    minimums.sort_unstable();
+    let recent_median = minimums.get(minimums.len() / 2).copied().unwrap_or(0);
+
+    // The template holds the coinbase on top of the pending transactions it selected.
+    let template = Template::new(app, mempool);
+    let selected = template.transactions.len() - 1;
+    let mempool_cutoff = (selected < mempool.len()).then(|| {
+        template
+            .transactions
+            .iter()
+            .skip(1)
+            .map(|tx| tx.fee)
+            .min()
+            .unwrap_or(0)
+            + 1
+    });
+
+    FeeEstimate {
+        fee: recent_median.max(mempool_cutoff.unwrap_or(0)),
+        recent_median,
+        mempool_cutoff,
+        blocks: minimums.len(),
+    }
+}
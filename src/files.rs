@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
+use libp2p::request_response::{RequestId, RequestResponseCodec};
+use libp2p::{PeerId, Swarm};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::app::{App, Block};
+use crate::cid;
+use crate::history::HISTORY_BATCH;
+use crate::p2p::AppBehavior;
+use crate::payload::{FileManifest, Payload};
+use crate::storage::{self, Storage};
+
+// CHUNK_SIZE is the size of every chunk of a file but the last.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+// chunk_hash returns the hex encoded SHA-256 digest a chunk is anchored and stored under.
+pub fn chunk_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// is_chunk_hash guards the chunk store against names that aren't a digest, like "../x".
+fn is_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// ChunkStore keeps file chunks on disk, one file per chunk named after its hash.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    // put stores the chunk and returns its hash.
+    pub fn put(&self, data: &[u8]) -> io::Result<String> {
+        if data.len() > CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunks are at most {} bytes", CHUNK_SIZE),
+            ));
+        }
+        let hash = chunk_hash(data);
+        fs::write(self.dir.join(&hash), data)?;
+        Ok(hash)
+    }
+
+    // get returns the content with the given hash, which peers may also name by its CID.
+    pub fn get(&self, hash: &str) -> io::Result<Option<Bytes>> {
+        let hash = cid::to_hash(hash).unwrap_or_else(|| hash.to_string());
+        if !is_chunk_hash(&hash) {
+            return Ok(None);
+        }
+        match fs::read(self.dir.join(&hash)) {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // keep_payloads stores the payloads of the block's transactions, so they can be fetched
+    // by CID. Payloads larger than a chunk are left out.
+    pub fn keep_payloads(&self, block: &Block) -> io::Result<()> {
+        for tx in &block.transactions {
+            if !tx.payload.is_empty() && tx.payload.len() <= CHUNK_SIZE {
+                self.put(&tx.payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    // keep_chain_payloads keeps the payloads of the first `until` blocks in storage, e.g. when
+    // content addressing is turned on for a chain that already has blocks.
+    pub async fn keep_chain_payloads(
+        &self,
+        storage: Arc<dyn Storage>,
+        until: usize,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for start in (0..until).step_by(HISTORY_BATCH) {
+            for block in storage
+                .range(start, (start + HISTORY_BATCH).min(until))
+                .await?
+            {
+                self.keep_payloads(&block)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// find_manifest walks the first `until` blocks of the chain for the transaction with the
+// given id and returns the file manifest it anchored.
+pub async fn find_manifest(
+    app: &App,
+    tx_id: &str,
+    until: usize,
+) -> Result<Option<FileManifest>, storage::Error> {
+    let mut start = 0;
+    while start < until {
+        for block in app.range(start, (start + HISTORY_BATCH).min(until)).await? {
+            let Some(tx) = block.transactions.iter().find(|tx| tx.id == tx_id) else {
+                continue;
+            };
+            return match Payload::decode(&tx.payload) {
+                Some(Payload::File(manifest)) => Ok(Some(manifest)),
+                _ => Ok(None),
+            };
+        }
+        start += HISTORY_BATCH;
+    }
+    Ok(None)
+}
+
+// ChunkProtocol is the request-response protocol peers fetch chunks from each other with.
+#[derive(Debug, Clone)]
+pub struct ChunkProtocol;
+
+impl ProtocolName for ChunkProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/mchain/chunks/1"
+    }
+}
+
+// ChunkRequest asks for the chunk with the given hash.
+#[derive(Debug, Clone)]
+pub struct ChunkRequest(pub String);
+
+// ChunkResponse carries the requested chunk, or None if the peer doesn't have it.
+#[derive(Debug, Clone)]
+pub struct ChunkResponse(pub Option<Bytes>);
+
+// ChunkCodec writes requests and responses as length prefixed bytes. An empty response means
+// the chunk was not found, which is unambiguous because files are never split into empty
+// chunks.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkCodec;
+
+#[async_trait]
+impl RequestResponseCodec for ChunkCodec {
+    type Protocol = ChunkProtocol;
+    type Request = ChunkRequest;
+    type Response = ChunkResponse;
+
+    async fn read_request<T>(&mut self, _: &ChunkProtocol, io: &mut T) -> io::Result<ChunkRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 64).await?;
+        let hash =
+            String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(ChunkRequest(hash))
+    }
+
+    async fn read_response<T>(&mut self, _: &ChunkProtocol, io: &mut T) -> io::Result<ChunkResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, CHUNK_SIZE).await?;
+        if data.is_empty() {
+            return Ok(ChunkResponse(None));
+        }
+        Ok(ChunkResponse(Some(data.into())))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &ChunkProtocol,
+        io: &mut T,
+        ChunkRequest(hash): ChunkRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, hash).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &ChunkProtocol,
+        io: &mut T,
+        ChunkResponse(data): ChunkResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, data.unwrap_or_default()).await
+    }
+}
+
+// Fetch is a chunk being requested from peers, one peer at a time.
+struct Fetch {
+    hash: String,
+    // peers are the peers left to ask if the current one fails.
+    peers: Vec<PeerId>,
+    reply: oneshot::Sender<Result<Option<Bytes>, String>>,
+}
+
+// Fetches tracks the chunk requests in flight.
+#[derive(Default)]
+pub struct Fetches {
+    pending: HashMap<RequestId, Fetch>,
+}
+
+impl Fetches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // start asks the peers for the chunk in turn until one returns it, answering None once
+    // every peer has been asked.
+    pub fn start(
+        &mut self,
+        swarm: &mut Swarm<AppBehavior>,
+        hash: String,
+        peers: Vec<PeerId>,
+        reply: oneshot::Sender<Result<Option<Bytes>, String>>,
+    ) {
+        self.next(swarm, Fetch { hash, peers, reply });
+    }
+
+    fn next(&mut self, swarm: &mut Swarm<AppBehavior>, mut fetch: Fetch) {
+        let Some(peer) = fetch.peers.pop() else {
+            let _ = fetch.reply.send(Ok(None));
+            return;
+        };
+        let request = ChunkRequest(fetch.hash.clone());
+        let id = swarm.behaviour_mut().chunks.send_request(&peer, request);
+        self.pending.insert(id, fetch);
+    }
+
+    // complete handles a peer's response, keeping the chunk if it matches the hash asked for.
+    pub fn complete(
+        &mut self,
+        swarm: &mut Swarm<AppBehavior>,
+        store: &ChunkStore,
+        id: RequestId,
+        response: ChunkResponse,
+    ) {
+        let Some(fetch) = self.pending.remove(&id) else {
+            return;
+        };
+        match response.0 {
+            Some(data) if chunk_hash(&data) == fetch.hash => {
+                let result = store.put(&data).map(|_| Some(data));
+                let _ = fetch.reply.send(result.map_err(|e| e.to_string()));
+            }
+            _ => self.next(swarm, fetch),
+        }
+    }
+
+    // fail moves on to the next peer after a request failed.
+    pub fn fail(&mut self, swarm: &mut Swarm<AppBehavior>, id: RequestId) {
+        if let Some(fetch) = self.pending.remove(&id) {
+            self.next(swarm, fetch);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use chrono::prelude::*;
+use serde::Serialize;
+use std::collections::VecDeque;
+
+use crate::app::Block;
+
+// MAX_STALE_BLOCKS bounds how many orphaned blocks we keep around for debugging.
+const MAX_STALE_BLOCKS: usize = 256;
+
+// Branch identifies which side of a fork a block or chain came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Branch {
+    Local,
+    Remote,
+}
+
+// Fork describes two chains that share a prefix and then diverge.
+#[derive(Debug, Clone, Serialize)]
+pub struct Fork {
+    // height is the number of blocks both chains have in common.
+    pub height: usize,
+    // depth is how many blocks the losing branch had past the fork point.
+    pub depth: usize,
+    pub winner: Branch,
+    pub local_tip: String,
+    pub remote_tip: String,
+}
+
+impl Fork {
+    // between returns the fork between the two chains, or None when one chain simply
+    // extends the other. Both chains start at the same height, which need not be the genesis.
+    pub fn between(local: &[Block], remote: &[Block], winner: Branch) -> Option<Self> {
+        let common = local
+            .iter()
+            .zip(remote)
+            .take_while(|(l, r)| l.hash == r.hash)
+            .count();
+        if common == local.len() || common == remote.len() {
+            return None;
+        }
+
+        let loser_len = match winner {
+            Branch::Local => remote.len(),
+            Branch::Remote => local.len(),
+        };
+        Some(Self {
+            height: local[0].height + common,
+            depth: loser_len - common,
+            winner,
+            local_tip: local.last()?.hash.clone(),
+            remote_tip: remote.last()?.hash.clone(),
+        })
+    }
+}
+
+// StaleBlock is a block that lost a fork, kept along with why it was orphaned.
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleBlock {
+    pub block: Block,
+    pub branch: Branch,
+    pub fork_height: usize,
+    // replaced_by is the tip hash of the branch that won.
+    pub replaced_by: String,
+    pub orphaned_at: i64,
+}
+
+// StaleBlocks is a bounded store of recently orphaned blocks; the oldest are evicted first.
+#[derive(Debug, Default)]
+pub struct StaleBlocks {
+    blocks: VecDeque<StaleBlock>,
+}
+
+impl StaleBlocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // record stores the losing branch of a fork.
+    pub fn record(&mut self, fork: &Fork, losing: &[Block]) {
+        let (branch, replaced_by) = match fork.winner {
+            Branch::Local => (Branch::Remote, &fork.local_tip),
+            Branch::Remote => (Branch::Local, &fork.remote_tip),
+        };
+        let now = Utc::now().timestamp();
+        for block in losing.iter().filter(|block| block.height >= fork.height) {
+            if self.blocks.len() == MAX_STALE_BLOCKS {
+                self.blocks.pop_front();
+            }
+            self.blocks.push_back(StaleBlock {
+                block: block.clone(),
+                branch,
+                fork_height: fork.height,
+                replaced_by: replaced_by.clone(),
+                orphaned_at: now,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    // list returns the stored blocks, most recently orphaned first.
+    pub fn list(&self) -> Vec<StaleBlock> {
+        self.blocks.iter().rev().cloned().collect()
+    }
+}
+
+// HeldReorg is a remote chain that won a fork but would rewind more blocks than the node
+// accepts on its own. It waits for an operator to accept it.
+#[derive(Debug, Clone, Serialize)]
+pub struct HeldReorg {
+    pub fork: Fork,
+    pub held_at: i64,
+    #[serde(skip)]
+    pub chain: Vec<Block>,
+}
+
+impl HeldReorg {
+    pub fn new(fork: Fork, chain: Vec<Block>) -> Self {
+        Self {
+            fork,
+            held_at: Utc::now().timestamp(),
+            chain,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::versionbits::Signaling;
+
+// Genesis holds the consensus parameters a network is started with. Unlike Config, it can't
+// change while the node runs: every node on a network has to agree on it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Genesis {
+    // seed is mixed into the genesis block, so a test network gets a chain of its own even
+    // with otherwise default parameters, and the same genesis hash on every run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<String>,
+    pub emission: Emission,
+    // signaling lists the consensus changes miners can signal readiness for.
+    pub signaling: Signaling,
+}
+
+impl Genesis {
+    // load reads the genesis file at `path`.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let genesis: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        genesis.signaling.check()?;
+        Ok(genesis)
+    }
+
+    // hash is the SHA-256 hash of the parameters' JSON encoding, whose fields are always in the
+    // same order. The genesis block commits to it.
+    pub fn hash(&self) -> [u8; 32] {
+        let encoded = serde_json::to_vec(self).expect("genesis parameters encode to JSON");
+        Sha256::digest(&encoded).into()
+    }
+}
+
+// Emission is the schedule of coinbase rewards a miner may claim for each block.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Emission {
+    // Constant pays the same reward for every block.
+    Constant { reward: u64 },
+    // Halving starts at `initial` and halves every `interval` blocks.
+    Halving { initial: u64, interval: usize },
+    // Table pays the reward of the last step whose `from` height has been reached, and
+    // nothing before the first step.
+    Table { steps: Vec<EmissionStep> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmissionStep {
+    pub from: usize,
+    pub reward: u64,
+}
+
+impl Default for Emission {
+    fn default() -> Self {
+        Emission::Constant { reward: 50 }
+    }
+}
+
+impl Emission {
+    // reward returns the coinbase reward for the block at `height`.
+    pub fn reward(&self, height: usize) -> u64 {
+        match self {
+            Emission::Constant { reward } => *reward,
+            Emission::Halving { initial, interval } => {
+                let halvings = height.checked_div(*interval).unwrap_or(0);
+                initial.checked_shr(halvings as u32).unwrap_or(0)
+            }
+            Emission::Table { steps } => steps
+                .iter()
+                .filter(|step| step.from <= height)
+                .max_by_key(|step| step.from)
+                .map_or(0, |step| step.reward),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_ignores_the_formatting_of_the_file() {
+        let compact: Genesis = toml::from_str("[emission]\ntype = \"constant\"\nreward = 50\n")
+            .expect("valid genesis");
+        let spaced: Genesis = toml::from_str(
+            "# rewards\n[emission]\nreward   = 50\ntype = \"constant\"\n\n[signaling]\n",
+        )
+        .expect("valid genesis");
+        assert_eq!(compact.hash(), spaced.hash());
+        assert_eq!(compact.hash(), Genesis::default().hash());
+    }
+
+    #[test]
+    fn hash_changes_with_the_parameters() {
+        let halving: Genesis =
+            toml::from_str("[emission]\ntype = \"halving\"\ninitial = 50\ninterval = 1000\n")
+                .expect("valid genesis");
+        assert_ne!(halving.hash(), Genesis::default().hash());
+    }
+
+    #[test]
+    fn hash_changes_with_the_seed() {
+        let seeded = |seed: &str| Genesis {
+            seed: Some(seed.to_string()),
+            ..Genesis::default()
+        };
+        assert_eq!(seeded("test").hash(), seeded("test").hash());
+        assert_ne!(seeded("test").hash(), seeded("other").hash());
+        assert_ne!(seeded("test").hash(), Genesis::default().hash());
+    }
+
+    #[test]
+    fn seeded_file_reads_back() {
+        let genesis = Genesis {
+            seed: Some("test".to_string()),
+            ..Genesis::default()
+        };
+        let file = toml::to_string(&genesis).expect("genesis encodes to TOML");
+        assert_eq!(
+            toml::from_str::<Genesis>(&file).expect("valid genesis"),
+            genesis
+        );
+    }
+}
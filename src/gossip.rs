@@ -0,0 +1,149 @@
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{ConnectedPoint, Multiaddr};
+use libp2p::floodsub::{Floodsub, FloodsubEvent, FloodsubMessage};
+use libp2p::swarm::{
+    ConnectionHandler, IntoConnectionHandler, NetworkBehaviour, NetworkBehaviourAction,
+    PollParameters,
+};
+use libp2p::PeerId;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::task::{Context, Poll};
+
+// Gossip is floodsub, except that it reports which connected peer relayed every message to us.
+// A message's source is whatever the publisher wrote into it, so anything that has to hold a
+// peer to account for a message, like rate limiting, goes by the relayer instead.
+
+// RELAYERS_LIMIT is how many inbound messages we remember the relayer of until floodsub hands
+// them out. Duplicates are never handed out, so they are forgotten once the limit is reached.
+const RELAYERS_LIMIT: usize = 1024;
+
+type Handler = <Floodsub as NetworkBehaviour>::ConnectionHandler;
+type Inbound = <<Handler as IntoConnectionHandler>::Handler as ConnectionHandler>::OutEvent;
+
+pub struct Gossip {
+    floodsub: Floodsub,
+    // relayers holds the source and sequence number of the messages floodsub is yet to hand
+    // out, along with the peer that relayed them, oldest first.
+    relayers: VecDeque<(PeerId, Vec<u8>, PeerId)>,
+}
+
+#[derive(Debug)]
+pub enum GossipEvent {
+    // Message is a message relayed to us by `relayer`, who may not be its source.
+    Message {
+        relayer: PeerId,
+        message: FloodsubMessage,
+    },
+    // Other is any other floodsub event, e.g. a peer subscribing to a topic.
+    Other(FloodsubEvent),
+}
+
+impl Gossip {
+    pub fn new(local_peer_id: PeerId) -> Self {
+        Self {
+            floodsub: Floodsub::new(local_peer_id),
+            relayers: VecDeque::new(),
+        }
+    }
+
+    // relayer returns the peer that relayed the message to us, falling back to its source if we
+    // no longer know.
+    fn relayer(&mut self, message: &FloodsubMessage) -> PeerId {
+        let position = self.relayers.iter().position(|(source, seqno, _)| {
+            *source == message.source && *seqno == message.sequence_number
+        });
+        match position.and_then(|position| self.relayers.remove(position)) {
+            Some((_, _, relayer)) => relayer,
+            None => message.source,
+        }
+    }
+}
+
+// Gossip derefs to floodsub, so topics are subscribed and messages published as before.
+impl Deref for Gossip {
+    type Target = Floodsub;
+
+    fn deref(&self) -> &Floodsub {
+        &self.floodsub
+    }
+}
+
+impl DerefMut for Gossip {
+    fn deref_mut(&mut self) -> &mut Floodsub {
+        &mut self.floodsub
+    }
+}
+
+// Only the methods floodsub implements are passed on, the others do nothing for it either.
+impl NetworkBehaviour for Gossip {
+    type ConnectionHandler = Handler;
+    type OutEvent = GossipEvent;
+
+    fn new_handler(&mut self) -> Self::ConnectionHandler {
+        self.floodsub.new_handler()
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        failed_addresses: Option<&Vec<Multiaddr>>,
+        other_established: usize,
+    ) {
+        self.floodsub.inject_connection_established(
+            peer_id,
+            connection_id,
+            endpoint,
+            failed_addresses,
+            other_established,
+        );
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        handler: <Self::ConnectionHandler as IntoConnectionHandler>::Handler,
+        remaining_established: usize,
+    ) {
+        self.floodsub.inject_connection_closed(
+            peer_id,
+            connection_id,
+            endpoint,
+            handler,
+            remaining_established,
+        );
+    }
+
+    fn inject_event(&mut self, peer_id: PeerId, connection: ConnectionId, event: Inbound) {
+        if let Inbound::Rx(rpc) = &event {
+            for message in &rpc.messages {
+                let (source, seqno) = (message.source, message.sequence_number.clone());
+                self.relayers.push_back((source, seqno, peer_id));
+            }
+            while self.relayers.len() > RELAYERS_LIMIT {
+                self.relayers.pop_front();
+            }
+        }
+        self.floodsub.inject_event(peer_id, connection, event);
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
+        self.floodsub.poll(cx, params).map(|action| {
+            action.map_out(|event| match event {
+                FloodsubEvent::Message(message) => GossipEvent::Message {
+                    relayer: self.relayer(&message),
+                    message,
+                },
+                event => GossipEvent::Other(event),
+            })
+        })
+    }
+}
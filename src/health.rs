@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+// Transition is a change in the health of our tip reported by TipWatch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    // Stale means the tip has not moved for too long while peers are ahead, so we should
+    // resync. It is reported again every time the limit elapses without progress.
+    Stale { first: bool, stalled: Duration },
+    // Recovered means the tip moved again after having gone stale.
+    Recovered,
+}
+
+// TipWatch notices when our tip stops moving while peers report longer chains, which means
+// we are stuck rather than the network being quiet.
+#[derive(Debug)]
+pub struct TipWatch {
+    tip: Option<String>,
+    // moved is when the tip last changed, and checked when it was last reported stale.
+    moved: Instant,
+    checked: Instant,
+    stale: bool,
+}
+
+impl Default for TipWatch {
+    fn default() -> Self {
+        Self {
+            tip: None,
+            moved: Instant::now(),
+            checked: Instant::now(),
+            stale: false,
+        }
+    }
+}
+
+impl TipWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // check updates the watch with our current tip. `limit` is how long the tip may stay put
+    // while `peers_ahead`.
+    pub fn check(
+        &mut self,
+        tip: Option<&str>,
+        limit: Duration,
+        peers_ahead: bool,
+    ) -> Option<Transition> {
+        let now = Instant::now();
+        if tip != self.tip.as_deref() {
+            self.tip = tip.map(str::to_string);
+            self.moved = now;
+            self.checked = now;
+            if self.stale {
+                self.stale = false;
+                return Some(Transition::Recovered);
+            }
+            return None;
+        }
+        if !peers_ahead || now.duration_since(self.checked) < limit {
+            return None;
+        }
+        self.checked = now;
+        let first = !self.stale;
+        self.stale = true;
+        Some(Transition::Stale {
+            first,
+            stalled: now.duration_since(self.moved),
+        })
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+}
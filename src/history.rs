@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::payload::Payload;
+use crate::storage;
+
+// HISTORY_BATCH is how many blocks are read at a time while walking the chain, so the
+// whole chain never has to be in memory. Everything that walks the chain reads it in batches
+// of this size.
+pub const HISTORY_BATCH: usize = 1000;
+
+// ChatEntry is a chat message as recorded on the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatEntry {
+    pub height: usize,
+    pub block_hash: String,
+    // timestamp is when the sender created the message.
+    pub timestamp: i64,
+    pub sender: String,
+    pub topic: String,
+    pub text: String,
+}
+
+// chat_history walks the first `until` blocks of the chain and returns the messages posted
+// to `topic` at or after the unix timestamp `since`, in chain order.
+pub async fn chat_history(
+    app: &App,
+    topic: &str,
+    since: i64,
+    until: usize,
+) -> Result<Vec<ChatEntry>, storage::Error> {
+    let mut entries = vec![];
+    let mut start = 0;
+    while start < until {
+        let end = (start + HISTORY_BATCH).min(until);
+        for block in app.range(start, end).await? {
+            for tx in &block.transactions {
+                let Some(Payload::Chat(message)) = Payload::decode(&tx.payload) else {
+                    continue;
+                };
+                if message.topic != topic || tx.timestamp < since {
+                    continue;
+                }
+                entries.push(ChatEntry {
+                    height: block.height,
+                    block_hash: block.hash.clone(),
+                    timestamp: tx.timestamp,
+                    sender: tx.sender.clone(),
+                    topic: message.topic,
+                    text: message.text,
+                });
+            }
+        }
+        start += HISTORY_BATCH;
+    }
+    Ok(entries)
+}
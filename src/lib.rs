@@ -0,0 +1,43 @@
+// mchain is a small proof-of-work chain gossiped over libp2p. The modules below are what
+// the mchain binary is built from; applications can embed them to run their own node, e.g.
+// registering a validator::PayloadValidator to restrict what payloads the chain accepts.
+pub mod admin;
+pub mod api;
+pub mod app;
+pub mod backfill;
+pub mod cid;
+pub mod config;
+pub mod datadir;
+pub mod events;
+pub mod export;
+pub mod fees;
+pub mod files;
+pub mod fork;
+pub mod genesis;
+pub mod gossip;
+pub mod health;
+pub mod history;
+pub mod mempool;
+pub mod merkle;
+pub mod metrics;
+pub mod miner;
+pub mod names;
+pub mod node;
+pub mod nonces;
+pub mod notary;
+pub mod p2p;
+pub mod pages;
+pub mod payload;
+pub mod peers;
+pub mod receipts;
+pub mod rejects;
+pub mod rpc;
+pub mod snapshot;
+pub mod state;
+pub mod storage;
+pub mod sync;
+pub mod telemetry;
+pub mod validator;
+pub mod versionbits;
+pub mod wallet;
+pub mod wire;
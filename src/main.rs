@@ -1,127 +1,1528 @@
 use async_std::{io, task};
+use bytes::Bytes;
+use clap::Parser;
 use futures::{
+    channel::oneshot,
     prelude::{stream::StreamExt, *},
     select,
 };
 use libp2p::{
-    floodsub::{self, Floodsub, FloodsubEvent},
-    identity,
+    floodsub::FloodsubEvent,
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     mdns::{Mdns, MdnsConfig, MdnsEvent},
-    swarm::SwarmEvent,
-    Multiaddr, NetworkBehaviour, PeerId, Swarm,
+    multiaddr::Protocol,
+    ping,
+    request_response::{
+        ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage,
+    },
+    swarm::{SwarmBuilder, SwarmEvent},
+    PeerId, Swarm,
 };
 use mongodb::{
-    bson::{doc, Document},
+    bson::doc,
     options::{ClientOptions, ResolverConfig},
     Client,
 };
-use serde;
 use std::error::Error;
+use std::iter;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod app;
-mod p2p;
+use mchain::telemetry::SpanContext;
+use mchain::{
+    admin, api, app, backfill, cid, config, datadir, events, export, fees, files, genesis, gossip,
+    health, history, mempool, metrics, miner, names, node, notary, p2p, pages, payload, peers,
+    rejects, rpc, snapshot, state, storage, sync, telemetry, versionbits, wallet, wire,
+};
+
+mod cli;
+mod client;
+mod testnet;
+
+// SYNC_INTERVAL is how often we check whether a peer is ahead of us or a sync request has
+// timed out.
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+// MINE_INTERVAL is how often the miner checks for pending transactions to seal into a block.
+const MINE_INTERVAL: Duration = Duration::from_secs(1);
+
+// PEER_STORE_INTERVAL is how often peer bans and reputations are synced with the database.
+const PEER_STORE_INTERVAL: Duration = Duration::from_secs(30);
+
+// STATUS_INTERVAL is how often we publish a status heartbeat.
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+// OBSERVER_SUBMIT is the reason submissions are refused in observer mode.
+const OBSERVER_SUBMIT: &str = "observer nodes do not submit transactions";
+
+// CONFIG_POLL_INTERVAL is how often the config file is checked for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// connect_mongo returns a client of the MongoDB server at uri, once it answers a ping.
+async fn connect_mongo(uri: &str) -> Result<Client, Box<dyn Error>> {
+    let mut options =
+        ClientOptions::parse_with_resolver_config(uri, ResolverConfig::cloudflare()).await?;
+
+    // Export the latency, errors and retries of every command through the metrics endpoint.
+    options.command_event_handler = Some(Arc::new(metrics::MongoMetrics::default()));
+
+    let client = Client::with_options(options)?;
+
+    // Ping the MDB server.
+    client
+        .database("admin")
+        .run_command(doc! {"ping": 1}, None)
+        .await?;
+    log::info!("Connected to MongoDB!");
+    Ok(client)
+}
+
+// Context is the state of the node that the event loop and the message handlers work on.
+struct Context {
+    swarm: Swarm<p2p::AppBehavior>,
+    app: app::App,
+    mempool: mempool::Mempool,
+    peers: peers::PeerManager,
+    sync: sync::Sync,
+    // backfill repairs the gaps found in the stored chain at startup.
+    backfill: backfill::Backfill,
+    config: config::Config,
+    // clock_skewed is set while our clock is too far off from our peers' to mine.
+    clock_skewed: bool,
+}
+
+// apply_config switches the running node over to `new` without touching connections.
+fn apply_config(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    toggles: &api::Toggles,
+    old: &config::Config,
+    new: &config::Config,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(level) = new.log_level()? {
+        log::set_max_level(level);
+    }
+    toggles.set(new.api.enabled, new.api.admin);
+    versionbits::signal(&new.signal);
+    miner::set_policy(&new.mining);
+
+    for addr in new
+        .bootstrap
+        .iter()
+        .filter(|addr| !old.bootstrap.contains(addr))
+    {
+        log::info!("Dialing bootstrap peer {}", addr);
+        if let Err(e) = swarm.dial(addr.clone()) {
+            log::warn!("Could not dial bootstrap peer {}: {}", addr, e);
+        }
+    }
+    Ok(())
+}
+
+// reload_config reads the config file again and applies it, keeping the current config if
+// the file is invalid.
+fn reload_config(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    toggles: &api::Toggles,
+    path: &std::path::Path,
+    config: &mut config::Config,
+) -> Result<(), String> {
+    let mut new = config::Config::load(path).map_err(|e| e.to_string())?;
+    if new.limits != config.limits {
+        log::warn!("Connection limits changed; they take effect once the node restarts");
+        new.limits = config.limits.clone();
+    }
+    if new.telemetry != config.telemetry {
+        log::warn!("Telemetry settings changed; they take effect once the node restarts");
+        new.telemetry = config.telemetry.clone();
+    }
+    apply_config(swarm, toggles, config, &new).map_err(|e| e.to_string())?;
+    log::info!("Reloaded config from {}", path.display());
+    *config = new;
+    Ok(())
+}
+
+// keep_payloads has the chunk store keep the payloads of the blocks added to the chain while
+// content addressing is on. Turning it on also keeps the payloads of the blocks already on the
+// chain, in the background.
+fn keep_payloads(app: &mut app::App, chunk_store: &files::ChunkStore, cids: bool) {
+    if app.keeps_payloads() == cids {
+        return;
+    }
+    app.keep_payloads_in(cids.then(|| chunk_store.clone()));
+    if cids {
+        let (store, storage, height) = (chunk_store.clone(), app.storage(), app.height());
+        task::spawn(async move {
+            match store.keep_chain_payloads(storage, height).await {
+                Ok(()) => log::info!("Kept the payloads of the first {} blocks", height),
+                Err(e) => log::warn!("Could not keep the payloads of the chain: {}", e),
+            }
+        });
+    }
+}
+
+// maybe_sync spreads the ranges of blocks we are missing over the best peers that are
+// ahead of us.
+fn maybe_sync(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    app: &app::App,
+    peers: &peers::PeerManager,
+    sync: &mut sync::Sync,
+) {
+    for (peer, start, trace) in sync.next_requests(peers, app.height()) {
+        let rtt = peers.get(&peer).and_then(|info| info.rtt);
+        log::info!(
+            "Requesting blocks from {} starting at {} (rtt: {:?})",
+            peer,
+            start,
+            rtt
+        );
+        p2p::request_range(swarm, &peer, start, Some(&trace));
+    }
+}
+
+// resync_stale_tip starts over syncing from the peers that are ahead, asking the best one
+// for its recent blocks in case ours diverged from it.
+fn resync_stale_tip(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    app: &app::App,
+    peers: &peers::PeerManager,
+    sync: &mut sync::Sync,
+    stalled: Duration,
+) {
+    let Some(best) = peers.sync_peers(app.height(), 1).pop() else {
+        return;
+    };
+    log::warn!(
+        "No new block for {:?} while peers are ahead, resyncing from {}",
+        stalled,
+        best
+    );
+    sync.reset();
+    let request = p2p::LocalChainRequest {
+        from_peer_id: best.to_string(),
+    };
+    p2p::publish(swarm, &p2p::CHAIN_TOP, &request);
+    maybe_sync(swarm, app, peers, sync);
+}
+
+// check_clock compares our clock against the time our connected peers report, warning and
+// pausing mining while the skew exceeds the allowed drift. Too few peers to tell never pause
+// mining.
+fn check_clock(peers: &peers::PeerManager, config: &config::Config, clock_skewed: &mut bool) {
+    let skew = peers.clock_skew().unwrap_or(0);
+    let skewed =
+        config.max_clock_drift_secs > 0 && skew.unsigned_abs() > config.max_clock_drift_secs;
+    if skewed && !*clock_skewed {
+        log::warn!(
+            "Local clock is {}s off from our peers', more than the allowed {}s; not mining",
+            skew,
+            config.max_clock_drift_secs
+        );
+    } else if !skewed && *clock_skewed {
+        log::info!("Local clock is back in line with our peers'");
+    }
+    *clock_skewed = skewed;
+}
+
+// print_peers prints the connected peers along with the status they last reported, and
+// whether they are ahead of or behind our `height`.
+fn print_peers(peers: &peers::PeerManager, height: usize) {
+    println!("Connected peers:");
+    for (peer, info) in peers.iter() {
+        let position = match info.height {
+            Some(h) if h > height => format!("{} blocks ahead", h - height),
+            Some(h) if h < height => format!("{} blocks behind", height - h),
+            Some(_) => "in sync".to_string(),
+            None => "unknown height".to_string(),
+        };
+        let status = info.status.as_ref().map_or(String::new(), |status| {
+            format!(
+                ", tip {}, version {}, mempool {}",
+                status.tip.as_deref().unwrap_or("none"),
+                status.version,
+                status.mempool_size
+            )
+        });
+        let role = if info.archival { ", archival" } else { "" };
+        println!(
+            "{}: {}{}, rtt {:?}, score {}{}",
+            peer, position, status, info.rtt, info.score, role
+        );
+    }
+}
+
+// check_history refuses queries of the state as of the first `until` blocks if its history
+// was pruned below `horizon`, pointing at the archival peers that can answer them instead.
+fn check_history(peers: &peers::PeerManager, until: usize, horizon: usize) -> Result<(), String> {
+    if until >= horizon {
+        return Ok(());
+    }
+    let archival: Vec<String> = peers
+        .archival_peers()
+        .iter()
+        .map(PeerId::to_string)
+        .collect();
+    let referral = if archival.is_empty() {
+        "none of our peers is archival".to_string()
+    } else {
+        format!("ask one of the archival peers {}", archival.join(", "))
+    };
+    Err(format!(
+        "history below height {} is only kept by archival nodes; {}",
+        horizon, referral
+    ))
+}
+
+// view reads the chain through the snapshot with the given id, or live without one. It
+// returns None if the snapshot expired.
+fn view<'a>(app: &'a mut app::App, snapshot: Option<&str>) -> Option<snapshot::View<'a>> {
+    match snapshot {
+        Some(id) => snapshot::View::pinned(app, id),
+        None => Some(snapshot::View::live(app)),
+    }
+}
+
+// submit turns the payload into a transaction from this node's wallet and gossips it,
+// returning the transaction id along with the correlation id it is tracked under. The
+// transaction gets the next nonce unless it replaces a pending one.
+fn submit(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    app: &app::App,
+    mempool: &mut mempool::Mempool,
+    wallet: &wallet::Wallet,
+    payload: Bytes,
+    query: api::SubmitQuery,
+    correlation_id: Option<String>,
+) -> Result<api::Submitted, String> {
+    let tx = match query.nonce {
+        Some(nonce) => wallet.replacement(nonce, query.fee, payload),
+        None => wallet.build(app, mempool, query.fee, payload),
+    };
+    let correlation_id = correlation_id.unwrap_or_else(|| tx.id.clone());
+    if !mempool.insert_correlated(tx.clone(), correlation_id.clone(), app.nonces()) {
+        return Err("transaction was rejected by the mempool".to_string());
+    }
+    p2p::publish(swarm, &p2p::TX_TOP, &tx);
+    Ok(api::Submitted {
+        id: tx.id,
+        correlation_id,
+    })
+}
+
+// submit_batch turns the payloads into consecutive transactions from this node's wallet and
+// admits them to the mempool together, gossiping them only if every one was accepted.
+fn submit_batch(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    app: &app::App,
+    mempool: &mut mempool::Mempool,
+    wallet: &wallet::Wallet,
+    items: Vec<api::BatchItem>,
+) -> Result<api::BatchSubmitted, String> {
+    let payloads = items
+        .iter()
+        .map(|item| (item.fee, item.payload.encode()))
+        .collect();
+    let txs = wallet.build_batch(app, mempool, payloads);
+    let batch: Vec<(app::Transaction, String)> = txs
+        .into_iter()
+        .zip(items)
+        .map(|(tx, item)| {
+            let correlation_id = item.correlation_id.unwrap_or_else(|| tx.id.clone());
+            (tx, correlation_id)
+        })
+        .collect();
+    let results = mempool.insert_batch(batch.clone(), app.nonces());
+    let admitted = results.iter().all(Result::is_ok);
+    if admitted {
+        for (tx, _) in &batch {
+            p2p::publish(swarm, &p2p::TX_TOP, tx);
+        }
+    }
+    let items = batch
+        .into_iter()
+        .zip(results)
+        .map(|((tx, correlation_id), result)| api::BatchItemResult {
+            id: tx.id,
+            correlation_id,
+            error: result.err(),
+        })
+        .collect();
+    Ok(api::BatchSubmitted { admitted, items })
+}
+
+// sync_peer_store reconciles the peer manager with the peers collection: bans and
+// reputations written by other nodes are picked up, and ours are written back.
+async fn sync_peer_store(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    peers: &mut peers::PeerManager,
+    sync: &mut sync::Sync,
+    store: &storage::MongoPeers,
+) -> Result<(), storage::Error> {
+    for peer in peers.restore(store.load().await?) {
+        log::info!("Disconnecting {}, which is banned", peer);
+        sync.forget(&peer);
+        swarm
+            .behaviour_mut()
+            .floodsub
+            .remove_node_from_partial_view(&peer);
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    store.save(&peers.records()).await
+}
+
+// apply_downloaded validates and appends the downloaded ranges that extend our tip, in
+// chain order.
+async fn apply_downloaded(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    app: &mut app::App,
+    mempool: &mut mempool::Mempool,
+    peers: &mut peers::PeerManager,
+    sync: &mut sync::Sync,
+) -> Result<(), Box<dyn Error>> {
+    while let Some((peer, blocks)) = sync.pop_ready(app.height()) {
+        let tip = app.tip().expect("there is at least one block");
+        if blocks[0].previous_hash != tip.hash {
+            // The peer's chain diverges from ours, so compare the chains from where they
+            // diverged instead of appending ranges.
+            log::info!("Chain of {} diverges from ours, requesting it", peer);
+            sync.reset();
+            let request = p2p::LocalChainRequest {
+                from_peer_id: peer.to_string(),
+            };
+            p2p::publish(swarm, &p2p::CHAIN_TOP, &request);
+            return Ok(());
+        }
+
+        let count = blocks.len();
+        let mut added = true;
+        for block in blocks {
+            if !app.try_add_block(block.clone()).await? {
+                added = false;
+                break;
+            }
+            mempool.remove_included(&block);
+        }
+        if added {
+            log::info!("Synced {} blocks from {}", count, peer);
+            peers.adjust_score(peer, peers::SCORE_USEFUL_RESPONSE);
+        } else {
+            peers.adjust_score(peer, peers::SCORE_INVALID_BLOCKS);
+            sync.reset();
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// request_backfill asks idle peers for the blocks missing from the stored chain.
+fn request_backfill(
+    swarm: &mut Swarm<p2p::AppBehavior>,
+    peers: &peers::PeerManager,
+    backfill: &mut backfill::Backfill,
+) {
+    for (peer, start) in backfill.next_requests(peers) {
+        log::info!(
+            "Requesting missing blocks from {} starting at {}",
+            peer,
+            start
+        );
+        p2p::request_range(swarm, &peer, start, None);
+    }
+}
+
+// report_backfill logs the summary of the repair of the stored chain once it is complete.
+fn report_backfill(backfill: &mut backfill::Backfill, height: usize) {
+    if let Some(report) = backfill.finish() {
+        log::info!(
+            "Repaired the stored chain: backfilled {} of {} missing blocks in {} ranges",
+            report.repaired,
+            report.detected,
+            report.ranges
+        );
+        events::emit(events::Event::Health {
+            status: events::Health::Ok,
+            height,
+        });
+    }
+}
+
+// apply_backfill stores the blocks a peer sent to fill the `requested` heights of a gap in the
+// stored chain, then asks for more of the gaps.
+async fn apply_backfill(
+    ctx: &mut Context,
+    peer: PeerId,
+    requested: Range<usize>,
+    blocks: Vec<app::Block>,
+) -> Result<(), Box<dyn Error>> {
+    let blocks: Vec<app::Block> = blocks
+        .into_iter()
+        .filter(|block| requested.contains(&block.height))
+        .collect();
+    if blocks.len() < requested.len() {
+        log::warn!(
+            "{} sent {} of the {} missing blocks at {}",
+            peer,
+            blocks.len(),
+            requested.len(),
+            requested.start
+        );
+        ctx.peers.adjust_score(peer, peers::SCORE_TIMEOUT);
+    } else if ctx.app.repair(blocks).await? {
+        log::info!(
+            "Backfilled blocks {} to {} from {}",
+            requested.start,
+            requested.end - 1,
+            peer
+        );
+        ctx.backfill.repaired(requested);
+        ctx.peers.adjust_score(peer, peers::SCORE_USEFUL_RESPONSE);
+        report_backfill(&mut ctx.backfill, ctx.app.height());
+    } else {
+        ctx.peers.adjust_score(peer, peers::SCORE_INVALID_BLOCKS);
+    }
+    request_backfill(&mut ctx.swarm, &ctx.peers, &mut ctx.backfill);
+    Ok(())
+}
+
+// on_block adds a block mined by a peer, or syncs with the peer if it doesn't extend our tip.
+fn on_block(
+    ctx: &mut Context,
+    source: PeerId,
+    announcement: p2p::BlockAnnouncement,
+) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        let block = announcement.block;
+        if ctx.app.contains(&block.hash).await? {
+            return Ok(());
+        }
+        let delay = announcement
+            .sent_at_ms
+            .and_then(|sent_at| ctx.peers.propagation_delay(&source, sent_at));
+        if let Some(delay) = delay {
+            metrics::BLOCK_PROPAGATION.observe(delay.as_secs_f64());
+        }
+        let tip = ctx.app.tip().expect("there is at least one block");
+        if block.previous_hash == tip.hash {
+            if ctx.app.try_add_block(block.clone()).await? {
+                ctx.mempool.remove_included(&block);
+            }
+        } else {
+            // The block does not extend our tip, so the sender knows about at least one block
+            // we don't have.
+            let height = block.height + 1;
+            let known = ctx.peers.get(&source).and_then(|info| info.height);
+            if known.is_none_or(|h| h < height) {
+                ctx.peers.record_height(source, height);
+            }
+            maybe_sync(&mut ctx.swarm, &ctx.app, &ctx.peers, &mut ctx.sync);
+        }
+        Ok(())
+    })
+}
+
+// on_sync serves the block ranges peers ask us for, and applies the ones we asked for.
+fn on_sync(ctx: &mut Context, source: PeerId, message: p2p::SyncMessage) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        match message {
+            p2p::SyncMessage::RangeRequest {
+                receiver,
+                start,
+                limit,
+                traceparent,
+            } if receiver == p2p::PEER_ID.to_string() => {
+                let (name, kind) = ("sync.serve_range", telemetry::Kind::Server);
+                let mut span = match traceparent.as_deref().and_then(SpanContext::parse) {
+                    Some(parent) => telemetry::Span::child_of(&parent, name, kind),
+                    None => telemetry::Span::start(name, kind),
+                };
+                span.set("sync.peer", source.to_string());
+                span.set("sync.start", start);
+                let limit = limit.min(sync::RANGE_LIMIT);
+                let end = ctx.app.height().min(start.saturating_add(limit));
+                let blocks = ctx.app.range(start, end).await?;
+                span.set("sync.blocks", blocks.len());
+                let response = p2p::SyncMessage::RangeResponse {
+                    receiver: source.to_string(),
+                    start,
+                    height: ctx.app.height(),
+                    blocks,
+                };
+                p2p::publish(&mut ctx.swarm, &p2p::SYNC_TOP, &response);
+                span.end();
+            }
+            p2p::SyncMessage::RangeResponse {
+                receiver,
+                start,
+                height,
+                blocks,
+            } if receiver == p2p::PEER_ID.to_string() => {
+                ctx.peers.record_height(source, height);
+                if let Some(requested) = ctx.backfill.complete(&source, start) {
+                    return apply_backfill(ctx, source, requested, blocks).await;
+                }
+                if ctx.sync.complete(&source, start) || start == ctx.app.height() {
+                    ctx.sync.insert(source, start, blocks);
+                }
+                apply_downloaded(
+                    &mut ctx.swarm,
+                    &mut ctx.app,
+                    &mut ctx.mempool,
+                    &mut ctx.peers,
+                    &mut ctx.sync,
+                )
+                .await?;
+                maybe_sync(&mut ctx.swarm, &ctx.app, &ctx.peers, &mut ctx.sync);
+            }
+            _ => {}
+        }
+        Ok(())
+    })
+}
+
+// on_chain switches to a peer's chain if it wins over ours. Chains are exchanged when a
+// peer's chain diverges from ours.
+fn on_chain(ctx: &mut Context, source: PeerId, resp: p2p::ChainResponse) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        if resp.receiver != p2p::PEER_ID.to_string() {
+            return Ok(());
+        }
+        // Only consider chains signed by the peer that sent them.
+        let verified = resp.verify().and_then(|responder| match responder {
+            responder if responder == source => Ok(()),
+            responder => Err(format!("signed by {}", responder)),
+        });
+        if let Err(e) = verified {
+            log::warn!("Rejecting chain from {}: {}", source, e);
+            ctx.peers.adjust_score(source, peers::SCORE_INVALID_BLOCKS);
+            return Ok(());
+        }
+        let fork = ctx
+            .app
+            .replace_chain(resp.blocks, ctx.config.reorg_limit())
+            .await?;
+        for block in ctx.app.recent() {
+            ctx.mempool.remove_included(block);
+        }
+        if let Some(fork) = fork {
+            events::emit(events::Event::Fork {
+                peer: source.to_string(),
+                fork,
+            });
+        }
+        Ok(())
+    })
+}
+
+// on_chain_request sends the blocks we keep in memory, the most recent ones of our chain, to
+// the peer that asked us for them. They cover any fork the peer would switch to on its own,
+// and peers further behind catch up through range sync.
+fn on_chain_request(
+    ctx: &mut Context,
+    source: PeerId,
+    req: p2p::LocalChainRequest,
+) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        if req.from_peer_id == p2p::PEER_ID.to_string() {
+            let blocks = ctx.app.recent().cloned().collect();
+            let response = p2p::ChainResponse::new(blocks, source.to_string());
+            p2p::publish(&mut ctx.swarm, &p2p::CHAIN_TOP, &response);
+        }
+        Ok(())
+    })
+}
+
+// on_status keeps track of how far along every peer is from their heartbeats.
+fn on_status(ctx: &mut Context, source: PeerId, status: p2p::Status) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        ctx.peers.record_status(source, status);
+        maybe_sync(&mut ctx.swarm, &ctx.app, &ctx.peers, &mut ctx.sync);
+        check_clock(&ctx.peers, &ctx.config, &mut ctx.clock_skewed);
+        Ok(())
+    })
+}
+
+// on_transaction adds a transaction gossiped by a peer to the mempool.
+fn on_transaction(ctx: &mut Context, source: PeerId, tx: app::Transaction) -> p2p::Handled<'_> {
+    Box::pin(async move {
+        log::info!("Received transaction {} from {}", tx.id, source);
+        ctx.mempool
+            .insert(tx, mempool::Source::Remote, ctx.app.nonces());
+        Ok(())
+    })
+}
+
+// router routes every kind of pubsub message to its handler.
+fn router() -> p2p::Router<Context> {
+    let mut router = p2p::Router::new();
+    router
+        .on(on_block)
+        .on(on_sync)
+        .on(on_chain)
+        .on(on_chain_request)
+        .on(on_status)
+        .on(on_transaction);
+    router
+}
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
+    let args = cli::Args::parse();
+    let data_dir = args
+        .data_dir
+        .clone()
+        .map_or_else(datadir::DataDir::platform, datadir::DataDir::new);
+    let config_path = args
+        .config
+        .clone()
+        .or_else(|| Some(data_dir.config()).filter(|path| path.exists()));
+    let config = match &config_path {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    let genesis = match &args.genesis {
+        Some(path) => genesis::Genesis::load(path)?,
+        None => genesis::Genesis::default(),
+    };
+
+    // If the config sets a log level, RUST_LOG still filters per module but the overall level
+    // comes from the config, so that it can be changed at runtime. Otherwise RUST_LOG alone
+    // decides what is logged.
+    let mut logger = env_logger::Builder::new();
+    let log_level = config.log_level()?;
+    if log_level.is_some() {
+        logger.filter_level(log::LevelFilter::Trace);
+    }
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
+    if let Some(level) = log_level {
+        log::set_max_level(level);
+    }
+
+    // Subcommands talk to a running node instead of starting one, or start several.
+    match args.command {
+        Some(cli::Command::Testnet {
+            nodes,
+            dir,
+            p2p_port,
+            api_port,
+            mongo_uri,
+            seed,
+        }) => {
+            let ports = (p2p_port, api_port);
+            return testnet::run(nodes, &dir, ports, &mongo_uri, seed.as_deref()).await;
+        }
+        Some(cli::Command::Export {
+            mongo_uri,
+            mongo_db,
+            format,
+            fields,
+            out,
+        }) => {
+            let client = connect_mongo(&mongo_uri).await?;
+            let store = storage::MongoStorage::new(&client.database(&mongo_db)).await?;
+            let exported = match &out {
+                Some(path) => {
+                    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                    export::export(&store, format, &fields, file).await?
+                }
+                None => {
+                    let stdout = std::io::BufWriter::new(std::io::stdout());
+                    export::export(&store, format, &fields, stdout).await?
+                }
+            };
+            log::info!("Exported {} blocks", exported);
+            return Ok(());
+        }
+        Some(command) => return client::run(command).await,
+        None => {}
+    }
 
-    // Create a random PeerId
+    data_dir.create()?;
+    log::info!("Data directory is {}", data_dir.root().display());
+
+    if let Some(path) = &args.debug_wire {
+        let path = path.clone().unwrap_or_else(|| data_dir.wire_log());
+        wire::enable(&path)?;
+    }
+
+    if let Some(seed) = &args.seed {
+        p2p::seed_keys(seed)?;
+        log::warn!("Keys are derived from a seed; use this for testing only");
+    } else {
+        p2p::load_keys(&data_dir.keys())?;
+        log::info!("Keys are kept in {}", data_dir.keys().display());
+    }
+
+    // Our topics and the protocol version we announce are scoped to the genesis block, which
+    // is mined from the genesis parameters.
+    app::set_genesis(&genesis)?;
+    log::info!(
+        "Genesis block is {}, for genesis parameters {}",
+        app::genesis_hash(),
+        app::genesis_params_hash()
+    );
+
+    // The peer id is that of the keys seeded or kept in the data directory.
     println!("Local peer id: {:?}", *p2p::PEER_ID);
 
     // Set up an encrypted DNS-enabled TCP Transport over the Mplex and Yamux protocols
     let transport = libp2p::development_transport(p2p::KEYS.clone()).await?;
 
-    // Create a Floodsub topic
-    let floodsub_topic = floodsub::Topic::new("chat");
+    // router hands the messages of every topic we subscribe to to their handlers.
+    let router = router();
 
     // Create a Swarm to manage peers and events
     let mut swarm = {
         let mdns = task::block_on(Mdns::new(MdnsConfig::default()))?;
         let mut behaviour = p2p::AppBehavior {
-            floodsub: Floodsub::new(*p2p::PEER_ID),
+            floodsub: gossip::Gossip::new(*p2p::PEER_ID),
             mdns,
+            ping: ping::Behaviour::new(ping::Config::new().with_keep_alive(true)),
+            identify: Identify::new(
+                IdentifyConfig::new(p2p::protocol_version(), p2p::KEYS.public())
+                    .with_agent_version(p2p::agent_version(args.archival)),
+            ),
+            chunks: RequestResponse::new(
+                files::ChunkCodec,
+                iter::once((files::ChunkProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
+            admin: admin::behaviour(),
         };
 
-        behaviour.floodsub.subscribe(floodsub_topic.clone());
-        Swarm::new(transport, behaviour, *p2p::PEER_ID)
+        for topic in router.topics() {
+            behaviour.floodsub.subscribe(topic);
+        }
+        // Bound the connections and streams peers can open, so a flood of them can't exhaust
+        // our resources.
+        SwarmBuilder::new(transport, behaviour, *p2p::PEER_ID)
+            .connection_limits(config.limits.connection_limits())
+            .max_negotiating_inbound_streams(config.limits.negotiating_streams())
+            .build()
     };
 
     // Reach out to another node if specified
-    if let Some(to_dial) = std::env::args().nth(1) {
-        let addr: Multiaddr = to_dial.parse()?;
+    if let Some(addr) = args.dial {
+        println!("Dialed {:?}", addr);
         swarm.dial(addr)?;
-        println!("Dialed {:?}", to_dial)
     }
 
+    // Reach out to the bootstrap peers from the config, which are dialed again whenever new
+    // ones are added to it.
+    let toggles = Arc::new(api::Toggles::new(config.api.enabled, config.api.admin));
+    apply_config(&mut swarm, &toggles, &config::Config::default(), &config)?;
+    let mut config_watcher = config_path.map(config::Watcher::new);
+    let mut config_ticks = async_std::stream::interval(CONFIG_POLL_INTERVAL).fuse();
+
     // Read full lines from stdin
     let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
 
-    // Listen on all interfaces and whatever port the OS assigns
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // Listen for peers, by default on all interfaces and whatever port the OS assigns
+    swarm.listen_on(args.listen.clone())?;
 
-    // app is a state machine for the blockchain.
-    let mut app = app::App::new();
+    // mining can be paused through the admin API, in which case pending transactions wait
+    // in the mempool. Observers never mine, nor publish blocks or transactions of their own,
+    // so they cannot influence consensus.
+    let observer = args.observer;
+    let mut mining = !observer;
 
-    // Get an MDB client.
-    let client_uri = "mongodb://localhost:27017";
+    // Archival nodes keep the history of the chain's state at every height. Other nodes prune
+    // it below the last state::HISTORY_BLOCKS blocks and refer older name lookups to them.
+    // Every node keeps every block, so queries answered from the blocks are never referred.
+    let archival = args.archival;
 
-    let options =
-        ClientOptions::parse_with_resolver_config(&client_uri, ResolverConfig::cloudflare())
-            .await?;
+    let mut mine_ticks = async_std::stream::interval(MINE_INTERVAL).fuse();
+    // Searching for a nonce takes a while, so blocks are mined on a blocking task, one at a
+    // time, while the event loop carries on.
+    let mut mined = stream::FuturesUnordered::<task::JoinHandle<app::Block>>::new();
 
-    let client = Client::with_options(options)?;
+    // peers ranks connected peers by latency and behaviour when choosing sync sources.
+    // Their bans and reputations are persisted to the "peers" collection once it is connected.
+    let mut peers = peers::PeerManager::new();
 
-    // Ping the MDB server.
-    client
-        .database("admin")
-        .run_command(doc! {"ping": 1}, None)
+    // chunk_store holds the chunks of files anchored on the chain that we can serve to peers.
+    // Chunks used to be kept in ./chunks by default; they have to be moved to the data
+    // directory, or --chunk-dir pointed at them, for the node to serve them again.
+    let chunk_dir = args.chunk_dir.clone().unwrap_or_else(|| data_dir.chunks());
+    let old_chunk_dir = Path::new("chunks");
+    let moved = old_chunk_dir.canonicalize().ok() != chunk_dir.canonicalize().ok();
+    if args.chunk_dir.is_none() && old_chunk_dir.is_dir() && moved {
+        log::warn!(
+            "Chunks are now kept in {} rather than {}; move them there or pass --chunk-dir",
+            chunk_dir.display(),
+            old_chunk_dir.display()
+        );
+    }
+    let chunk_store = files::ChunkStore::new(chunk_dir)?;
+    let mut chunk_fetches = files::Fetches::new();
+    let mut sync = sync::Sync::new();
+    let mut sync_ticks = async_std::stream::interval(SYNC_INTERVAL).fuse();
+
+    // tip_watch notices when we stop receiving blocks although peers are ahead of us.
+    let mut tip_watch = health::TipWatch::new();
+
+    // Ship traces and metrics to the OTLP endpoint, if there is one.
+    if let Some(endpoint) = &config.telemetry.otlp_endpoint {
+        let telemetry = &config.telemetry;
+        let peer_id = p2p::PEER_ID.to_string();
+        let exporter = telemetry::OtlpExporter::new(endpoint, &telemetry.service_name, &peer_id);
+        task::spawn(telemetry::run(exporter, telemetry.export_interval()));
+        log::info!("Exporting telemetry to {}", endpoint);
+    }
+
+    // Serve the HTTP API, which queries the event loop through api_requests.
+    let (api_tx, mut api_requests) = async_std::channel::unbounded();
+    // Admin commands operators send over libp2p are queued along with the API's requests, and
+    // answered once admin_replies resolves.
+    let admin_requests = api_tx.clone();
+    let mut admin_replies = stream::FuturesUnordered::<admin::Pending>::new();
+    let api_toggles = toggles.clone();
+    task::spawn(async move {
+        if let Err(e) = api::serve(args.api_addr.clone(), api_tx, api_toggles).await {
+            log::error!("API server stopped: {}", e);
+        }
+    });
+
+    // Get an MDB client.
+    let client = connect_mongo(&args.mongo_uri).await?;
+
+    // Everything the node persists lives in this database.
+    let db = client.database(&args.mongo_db);
+
+    // app is a state machine for the blockchain, persisted to the "blocks" collection.
+    // mempool holds submitted transactions until the miner seals them into a block, and
+    // wallet picks the nonces of the transactions this node submits.
+    let store = Arc::new(storage::MongoStorage::new(&db).await?);
+    let node::Node {
+        mut app,
+        mempool,
+        wallet,
+    } = node::NodeBuilder::new()
+        .storage(store)
+        .genesis(genesis)
+        .validator(names::validate_payload)
+        .build()
         .await?;
-    log::info!("Connected to MongoDB!");
+    // The rest of the node builds on the tip, so a new chain starts with its genesis block
+    // before anything else runs; if it can't be stored, the node doesn't start.
+    if app.tip().is_none() {
+        app.genesis().await?;
+    }
+
+    // Blocks may be missing below the tip, e.g. if the node crashed while writing them, in
+    // which case they are downloaded from peers once we have some.
+    let backfill = backfill::Backfill::new(app.gaps().await?);
+    if !backfill.is_done() {
+        let report = backfill.report();
+        log::info!(
+            "Stored chain is missing {} blocks in {} ranges",
+            report.detected,
+            report.ranges
+        );
+        events::emit(events::Event::Health {
+            status: events::Health::Repairing,
+            height: app.height(),
+        });
+    }
+
+    // peer_store shares bans and reputations with the other nodes using the database, and
+    // keeps them across restarts.
+    let peer_store = storage::MongoPeers::new(&db, p2p::PEER_ID.to_string()).await?;
+    sync_peer_store(&mut swarm, &mut peers, &mut sync, &peer_store).await?;
+    let mut peer_store_ticks = async_std::stream::interval(PEER_STORE_INTERVAL).fuse();
 
-    // Initialize the ledger.
-    let db = client.database("app");
-    let collection = db.collection::<Document>("ledger");
+    // Rejected blocks and transactions are kept in the "rejects" collection, so users can
+    // find out why their data didn't make it on chain.
+    let reject_store = storage::MongoRejects::new(&db).await?;
+    task::spawn(reject_store.clone().persist(rejects::recorded()));
+
+    // status_ticks publish our heartbeat, from which peers learn how far along we are.
+    let mut status_ticks = async_std::stream::interval(STATUS_INTERVAL).fuse();
+
+    // names is the name registry, brought up to date with the chain whenever it is queried.
+    let mut names = state::Replay::<names::Names>::new();
+
+    // ctx is the state the message handlers and the rest of the event loop work on.
+    let mut ctx = Context {
+        swarm,
+        app,
+        mempool,
+        peers,
+        sync,
+        backfill,
+        config,
+        clock_skewed: false,
+    };
+    keep_payloads(&mut ctx.app, &chunk_store, ctx.config.cids);
 
     loop {
         select! {
-            line = stdin.select_next_some() => swarm
-                .behaviour_mut()
-                .floodsub
-                .publish(floodsub_topic.clone(), line.expect("Stdin not to close").as_bytes()),
+            // Every line typed on stdin is submitted as a chat message, except for "ls peers"
+            // which lists the peers and how far along they are.
+            line = stdin.select_next_some() => {
+                let line = line.expect("Stdin not to close");
+                if line.trim() == "ls peers" {
+                    print_peers(&ctx.peers, ctx.app.height());
+                    continue;
+                }
+                let payload = payload::Payload::Chat(payload::ChatMessage {
+                    topic: "chat".to_string(),
+                    text: line,
+                })
+                .encode();
+                let query = api::SubmitQuery::default();
+                let submitted = if observer {
+                    Err(OBSERVER_SUBMIT.to_string())
+                } else {
+                    let wallet = &wallet;
+                    submit(&mut ctx.swarm, &ctx.app, &mut ctx.mempool, wallet, payload, query, None)
+                };
+                if let Err(e) = submitted {
+                    log::warn!("Could not send message: {}", e);
+                }
+            }
+
+            _ = status_ticks.select_next_some() => {
+                let tip = ctx.app.tip().map(|block| block.hash.clone());
+                let status = p2p::Status::new(ctx.app.height(), tip, ctx.mempool.len());
+                p2p::publish(&mut ctx.swarm, &p2p::STATUS_TOP, &status);
+            }
+
+            _ = peer_store_ticks.select_next_some() => {
+                let synced =
+                    sync_peer_store(&mut ctx.swarm, &mut ctx.peers, &mut ctx.sync, &peer_store)
+                        .await;
+                if let Err(e) = synced {
+                    log::error!("could not sync peers with the database: {}", e);
+                }
+            }
+
+            _ = mine_ticks.select_next_some() => {
+                let idle = mined.is_empty();
+                if idle && mining && !ctx.clock_skewed && !ctx.mempool.is_empty() {
+                    let template = miner::Template::new(&ctx.app, &ctx.mempool);
+                    mined.push(task::spawn_blocking(move || template.mine()));
+                }
+            }
+
+            block = mined.select_next_some() => {
+                // The chain may have moved on while we were mining, in which case the block is
+                // stale and its transactions are mined again on top of the new tip.
+                let tip = ctx.app.tip().map(|tip| tip.hash.as_str());
+                if tip != Some(block.previous_hash.as_str()) {
+                    log::info!("Dropping mined block {}: the tip moved on", block.hash);
+                    continue;
+                }
+                log::info!("New block: {:?}", block);
+                match ctx.app.try_add_block(block.clone()).await {
+                    Ok(true) => {
+                        ctx.mempool.remove_included(&block);
+                        let announcement = p2p::BlockAnnouncement::new(block);
+                        p2p::publish(&mut ctx.swarm, &p2p::BLOCK_TOP, &announcement);
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::error!("Could not store mined block {}: {}", block.hash, e),
+                }
+            }
+
+            request = api_requests.select_next_some() => match request {
+                api::Request::StaleBlocks(reply) => {
+                    let _ = reply.send(ctx.app.stale.list());
+                }
+                api::Request::History(query, reply) => {
+                    let until = ctx.app.confirmed_height(query.min_confirmations);
+                    let entries =
+                        history::chat_history(&ctx.app, &query.topic, query.since, until).await;
+                    let _ = reply.send(entries.map_err(|e| e.to_string()));
+                }
+                api::Request::Blocks(cursor, limit, snapshot, reply) => {
+                    let page = match view(&mut ctx.app, snapshot.as_deref()) {
+                        Some(view) => pages::blocks(&view, cursor, limit).await.map(Some),
+                        None => Ok(None),
+                    };
+                    let _ = reply.send(page.map_err(|e| e.to_string()));
+                }
+                api::Request::Transactions(cursor, limit, snapshot, reply) => {
+                    let mut page = match view(&mut ctx.app, snapshot.as_deref()) {
+                        Some(view) => pages::transactions(&view, cursor, limit).await.map(Some),
+                        None => Ok(None),
+                    };
+                    if let (Ok(Some(page)), true) = (&mut page, ctx.config.cids) {
+                        for entry in &mut page.items {
+                            entry.cid = Some(cid::of(&entry.transaction.payload));
+                        }
+                    }
+                    let _ = reply.send(page.map_err(|e| e.to_string()));
+                }
+                api::Request::OpenSnapshot(reply) => {
+                    let (height, tip) = (ctx.app.height(), ctx.app.tip().map(|b| b.hash.clone()));
+                    let _ = reply.send(ctx.app.snapshots.open(height, tip));
+                }
+                api::Request::CloseSnapshot(id, reply) => {
+                    let _ = reply.send(ctx.app.snapshots.close(&id));
+                }
+                api::Request::Submit(payload, query, correlation_id, reply) => {
+                    let payload = payload.encode();
+                    let submitted = if observer {
+                        Err(OBSERVER_SUBMIT.to_string())
+                    } else {
+                        let (wallet, mempool) = (&wallet, &mut ctx.mempool);
+                        let cid = correlation_id;
+                        submit(&mut ctx.swarm, &ctx.app, mempool, wallet, payload, query, cid)
+                    };
+                    let _ = reply.send(submitted);
+                }
+                api::Request::SubmitBatch(items, reply) => {
+                    let submitted = if observer {
+                        Err(OBSERVER_SUBMIT.to_string())
+                    } else {
+                        let (wallet, mempool) = (&wallet, &mut ctx.mempool);
+                        submit_batch(&mut ctx.swarm, &ctx.app, mempool, wallet, items)
+                    };
+                    let _ = reply.send(submitted);
+                }
+                api::Request::Receipt(correlation_id, reply) => {
+                    let _ = reply.send(ctx.mempool.receipt(&correlation_id).cloned());
+                }
+                api::Request::ResolveName(name, confirmations, reply) => {
+                    let until = ctx.app.confirmed_height(confirmations.min_confirmations);
+                    let horizon = state::history_horizon(&ctx.app, archival);
+                    names.prune(horizon);
+                    let record = match check_history(&ctx.peers, until, horizon) {
+                        Ok(()) => names
+                            .sync(&ctx.app)
+                            .await
+                            .map(|names| names.resolve(&name, until).cloned())
+                            .map_err(|e| e.to_string()),
+                        Err(e) => Err(e),
+                    };
+                    let _ = reply.send(record);
+                }
+                api::Request::Prove(digest, confirmations, reply) => {
+                    // Proofs are read from the blocks, which every node keeps, so they don't
+                    // depend on the history of the state.
+                    let until = ctx.app.confirmed_height(confirmations.min_confirmations);
+                    let proof = notary::prove(&ctx.app, &digest, until).await;
+                    let _ = reply.send(proof.map_err(|e| e.to_string()));
+                }
+                api::Request::PutChunk(data, reply) => {
+                    let _ = reply.send(chunk_store.put(&data).map_err(|e| e.to_string()));
+                }
+                api::Request::GetChunk(hash, reply) => match chunk_store.get(&hash) {
+                    Ok(None) => {
+                        let providers = ctx.peers.iter().map(|(peer, _)| *peer).collect();
+                        chunk_fetches.start(&mut ctx.swarm, hash, providers, reply);
+                    }
+                    chunk => {
+                        let _ = reply.send(chunk.map_err(|e| e.to_string()));
+                    }
+                },
+                api::Request::Manifest(id, confirmations, reply) => {
+                    let until = ctx.app.confirmed_height(confirmations.min_confirmations);
+                    let manifest = files::find_manifest(&ctx.app, &id, until).await;
+                    let _ = reply.send(manifest.map_err(|e| e.to_string()));
+                }
+                api::Request::Fees(reply) => {
+                    let _ = reply.send(fees::estimate(&ctx.app, &ctx.mempool));
+                }
+                api::Request::Template(reply) => {
+                    let _ = reply.send(miner::Template::new(&ctx.app, &ctx.mempool));
+                }
+                api::Request::State(reply) => {
+                    let state = api::NodeState {
+                        peer_id: p2p::PEER_ID.to_string(),
+                        chain_id: app::chain_id().to_string(),
+                        height: ctx.app.height(),
+                        tip: ctx.app.tip().map(|block| block.hash.clone()),
+                        mining,
+                        observer,
+                        archival,
+                        degraded: tip_watch.is_stale(),
+                        clock_skew_secs: ctx.peers.clock_skew(),
+                        listen_addrs: ctx.swarm.listeners().map(|addr| addr.to_string()).collect(),
+                        peers: ctx.peers
+                            .iter()
+                            .map(|(peer, info)| api::PeerState {
+                                peer_id: peer.to_string(),
+                                address: info.address.as_ref().map(|addr| addr.to_string()),
+                                rtt_ms: info.rtt.map(|rtt| rtt.as_millis()),
+                                score: info.score,
+                                height: info.height,
+                                archival: info.archival,
+                            })
+                            .collect(),
+                        sync_in_flight: ctx.sync.in_flight(),
+                        sync_downloaded: ctx.sync.downloaded(),
+                        stale_blocks: ctx.app.stale.len(),
+                        mempool_size: ctx.mempool.len(),
+                        backfill: ctx.backfill.report(),
+                    };
+                    let _ = reply.send(state);
+                }
+                api::Request::Health(reply) => {
+                    let status = if tip_watch.is_stale() {
+                        events::Health::Degraded
+                    } else if !ctx.backfill.is_done() {
+                        events::Health::Repairing
+                    } else {
+                        events::Health::Ok
+                    };
+                    let _ = reply.send(api::Health {
+                        status,
+                        height: ctx.app.height(),
+                        backfill: ctx.backfill.report(),
+                    });
+                }
+                api::Request::Resync(peer, reply) => {
+                    let result = if ctx.peers.get(&peer).is_some() {
+                        log::info!("Forcing resync from {}", peer);
+                        ctx.sync.reset();
+                        let request = p2p::LocalChainRequest {
+                            from_peer_id: peer.to_string(),
+                        };
+                        p2p::publish(&mut ctx.swarm, &p2p::CHAIN_TOP, &request);
+                        Ok(())
+                    } else {
+                        Err(format!("not connected to {}", peer))
+                    };
+                    let _ = reply.send(result);
+                }
+                api::Request::Dial(addr, reply) => {
+                    log::info!("Dialing {}", addr);
+                    let _ = reply.send(ctx.swarm.dial(addr).map_err(|e| e.to_string()));
+                }
+                api::Request::Disconnect(addr, reply) => {
+                    // Prefer the peer id embedded in the address, if any.
+                    let peer = addr
+                        .iter()
+                        .find_map(|protocol| match protocol {
+                            Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+                            _ => None,
+                        })
+                        .or_else(|| ctx.peers.find_by_address(&addr));
+                    let result = match peer {
+                        Some(peer) => ctx.swarm
+                            .disconnect_peer_id(peer)
+                            .map_err(|_| format!("not connected to {}", peer)),
+                        None => Err(format!("no peer connected at {}", addr)),
+                    };
+                    let _ = reply.send(result);
+                }
+                api::Request::SetMining(true, reply) if observer => {
+                    let _ = reply.send(Err("observer nodes do not mine".to_string()));
+                }
+                api::Request::SetMining(enabled, reply) => {
+                    log::info!("Mining {}", if enabled { "resumed" } else { "paused" });
+                    mining = enabled;
+                    let _ = reply.send(Ok(()));
+                }
+                api::Request::Ban(peer, duration, reason, reply) => {
+                    ctx.peers.ban(peer, duration, reason);
+                    ctx.sync.forget(&peer);
+                    ctx.backfill.forget(&peer);
+                    ctx.swarm.behaviour_mut().floodsub.remove_node_from_partial_view(&peer);
+                    let _ = ctx.swarm.disconnect_peer_id(peer);
+                    let saved = peer_store.save(&ctx.peers.records()).await;
+                    let _ = reply.send(saved.map_err(|e| e.to_string()));
+                }
+                api::Request::Unban(peer, reply) => {
+                    let result = if ctx.peers.unban(&peer) {
+                        peer_store.unban(&peer.to_string()).await.map_err(|e| e.to_string())
+                    } else {
+                        Err(format!("{} is not banned", peer))
+                    };
+                    let _ = reply.send(result);
+                }
+                api::Request::Bans(reply) => {
+                    let _ = reply.send(ctx.peers.bans());
+                }
+                api::Request::Rejects(query, reply) => {
+                    let _ = reply.send(reject_store.list(&query).await.map_err(|e| e.to_string()));
+                }
+                api::Request::HeldReorg(reply) => {
+                    let _ = reply.send(ctx.app.held.clone());
+                }
+                api::Request::AcceptReorg(reply) => {
+                    let result = ctx.app.accept_held().await;
+                    for block in ctx.app.recent() {
+                        ctx.mempool.remove_included(block);
+                    }
+                    let _ = reply.send(result.map(|_| ()));
+                }
+                api::Request::Deployments(reply) => {
+                    let _ = reply.send(ctx.app.deployments.statuses());
+                }
+                api::Request::Rpc(calls, reply) => {
+                    let mut responses = Vec::with_capacity(calls.len());
+                    for call in calls {
+                        responses.push(rpc::handle(&ctx.app, &ctx.mempool, call).await);
+                    }
+                    let _ = reply.send(responses);
+                }
+                api::Request::ReloadConfig(reply) => {
+                    let result = match &config_watcher {
+                        Some(watcher) => {
+                            let (path, config) = (watcher.path(), &mut ctx.config);
+                            let reloaded = reload_config(&mut ctx.swarm, &toggles, path, config);
+                            keep_payloads(&mut ctx.app, &chunk_store, ctx.config.cids);
+                            reloaded
+                        }
+                        None => Err(
+                            "no config file, --config was not given and the data directory has none"
+                                .to_string(),
+                        ),
+                    };
+                    let _ = reply.send(result);
+                }
+                api::Request::RotateKeys(reply) => {
+                    let result = match &args.seed {
+                        Some(_) => Err("keys are derived from --seed, not kept".to_string()),
+                        None => match p2p::rotate_keys(&data_dir.keys()) {
+                            Ok(peer_id) => {
+                                log::warn!("Rotated keys, restart to become peer {}", peer_id);
+                                Ok(())
+                            }
+                            Err(e) => Err(e.to_string()),
+                        },
+                    };
+                    let _ = reply.send(result);
+                }
+                api::Request::RotateLogs(reply) => {
+                    let result = match wire::rotate() {
+                        Ok(true) => Ok(()),
+                        Ok(false) => Err("no log file to rotate, --debug-wire is off".to_string()),
+                        Err(e) => Err(e.to_string()),
+                    };
+                    let _ = reply.send(result);
+                }
+            },
+
+            (channel, response) = admin_replies.select_next_some() => {
+                let _ = ctx.swarm.behaviour_mut().admin.send_response(channel, response);
+            }
+
+            _ = config_ticks.select_next_some() => {
+                if let Some(watcher) = &mut config_watcher {
+                    if watcher.changed() {
+                        let (path, config) = (watcher.path(), &mut ctx.config);
+                        if let Err(e) = reload_config(&mut ctx.swarm, &toggles, path, config) {
+                            log::error!("Keeping the current config, could not reload it: {}", e);
+                        }
+                        keep_payloads(&mut ctx.app, &chunk_store, ctx.config.cids);
+                    }
+                }
+            }
+
+            _ = sync_ticks.select_next_some() => {
+                for peer in ctx.sync.expire() {
+                    log::warn!("Sync request to {} timed out", peer);
+                    ctx.peers.adjust_score(peer, peers::SCORE_TIMEOUT);
+                }
+                maybe_sync(&mut ctx.swarm, &ctx.app, &ctx.peers, &mut ctx.sync);
+
+                if !ctx.backfill.is_done() {
+                    for peer in ctx.backfill.expire() {
+                        log::warn!("Backfill request to {} timed out", peer);
+                        ctx.peers.adjust_score(peer, peers::SCORE_TIMEOUT);
+                    }
+                    match ctx.app.gaps().await {
+                        Ok(gaps) => {
+                            ctx.backfill.update(gaps);
+                            report_backfill(&mut ctx.backfill, ctx.app.height());
+                            request_backfill(&mut ctx.swarm, &ctx.peers, &mut ctx.backfill);
+                        }
+                        Err(e) => log::error!("Could not look for gaps in the chain: {}", e),
+                    }
+                }
+
+                let tip = ctx.app.tip().map(|block| block.hash.as_str());
+                let ahead = !ctx.peers.sync_peers(ctx.app.height(), 1).is_empty();
+                match tip_watch.check(tip, ctx.config.stale_tip_after(), ahead) {
+                    Some(health::Transition::Stale { first, stalled }) => {
+                        if first {
+                            events::emit(events::Event::Health {
+                                status: events::Health::Degraded,
+                                height: ctx.app.height(),
+                            });
+                        }
+                        let sync = &mut ctx.sync;
+                        resync_stale_tip(&mut ctx.swarm, &ctx.app, &ctx.peers, sync, stalled);
+                    }
+                    Some(health::Transition::Recovered) => {
+                        events::emit(events::Event::Health {
+                            status: events::Health::Ok,
+                            height: ctx.app.height(),
+                        });
+                    }
+                    None => {}
+                }
+            }
+
+            event = ctx.swarm.select_next_some() => match event {
+                // Messages are rate limited by the peer that relayed them, as their source is
+                // whatever the publisher claims.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Message { relayer, .. })
+                    if !ctx.peers.allow_message(&relayer, ctx.config.max_messages_per_sec) =>
+                {
+                    log::debug!("Dropping message relayed by {}: rate limited", relayer);
+                }
 
-            event = swarm.select_next_some() => match event {
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Listening on {:?}", address);
+                }
 
-                    // Generate the genesis block.
-                    app.genesis();
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    if ctx.peers.is_banned(&peer_id) {
+                        log::info!("Refusing connection from banned peer {}", peer_id);
+                        let _ = ctx.swarm.disconnect_peer_id(peer_id);
+                        continue;
+                    }
+                    if ctx.peers.is_incompatible(&peer_id) {
+                        log::info!("Refusing connection from {} on another chain", peer_id);
+                        let _ = ctx.swarm.disconnect_peer_id(peer_id);
+                        continue;
+                    }
+                    ctx.peers.add_peer(peer_id, endpoint.get_remote_address().clone());
                 }
 
-                // User messages constitut data on a block chain.
-                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Floodsub(
-                    FloodsubEvent::Message(message)
+                // Peers announce their genesis hash over identify. A peer with another genesis
+                // would have every block we send it rejected and vice versa, so drop it.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Identify(event)) => {
+                    if let IdentifyEvent::Received { peer_id, info } = *event {
+                        ctx.peers.set_archival(peer_id, p2p::is_archival(&info.agent_version));
+                        let genesis = p2p::genesis_of(&info.protocol_version);
+                        if genesis != Some(app::genesis_hash()) {
+                            log::warn!(
+                                "Disconnecting {}: genesis {:?} does not match ours ({})",
+                                peer_id,
+                                genesis,
+                                app::genesis_hash()
+                            );
+                            ctx.peers.mark_incompatible(peer_id);
+                            ctx.swarm
+                                .behaviour_mut()
+                                .floodsub
+                                .remove_node_from_partial_view(&peer_id);
+                            let _ = ctx.swarm.disconnect_peer_id(peer_id);
+                        }
+                    }
+                }
+
+                // Peers fetch chunks of anchored files from each other.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Chunks(event)) => match event {
+                    RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Request { request, channel, .. },
+                        ..
+                    } => {
+                        let chunk = chunk_store.get(&request.0).unwrap_or_else(|e| {
+                            log::warn!("Could not read chunk {}: {}", request.0, e);
+                            None
+                        });
+                        let _ = ctx.swarm
+                            .behaviour_mut()
+                            .chunks
+                            .send_response(channel, files::ChunkResponse(chunk));
+                    }
+                    RequestResponseEvent::Message {
+                        message: RequestResponseMessage::Response { request_id, response },
+                        ..
+                    } => chunk_fetches.complete(&mut ctx.swarm, &chunk_store, request_id, response),
+                    RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                        log::debug!("Chunk request to {} failed: {}", peer, error);
+                        chunk_fetches.fail(&mut ctx.swarm, request_id);
+                    }
+                    _ => {}
+                },
+
+                // Operators instruct the node over the admin channel, which only acts on the
+                // commands of the operator keys in the config.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Admin(
+                    RequestResponseEvent::Message {
+                        peer,
+                        message: RequestResponseMessage::Request { request, channel, .. },
+                    }
                 )) => {
-                    // Get the previous block.
-                    let latest_block = app.blocks.last().unwrap();
+                    let (reply, answer) = oneshot::channel();
+                    let queued = if ctx.config.operators.contains(&peer.to_string()) {
+                        log::info!("Admin command from operator {}: {:?}", peer, request);
+                        request.into_api(reply).map(|request| {
+                            let _ = admin_requests.try_send(request);
+                        })
+                    } else {
+                        log::warn!("Refusing admin command from {}: not an operator", peer);
+                        Err("not an operator of this node".to_string())
+                    };
+                    match queued {
+                        Ok(()) => admin_replies.push(admin::pending(channel, answer)),
+                        Err(e) => {
+                            let admin = &mut ctx.swarm.behaviour_mut().admin;
+                            let _ = admin.send_response(channel, admin::AdminResponse(Err(e)));
+                        }
+                    }
+                }
 
-                    // Create a new block with the message data.
-                    let block = app::Block::new(latest_block.hash.clone(), message.data.clone());
-                    log::info!("New block: {:?}", block);
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    ctx.peers.remove_peer(&peer_id);
+                    check_clock(&ctx.peers, &ctx.config, &mut ctx.clock_skewed);
+                    ctx.sync.forget(&peer_id);
+                    ctx.backfill.forget(&peer_id);
+                }
 
-                    log::info!("Received message: {:?}", message);
-                    collection.insert_one(doc! {"data": "hi"}, None).await?;
+                // Deliver what we published while nobody was listening, and ask a peer for its
+                // chain as soon as it can hear sync requests.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Floodsub(
+                    FloodsubEvent::Subscribed { peer_id, topic }
+                )) => {
+                    p2p::flush(&mut ctx.swarm, &topic);
+                    if topic == *p2p::SYNC_TOP {
+                        p2p::request_range(&mut ctx.swarm, &peer_id, ctx.app.height(), None);
+                    }
+                }
+
+                // Every other pubsub message goes to the handler registered for its kind.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Message { message, .. }) => {
+                    // A handler failing, e.g. on a storage error, only loses that message.
+                    let source = message.source;
+                    match router.dispatch(&mut ctx, &message).await {
+                        Ok(true) => {}
+                        Ok(false) => log::warn!("Invalid message from {}", source),
+                        Err(e) => log::error!("Could not handle message from {}: {}", source, e),
+                    }
                 }
 
+                // Round-trip times feed into how we rank peers as sync sources.
+                SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Ping(ping::Event {
+                    peer,
+                    result,
+                })) => match result {
+                    Ok(ping::Success::Ping { rtt }) => ctx.peers.record_rtt(peer, rtt),
+                    Ok(ping::Success::Pong) => {}
+                    Err(e) => {
+                        log::debug!("Ping to {} failed: {}", peer, e);
+                        ctx.peers.adjust_score(peer, peers::SCORE_PING_FAILURE);
+                    }
+                },
+
                 // If a peer joins the network, add it to the floodsub viewer.
                 SwarmEvent::Behaviour(p2p::AppBehaviorEvent::Mdns(
                     MdnsEvent::Discovered(list)
                 )) => {
                     for (peer, _) in list {
-                        swarm
+                        if ctx.peers.is_banned(&peer) || ctx.peers.is_incompatible(&peer) {
+                            continue;
+                        }
+                        ctx.swarm
                             .behaviour_mut()
                             .floodsub
                             .add_node_to_partial_view(peer);
                     }
+                    log::info!("Discovered peers:");
+                    p2p::print_peers(&ctx.swarm);
                 }
 
                 // If a peer leaves the network, remove it from the floodsub viewer.
@@ -129,8 +1530,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     list
                 ))) => {
                     for (peer, _) in list {
-                        if !swarm.behaviour_mut().mdns.has_node(&peer) {
-                            swarm
+                        if !ctx.swarm.behaviour_mut().mdns.has_node(&peer) {
+                            ctx.swarm
                                 .behaviour_mut()
                                 .floodsub
                                 .remove_node_from_partial_view(&peer);
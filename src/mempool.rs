@@ -0,0 +1,460 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::app::{Block, Transaction};
+use crate::nonces::Nonces;
+use crate::receipts::{Receipt, Receipts};
+use crate::rejects::{self, Subject};
+use crate::validator::Validators;
+
+// MAX_MEMPOOL_SIZE bounds how many pending transactions we hold.
+const MAX_MEMPOOL_SIZE: usize = 10_000;
+
+// MAX_BATCH_SIZE bounds how many transactions a batch admits at once.
+pub const MAX_BATCH_SIZE: usize = 1000;
+
+// MIN_FEE_BUMP_PERCENT is how much higher the fee of a replacement transaction has to be
+// than the fee of the one it replaces.
+const MIN_FEE_BUMP_PERCENT: u64 = 10;
+
+// Source is where a pending transaction came from: submitted to this node, or gossiped by a
+// peer. It is recorded as the transaction is admitted, since anyone can relay a transaction
+// of any sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Local,
+    Remote,
+}
+
+// Mempool holds the transactions that are waiting to be mined into a block. A sender has at
+// most one pending transaction per nonce; a transaction with the same sender and nonce
+// replaces it if it pays a sufficiently higher fee.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: HashMap<String, Transaction>,
+    // slots maps each pending (sender, nonce) pair to the id of its transaction.
+    slots: HashMap<(String, u64), String>,
+    // sources maps the id of each pending transaction to where it came from.
+    sources: HashMap<String, Source>,
+    validators: Validators,
+    // receipts follow the transactions submitted with a correlation id beyond the mempool.
+    receipts: Receipts,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // with_validators creates a mempool that only admits transactions every validator accepts.
+    pub fn with_validators(validators: Validators) -> Self {
+        Self {
+            validators,
+            ..Self::default()
+        }
+    }
+
+    // insert admits a transaction from the source, returning false if it is invalid, already
+    // pending, the mempool is full, its nonce is not past the nonces its sender used in
+    // `mined`, the nonces of our chain, or it conflicts with a pending transaction it does not
+    // pay enough to replace.
+    pub fn insert(&mut self, tx: Transaction, source: Source, mined: &Nonces) -> bool {
+        match self.check(&tx, mined) {
+            Ok(replaced) => {
+                self.admit(tx, source, replaced);
+                true
+            }
+            Err(Some(reason)) => reject(tx, reason),
+            Err(None) => false,
+        }
+    }
+
+    // check returns whether the transaction can be admitted, along with the id of the pending
+    // transaction it replaces, if any. Transactions that are already pending are turned away
+    // without a reason, since there is nothing wrong with them.
+    fn check(&self, tx: &Transaction, mined: &Nonces) -> Result<Option<String>, Option<String>> {
+        if !tx.is_valid() {
+            return Err(Some("id or signature does not match contents".to_string()));
+        }
+        if tx.coinbase().is_some() {
+            return Err(Some("coinbase outside a block".to_string()));
+        }
+        self.validators.check(tx).map_err(Some)?;
+        if self.transactions.contains_key(&tx.id) {
+            return Err(None);
+        }
+        if let Some(highest) = mined.highest(&tx.sender).filter(|&nonce| nonce >= tx.nonce) {
+            return Err(Some(format!(
+                "nonce {} is not past {}, the last one its sender used on chain",
+                tx.nonce, highest
+            )));
+        }
+
+        match self
+            .slots
+            .get(&(tx.sender.clone(), tx.nonce))
+            .and_then(|id| self.transactions.get(id))
+        {
+            Some(pending) if tx.fee < replacement_fee(pending.fee) => Err(Some(format!(
+                "replacing {} requires a fee of at least {}",
+                pending.id,
+                replacement_fee(pending.fee)
+            ))),
+            Some(pending) => Ok(Some(pending.id.clone())),
+            None if self.transactions.len() >= MAX_MEMPOOL_SIZE => {
+                Err(Some("mempool is full".to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // admit adds a transaction that passed check, dropping the one it replaces.
+    fn admit(&mut self, tx: Transaction, source: Source, replaced: Option<String>) {
+        if let Some(id) = replaced {
+            log::info!("Transaction {} replaces {}", tx.id, id);
+            self.transactions.remove(&id);
+            self.sources.remove(&id);
+            self.receipts.replaced(&id, &tx.id);
+        }
+        self.slots
+            .insert((tx.sender.clone(), tx.nonce), tx.id.clone());
+        self.sources.insert(tx.id.clone(), source);
+        self.transactions.insert(tx.id.clone(), tx);
+    }
+
+    // insert_batch admits every transaction of the batch or none of them as local ones,
+    // keeping a receipt of each under its correlation id. It returns why each transaction was
+    // refused, or Ok for those that could be admitted; the batch was admitted if every one of
+    // them is Ok.
+    pub fn insert_batch(
+        &mut self,
+        batch: Vec<(Transaction, String)>,
+        mined: &Nonces,
+    ) -> Vec<Result<(), String>> {
+        if batch.len() > MAX_BATCH_SIZE {
+            let reason = format!("batches hold at most {} transactions", MAX_BATCH_SIZE);
+            return vec![Err(reason); batch.len()];
+        }
+        let mut slots = HashSet::new();
+        let mut added = 0;
+        let mut checks = vec![];
+        for (tx, _) in &batch {
+            let check = match self.check(tx, mined) {
+                Err(reason) => Err(reason.unwrap_or_else(|| "already pending".to_string())),
+                Ok(_) if !slots.insert((&tx.sender, tx.nonce)) => {
+                    Err("another transaction of the batch has the same nonce".to_string())
+                }
+                Ok(None) if self.transactions.len() + added >= MAX_MEMPOOL_SIZE => {
+                    Err("mempool is full".to_string())
+                }
+                Ok(replaced) => {
+                    added += usize::from(replaced.is_none());
+                    Ok(replaced)
+                }
+            };
+            checks.push(check);
+        }
+
+        let results: Vec<Result<(), String>> = checks
+            .iter()
+            .map(|check| check.as_ref().map(|_| ()).map_err(Clone::clone))
+            .collect();
+        if results.iter().any(Result::is_err) {
+            for ((tx, _), result) in batch.iter().zip(&results) {
+                if let Err(reason) = result {
+                    log::warn!("Refusing batch: transaction {} {}", tx.id, reason);
+                }
+            }
+            return results;
+        }
+        for ((tx, correlation_id), check) in batch.into_iter().zip(checks) {
+            let id = tx.id.clone();
+            self.admit(tx, Source::Local, check.expect("every check passed"));
+            self.receipts.track(correlation_id, id);
+        }
+        results
+    }
+
+    // insert_correlated admits a local transaction like insert, and keeps a receipt of it
+    // under the correlation id.
+    pub fn insert_correlated(
+        &mut self,
+        tx: Transaction,
+        correlation_id: String,
+        mined: &Nonces,
+    ) -> bool {
+        let id = tx.id.clone();
+        if !self.insert(tx, Source::Local, mined) {
+            return false;
+        }
+        self.receipts.track(correlation_id, id);
+        true
+    }
+
+    // receipt returns where the transaction submitted under the correlation id is.
+    pub fn receipt(&self, correlation_id: &str) -> Option<&Receipt> {
+        self.receipts.get(correlation_id)
+    }
+
+    // remove_included drops the transactions that made it into the block, along with the
+    // pending transactions they conflict with.
+    pub fn remove_included(&mut self, block: &Block) {
+        // Coinbase nonces are block heights, so they don't occupy the miner's slots.
+        for tx in block
+            .transactions
+            .iter()
+            .filter(|tx| tx.coinbase().is_none())
+        {
+            if let Some(id) = self.slots.remove(&(tx.sender.clone(), tx.nonce)) {
+                self.transactions.remove(&id);
+                self.sources.remove(&id);
+                if id != tx.id {
+                    self.receipts.replaced(&id, &tx.id);
+                }
+            }
+            self.receipts.included(&tx.id, block);
+        }
+    }
+
+    // highest_nonce returns the highest nonce of the sender's pending transactions.
+    pub fn highest_nonce(&self, sender: &str) -> Option<u64> {
+        self.slots
+            .keys()
+            .filter(|(s, _)| s == sender)
+            .map(|(_, nonce)| *nonce)
+            .max()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.transactions.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    // by_priority returns the pending transactions along with their source, in the order the
+    // miner should consider them: highest fee first, then oldest first.
+    pub fn by_priority(&self) -> Vec<(&Transaction, Source)> {
+        let mut txs: Vec<&Transaction> = self.transactions.values().collect();
+        txs.sort_by(|a, b| {
+            b.fee
+                .cmp(&a.fee)
+                .then(a.timestamp.cmp(&b.timestamp))
+                .then(a.id.cmp(&b.id))
+        });
+        txs.into_iter()
+            .map(|tx| (tx, self.sources[&tx.id]))
+            .collect()
+    }
+}
+
+// replacement_fee is the lowest fee a transaction needs to replace a pending one paying `fee`.
+fn replacement_fee(fee: u64) -> u64 {
+    fee.saturating_add((fee.saturating_mul(MIN_FEE_BUMP_PERCENT) / 100).max(1))
+}
+
+// reject logs why the transaction was turned away and records it as a reject, returning
+// false for insert to pass on.
+fn reject(tx: Transaction, reason: String) -> bool {
+    log::warn!("Rejecting transaction {}: {}", tx.id, reason);
+    rejects::record(Subject::Transaction(tx), reason);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use libp2p::identity::Keypair;
+
+    use crate::receipts::Status;
+
+    fn tx(keys: &Keypair, nonce: u64, fee: u64) -> Transaction {
+        Transaction::new(keys, nonce, fee, Bytes::from(format!("{}/{}", nonce, fee)))
+    }
+
+    #[test]
+    fn replacement_needs_a_higher_fee() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let original = tx(&keys, 0, 100);
+        assert!(mempool.insert(original.clone(), Source::Remote, &mined));
+
+        assert!(!mempool.insert(tx(&keys, 0, 109), Source::Remote, &mined));
+        assert!(mempool.contains(&original.id));
+
+        let replacement = tx(&keys, 0, 110);
+        assert!(mempool.insert(replacement.clone(), Source::Remote, &mined));
+        assert!(!mempool.contains(&original.id));
+        assert!(mempool.contains(&replacement.id));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn replacement_fee_always_rises() {
+        assert_eq!(replacement_fee(0), 1);
+        assert_eq!(replacement_fee(5), 6);
+        assert_eq!(replacement_fee(100), 110);
+        assert_eq!(replacement_fee(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn replacement_updates_the_receipt() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let original = tx(&keys, 0, 100);
+        assert!(mempool.insert_correlated(original, "order-1".to_string(), &mined));
+        let replacement = tx(&keys, 0, 200);
+        assert!(mempool.insert(replacement.clone(), Source::Remote, &mined));
+        assert_eq!(
+            mempool.receipt("order-1").map(|receipt| &receipt.status),
+            Some(&Status::Replaced { by: replacement.id })
+        );
+    }
+
+    #[test]
+    fn senders_have_slots_of_their_own() {
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        assert!(mempool.insert(tx(&alice, 0, 100), Source::Remote, &mined));
+        assert!(mempool.insert(tx(&bob, 0, 1), Source::Remote, &mined));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn mined_nonces_are_refused() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mut mined = Nonces::default();
+        let included = tx(&keys, 1, 100);
+        assert!(mempool.insert(included.clone(), Source::Remote, &mined));
+        let block = Block {
+            height: 1,
+            hash: String::new(),
+            previous_hash: String::new(),
+            timestamp: 0,
+            transactions: vec![included.clone()],
+            nonce: 0,
+            version: 0,
+        };
+        mined.apply(&block);
+        mempool.remove_included(&block);
+
+        assert!(!mempool.insert(included, Source::Remote, &mined));
+        assert!(!mempool.insert(tx(&keys, 1, 200), Source::Remote, &mined));
+        assert!(!mempool.insert(tx(&keys, 0, 100), Source::Remote, &mined));
+        assert!(mempool.insert(tx(&keys, 2, 100), Source::Remote, &mined));
+    }
+
+    #[test]
+    fn sources_are_kept_with_the_transactions() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let (local, remote) = (tx(&keys, 0, 100), tx(&keys, 1, 100));
+        assert!(mempool.insert(local.clone(), Source::Local, &mined));
+        assert!(mempool.insert(remote.clone(), Source::Remote, &mined));
+        let sources: HashMap<_, _> = mempool
+            .by_priority()
+            .into_iter()
+            .map(|(tx, source)| (tx.id.clone(), source))
+            .collect();
+        assert_eq!(sources[&local.id], Source::Local);
+        assert_eq!(sources[&remote.id], Source::Remote);
+
+        let replacement = tx(&keys, 0, 200);
+        assert!(mempool.insert(replacement.clone(), Source::Remote, &mined));
+        assert_eq!(mempool.by_priority()[0], (&replacement, Source::Remote));
+    }
+
+    #[test]
+    fn batch_is_admitted_whole() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let batch: Vec<_> = (0..3)
+            .map(|nonce| (tx(&keys, nonce, 10), format!("order-{}", nonce)))
+            .collect();
+        let results = mempool.insert_batch(batch, &mined);
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.highest_nonce(&tx(&keys, 0, 0).sender), Some(2));
+        assert!(mempool.receipt("order-2").is_some());
+    }
+
+    #[test]
+    fn batch_with_a_bad_transaction_admits_nothing() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let mut forged = tx(&keys, 1, 10);
+        forged.fee = 1_000;
+        let batch = vec![
+            (tx(&keys, 0, 10), "order-0".to_string()),
+            (forged, "order-1".to_string()),
+            (tx(&keys, 2, 10), "order-2".to_string()),
+        ];
+        let results = mempool.insert_batch(batch, &mined);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        assert!(mempool.is_empty());
+        assert!(mempool.receipt("order-0").is_none());
+    }
+
+    #[test]
+    fn batch_with_a_repeated_nonce_admits_nothing() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let batch = vec![
+            (tx(&keys, 0, 10), "order-0".to_string()),
+            (tx(&keys, 0, 20), "order-1".to_string()),
+        ];
+        let results = mempool.insert_batch(batch, &mined);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn batch_that_fails_to_replace_keeps_the_pending_transaction() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let pending = tx(&keys, 0, 100);
+        assert!(mempool.insert(pending.clone(), Source::Remote, &mined));
+        let batch = vec![
+            (tx(&keys, 0, 200), "order-0".to_string()),
+            (tx(&keys, 1, 10), "order-1".to_string()),
+            (tx(&keys, 1, 10), "order-2".to_string()),
+        ];
+        assert!(mempool
+            .insert_batch(batch, &mined)
+            .iter()
+            .any(Result::is_err));
+        assert!(mempool.contains(&pending.id));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn oversized_batch_is_refused() {
+        let keys = Keypair::generate_ed25519();
+        let mut mempool = Mempool::new();
+        let mined = Nonces::default();
+        let batch = (0..=MAX_BATCH_SIZE as u64)
+            .map(|nonce| (tx(&keys, nonce, 10), nonce.to_string()))
+            .collect();
+        let results = mempool.insert_batch(batch, &mined);
+        assert_eq!(results.len(), MAX_BATCH_SIZE + 1);
+        assert!(results.iter().all(Result::is_err));
+        assert!(mempool.is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Leaves and inner nodes are hashed with different prefixes so a leaf can never be passed
+// off as an inner node.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+// Side says on which side of the path a sibling hash is combined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// Step is one level of a Merkle proof.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub side: Side,
+    // hash is the hex encoded sibling hash.
+    pub hash: String,
+}
+
+fn leaf(data: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain([LEAF_PREFIX])
+        .chain(data)
+        .finalize()
+        .into()
+}
+
+fn node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain([NODE_PREFIX])
+        .chain(left)
+        .chain(right)
+        .finalize()
+        .into()
+}
+
+// next_level pairs up the hashes of a level. An odd hash out is promoted unchanged rather
+// than paired with itself, so no two lists of leaves share a root.
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node(left, right),
+            [single] => *single,
+            _ => unreachable!("chunks of two"),
+        })
+        .collect()
+}
+
+// root returns the Merkle root of the leaves, which is all zeroes for no leaves.
+pub fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+// proof returns the steps from the leaf at `index` up to the root, or None if there is no
+// such leaf.
+pub fn proof(leaves: &[[u8; 32]], mut index: usize) -> Option<Vec<Step>> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = vec![];
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(leaf).collect();
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            let side = if sibling < index {
+                Side::Left
+            } else {
+                Side::Right
+            };
+            steps.push(Step {
+                side,
+                hash: hex::encode(level[sibling]),
+            });
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(steps)
+}
+
+// verify checks that the steps lead from the leaf to the root.
+pub fn verify(data: &[u8; 32], steps: &[Step], root: &[u8; 32]) -> bool {
+    let mut hash = leaf(data);
+    for step in steps {
+        let mut sibling = [0; 32];
+        if hex::decode_to_slice(&step.hash, &mut sibling).is_err() {
+            return false;
+        }
+        hash = match step.side {
+            Side::Left => node(&sibling, &hash),
+            Side::Right => node(&hash, &sibling),
+        };
+    }
+    hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u8) -> Vec<[u8; 32]> {
+        (0..count).map(|i| [i; 32]).collect()
+    }
+
+    #[test]
+    fn root_of_no_leaves_is_zero() {
+        assert_eq!(root(&[]), [0; 32]);
+    }
+
+    #[test]
+    fn root_of_a_single_leaf_is_its_leaf_hash() {
+        assert_eq!(root(&leaves(1)), leaf(&[0; 32]));
+        assert_eq!(proof(&leaves(1), 0), Some(vec![]));
+        assert!(verify(&[0; 32], &[], &root(&leaves(1))));
+    }
+
+    #[test]
+    fn odd_leaf_is_promoted_not_duplicated() {
+        let three = leaves(3);
+        let (a, b, c) = (leaf(&three[0]), leaf(&three[1]), leaf(&three[2]));
+        assert_eq!(root(&three), node(&node(&a, &b), &c));
+
+        // Repeating the last leaf would give the same root if it were paired with itself.
+        let mut four = three.clone();
+        four.push(three[2]);
+        assert_ne!(root(&three), root(&four));
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, data) in leaves.iter().enumerate() {
+                let steps = proof(&leaves, index).expect("leaf exists");
+                assert!(verify(data, &steps, &root), "leaf {} of {}", index, count);
+            }
+            assert_eq!(proof(&leaves, leaves.len()), None);
+        }
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let leaves = leaves(5);
+        let root = root(&leaves);
+        let steps = proof(&leaves, 2).expect("leaf exists");
+
+        assert!(!verify(&[9; 32], &steps, &root));
+
+        let mut flipped = steps.clone();
+        flipped[0].side = Side::Left;
+        assert!(!verify(&leaves[2], &flipped, &root));
+
+        let mut replaced = steps.clone();
+        replaced[1].hash = hex::encode([0xff; 32]);
+        assert!(!verify(&leaves[2], &replaced, &root));
+
+        let mut garbled = steps.clone();
+        garbled[0].hash = "not hex".to_string();
+        assert!(!verify(&leaves[2], &garbled, &root));
+
+        assert!(!verify(&leaves[2], &steps[1..], &root));
+    }
+}
@@ -0,0 +1,182 @@
+use mongodb::event::command::{
+    CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// FORKS counts the forks we observed, labelled by the branch that won.
+pub static FORKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mchain_forks_total",
+        "Forks observed between our chain and a peer's",
+        &["winner"]
+    )
+    .expect("metric can be registered")
+});
+
+// FORK_DEPTH tracks how many blocks the losing branch of each fork had.
+pub static FORK_DEPTH: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "mchain_fork_depth_blocks",
+        "Blocks on the losing branch of a fork",
+        vec![1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 50.0, 100.0]
+    )
+    .expect("metric can be registered")
+});
+
+// BLOCK_PROPAGATION tracks how long new blocks took to reach us from the peer that mined
+// them, corrected for the peer's clock offset.
+pub static BLOCK_PROPAGATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "mchain_block_propagation_seconds",
+        "Time between a block being broadcast and it reaching us",
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0]
+    )
+    .expect("metric can be registered")
+});
+
+// DEGRADED is 1 while the node is stuck on a stale tip and 0 otherwise.
+pub static DEGRADED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "mchain_degraded",
+        "Whether the node is stuck on a stale tip while peers are ahead"
+    )
+    .expect("metric can be registered")
+});
+
+// QUEUED_PUBLISHES is how many publishes are held back until a peer is connected.
+pub static QUEUED_PUBLISHES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "mchain_queued_publishes",
+        "Publishes waiting for a peer to be connected"
+    )
+    .expect("metric can be registered")
+});
+
+// DROPPED_PUBLISHES counts the queued publishes dropped because the queue was full, labelled
+// by topic.
+pub static DROPPED_PUBLISHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mchain_dropped_publishes_total",
+        "Queued publishes dropped because the queue was full",
+        &["topic"]
+    )
+    .expect("metric can be registered")
+});
+
+// MONGODB_COMMAND_DURATION tracks how long each MongoDB command took, successful or not.
+pub static MONGODB_COMMAND_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "mchain_mongodb_command_duration_seconds",
+        "Latency of MongoDB commands",
+        &["command"]
+    )
+    .expect("metric can be registered")
+});
+
+// MONGODB_COMMAND_ERRORS counts the MongoDB commands that failed.
+pub static MONGODB_COMMAND_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mchain_mongodb_command_errors_total",
+        "MongoDB commands that failed",
+        &["command"]
+    )
+    .expect("metric can be registered")
+});
+
+// MONGODB_COMMAND_RETRIES counts the MongoDB commands the driver retried after a failure.
+pub static MONGODB_COMMAND_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "mchain_mongodb_command_retries_total",
+        "MongoDB commands retried by the driver",
+        &["command"]
+    )
+    .expect("metric can be registered")
+});
+
+// MongoMetrics records MongoDB command metrics from the driver's command monitoring events.
+//
+// The driver does not report retries directly, but it retries an operation by sending the
+// same command again on the same session, with the same txnNumber for writes. We remember
+// the last failed command of each session and count a retry when it is started again.
+#[derive(Debug, Default)]
+pub struct MongoMetrics {
+    commands: Mutex<Commands>,
+}
+
+// Command identifies a command by name and, for retryable writes, transaction number.
+type Command = (String, Option<i64>);
+
+#[derive(Debug, Default)]
+struct Commands {
+    // in_flight maps the request id of every started command to its session.
+    in_flight: HashMap<i32, (String, Command)>,
+    // failed holds the last failed command of each session.
+    failed: HashMap<String, Command>,
+}
+
+impl CommandEventHandler for MongoMetrics {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        let session = match event.command.get_document("lsid") {
+            Ok(lsid) => lsid.to_string(),
+            Err(_) => return,
+        };
+        let command = (event.command_name, event.command.get_i64("txnNumber").ok());
+
+        let mut commands = self
+            .commands
+            .lock()
+            .expect("mongo metrics lock is not poisoned");
+        if commands.failed.remove(&session).as_ref() == Some(&command) {
+            MONGODB_COMMAND_RETRIES
+                .with_label_values(&[&command.0])
+                .inc();
+        }
+        commands
+            .in_flight
+            .insert(event.request_id, (session, command));
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        MONGODB_COMMAND_DURATION
+            .with_label_values(&[&event.command_name])
+            .observe(event.duration.as_secs_f64());
+
+        let mut commands = self
+            .commands
+            .lock()
+            .expect("mongo metrics lock is not poisoned");
+        commands.in_flight.remove(&event.request_id);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        MONGODB_COMMAND_DURATION
+            .with_label_values(&[&event.command_name])
+            .observe(event.duration.as_secs_f64());
+        MONGODB_COMMAND_ERRORS
+            .with_label_values(&[&event.command_name])
+            .inc();
+
+        let mut commands = self
+            .commands
+            .lock()
+            .expect("mongo metrics lock is not poisoned");
+        if let Some((session, command)) = commands.in_flight.remove(&event.request_id) {
+            commands.failed.insert(session, command);
+        }
+    }
+}
+
+// gather renders every registered metric in the Prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        log::error!("could not encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
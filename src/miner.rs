@@ -0,0 +1,187 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Mutex;
+
+use crate::app::{self, Block, Transaction};
+use crate::mempool::{Mempool, Source};
+use crate::p2p;
+use crate::payload::{Coinbase, Payload};
+
+// MAX_BLOCK_SIZE is the maximum serialized size of the transactions in a block, in bytes.
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+// COINBASE_RESERVE is the room left in a block for the coinbase, which is only built once
+// the other transactions and their fees are known.
+const COINBASE_RESERVE: usize = 1024;
+
+// POLICY decides which pending transactions the miner picks up; see Policy.
+static POLICY: Lazy<Mutex<Policy>> = Lazy::new(|| Mutex::new(Policy::default()));
+
+// set_policy changes the policy of the blocks mined from now on.
+pub fn set_policy(policy: &Policy) {
+    *POLICY.lock().expect("policy lock is not poisoned") = policy.clone();
+}
+
+// Sources selects the transactions that are mined by their source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sources {
+    Local,
+    Remote,
+    #[default]
+    All,
+}
+
+// Policy lets operators choose what gets mined, so that a public node can't have its blocks
+// monopolized by transactions gossiped by others. The quotas bound the bytes of a block
+// that transactions of each source may take up; 0 leaves them bounded by the block size only.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub sources: Sources,
+    pub local_quota_bytes: usize,
+    pub remote_quota_bytes: usize,
+}
+
+impl Policy {
+    // admits returns whether transactions of the source are mined at all.
+    pub fn admits(&self, source: Source) -> bool {
+        matches!(
+            (self.sources, source),
+            (Sources::All, _) | (Sources::Local, Source::Local) | (Sources::Remote, Source::Remote)
+        )
+    }
+
+    // quota returns how many bytes of a block transactions of the source may take up.
+    pub fn quota(&self, source: Source) -> usize {
+        let quota = match source {
+            Source::Local => self.local_quota_bytes,
+            Source::Remote => self.remote_quota_bytes,
+        };
+        match quota {
+            0 => MAX_BLOCK_SIZE,
+            quota => quota,
+        }
+    }
+}
+
+// Template is the block the miner will try to seal next.
+#[derive(Debug, Clone, Serialize)]
+pub struct Template {
+    pub previous_hash: String,
+    pub height: usize,
+    // transactions starts with the coinbase paying this node.
+    pub transactions: Vec<Transaction>,
+    // size is the serialized size of the selected transactions, in bytes.
+    pub size: usize,
+    // local_size and remote_size split the size of the selected transactions by their
+    // source, not counting the coinbase.
+    pub local_size: usize,
+    pub remote_size: usize,
+    pub fees: u64,
+    // reward is what the emission schedule pays for the block, on top of the fees.
+    pub reward: u64,
+    // difficulty is the prefix the binary representation of the block hash must start with.
+    pub difficulty: String,
+    // version signals the deployments this node is ready for; see versionbits.
+    pub version: u32,
+}
+
+impl Template {
+    // new selects the most valuable pending transactions that fit in a block on top of our
+    // current tip, within the quotas of the mining policy.
+    pub fn new(app: &app::App, mempool: &Mempool) -> Self {
+        let policy = POLICY.lock().expect("policy lock is not poisoned").clone();
+        let tip = app.tip().expect("there is at least one block");
+        let height = app.height();
+        let mut transactions = vec![];
+        let mut size = 0;
+        let (mut local_size, mut remote_size) = (0, 0);
+        for (tx, source) in mempool.by_priority() {
+            if !policy.admits(source) {
+                continue;
+            }
+            let tx_size = transaction_size(tx);
+            let source_size = match source {
+                Source::Local => &mut local_size,
+                Source::Remote => &mut remote_size,
+            };
+            if size + tx_size > MAX_BLOCK_SIZE - COINBASE_RESERVE
+                || *source_size + tx_size > policy.quota(source)
+            {
+                continue;
+            }
+            *source_size += tx_size;
+            size += tx_size;
+            transactions.push(tx.clone());
+        }
+
+        let fees = transactions.iter().map(|tx| tx.fee).sum::<u64>();
+        let reward = app.genesis.emission.reward(height);
+        let coinbase = Transaction::new(
+            &p2p::KEYS,
+            height as u64,
+            0,
+            Payload::Coinbase(Coinbase {
+                amount: reward.saturating_add(fees),
+            })
+            .encode(),
+        );
+        size += transaction_size(&coinbase);
+        transactions.insert(0, coinbase);
+
+        Self {
+            previous_hash: tip.hash.clone(),
+            height,
+            transactions,
+            size,
+            local_size,
+            remote_size,
+            fees,
+            reward,
+            difficulty: app::DIFFICULTY_PREFIX.to_string(),
+            version: app.deployments.version(),
+        }
+    }
+
+    // mine seals the template into a block by searching for a valid nonce.
+    pub fn mine(self) -> Block {
+        Block::new(
+            self.height,
+            self.version,
+            self.previous_hash,
+            self.transactions,
+        )
+    }
+}
+
+// block_size returns the size of the block's transactions, as counted against
+// MAX_BLOCK_SIZE when the block was mined.
+pub fn block_size(block: &Block) -> usize {
+    block.transactions.iter().map(transaction_size).sum()
+}
+
+// transaction_size returns the serialized size of the transaction without serializing it
+// into a buffer.
+fn transaction_size(tx: &Transaction) -> usize {
+    let mut counter = ByteCounter(0);
+    match serde_json::to_writer(&mut counter, tx) {
+        Ok(()) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+// ByteCounter is a writer that only counts what is written to it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
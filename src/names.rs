@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::app::{Block, Transaction};
+use crate::payload::{NameClaim, Payload};
+use crate::state::State;
+
+// NAME_TTL_BLOCKS is how many blocks a registration lasts before the name can be claimed by
+// someone else. Owners keep a name by registering it again before it expires.
+pub const NAME_TTL_BLOCKS: usize = 10_000;
+
+// MAX_NAME_LEN bounds the length of a registered name.
+const MAX_NAME_LEN: usize = 64;
+
+// NameRecord is the current registration of a name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameRecord {
+    pub name: String,
+    // owner is the sender of the transaction that claimed the name. Transactions are signed by
+    // their sender, so nobody else can renew or take over the name before it expires.
+    pub owner: String,
+    pub address: String,
+    pub registered_at: usize,
+    // expires_at is the first height at which the name is free again.
+    pub expires_at: usize,
+}
+
+// validate_payload is a PayloadValidator that rejects malformed name claims, so they never
+// reach the mempool or a block.
+pub fn validate_payload(tx: &Transaction) -> Result<(), String> {
+    match Payload::decode(&tx.payload) {
+        Some(Payload::Name(claim)) if !is_valid_name(&claim.name) => Err(format!(
+            "invalid name {:?}: use up to {} lowercase letters, digits and dashes",
+            claim.name, MAX_NAME_LEN
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+// Names is the name registry, built by replaying the name claims on the chain. The first
+// claim of a free name wins; claims of a name registered to someone else are ignored until
+// it expires, and claims by the owner renew it.
+#[derive(Debug, Default)]
+pub struct Names {
+    // names holds every accepted registration of each name, oldest first, so the registry
+    // can also be read as it was at an earlier height.
+    names: HashMap<String, Vec<NameRecord>>,
+}
+
+impl State for Names {
+    fn apply(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            if let Some(Payload::Name(claim)) = Payload::decode(&tx.payload) {
+                self.claim(block.height, &tx.sender, claim);
+            }
+        }
+    }
+
+    // prune drops the registrations that were already superseded by the first `horizon`
+    // blocks, keeping the last registration of every name.
+    fn prune(&mut self, horizon: usize) {
+        for records in self.names.values_mut() {
+            let superseded = records
+                .iter()
+                .skip(1)
+                .take_while(|record| record.registered_at < horizon)
+                .count();
+            records.drain(..superseded);
+        }
+    }
+}
+
+impl Names {
+    fn claim(&mut self, height: usize, sender: &str, claim: NameClaim) {
+        if let Some(record) = self
+            .names
+            .get(&claim.name)
+            .and_then(|records| records.last())
+        {
+            if record.owner != sender && record.expires_at > height {
+                log::debug!("Ignoring claim of {} by {}: taken", claim.name, sender);
+                return;
+            }
+        }
+        let record = NameRecord {
+            name: claim.name.clone(),
+            owner: sender.to_string(),
+            address: claim.address,
+            registered_at: height,
+            expires_at: height + NAME_TTL_BLOCKS,
+        };
+        self.names.entry(claim.name).or_default().push(record);
+    }
+
+    // resolve returns the registration of the name as of the chain's first `height` blocks,
+    // if it has not expired by then.
+    pub fn resolve(&self, name: &str, height: usize) -> Option<&NameRecord> {
+        self.names
+            .get(name)?
+            .iter()
+            .rev()
+            .find(|record| record.registered_at < height)
+            .filter(|record| record.expires_at > height)
+    }
+}
@@ -0,0 +1,232 @@
+use async_std::task;
+use bytes::Bytes;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::app::{App, Block};
+use crate::genesis::Genesis;
+use crate::mempool::{Mempool, Source};
+use crate::miner;
+use crate::p2p;
+use crate::receipts::Status;
+use crate::storage::{self, MemoryStorage, Storage};
+use crate::validator::{PayloadValidator, Validators};
+use crate::wallet::Wallet;
+
+// NodeBuilder assembles the chain engine: storage, validation, the mempool and the miner.
+// The node it builds does no networking at all, so it can be embedded in a single process
+// as an append-only log. Its blocks have the same format as those of a networked node, and
+// a networked node started on the same storage picks up the chain where it left off.
+#[derive(Default)]
+pub struct NodeBuilder {
+    storage: Option<Arc<dyn Storage>>,
+    genesis: Genesis,
+    validators: Validators,
+}
+
+impl NodeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // storage sets where blocks are persisted; they are kept in memory by default.
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn genesis(mut self, genesis: Genesis) -> Self {
+        self.genesis = genesis;
+        self
+    }
+
+    // validator registers a payload validator shared by the mempool and the chain.
+    pub fn validator<V: PayloadValidator + 'static>(mut self, validator: V) -> Self {
+        self.validators.register(validator);
+        self
+    }
+
+    // build loads the chain from storage.
+    pub async fn build(self) -> Result<Node, storage::Error> {
+        let storage = self
+            .storage
+            .unwrap_or_else(|| Arc::new(MemoryStorage::new()));
+        let mempool = Mempool::with_validators(self.validators.clone());
+        let app = App::load(storage, self.validators, self.genesis).await?;
+        Ok(Node {
+            app,
+            mempool,
+            wallet: Wallet::new(p2p::KEYS.clone()),
+        })
+    }
+}
+
+// Node is the chain engine built by NodeBuilder. The networked node wraps one in a swarm;
+// embedded applications drive it directly through submit and mine.
+pub struct Node {
+    pub app: App,
+    pub mempool: Mempool,
+    // wallet picks the nonces of the transactions this node submits.
+    pub wallet: Wallet,
+}
+
+impl Node {
+    // submit adds the payload to the mempool as the next transaction of this node, returning
+    // the transaction id.
+    pub async fn submit(&mut self, fee: u64, payload: Bytes) -> Result<String, String> {
+        let tx = self.wallet.build(&self.app, &self.mempool, fee, payload);
+        if !self
+            .mempool
+            .insert(tx.clone(), Source::Local, self.app.nonces())
+        {
+            return Err("transaction was rejected by the mempool".to_string());
+        }
+        Ok(tx.id)
+    }
+
+    // submit_batch adds the payloads to the mempool as the next transactions of this node,
+    // all of them or none. It returns the id of every transaction along with why it was
+    // refused, if it was.
+    pub async fn submit_batch(
+        &mut self,
+        fee: u64,
+        payloads: Vec<Bytes>,
+    ) -> Result<Vec<(String, Result<(), String>)>, storage::Error> {
+        let items = payloads.into_iter().map(|payload| (fee, payload)).collect();
+        let txs = self.wallet.build_batch(&self.app, &self.mempool, items);
+        let ids: Vec<String> = txs.iter().map(|tx| tx.id.clone()).collect();
+        let batch = txs.into_iter().map(|tx| (tx.clone(), tx.id)).collect();
+        let results = self.mempool.insert_batch(batch, self.app.nonces());
+        Ok(ids.into_iter().zip(results).collect())
+    }
+
+    // submit_and_wait submits the payload and mines until the transaction has the given
+    // number of confirmations, or the timeout passes. A transaction that drops out of the
+    // chain and the mempool, e.g. in a reorg, is submitted again; one replaced by another
+    // transaction of ours is given up on.
+    pub async fn submit_and_wait(
+        &mut self,
+        fee: u64,
+        payload: Bytes,
+        confirmations: usize,
+        timeout: Duration,
+    ) -> Result<Confirmed, Error> {
+        let deadline = Instant::now() + timeout;
+        if self.app.height() == 0 {
+            self.app.genesis().await?;
+        }
+        let mut id = self.submit_tracked(fee, payload.clone()).await?;
+        loop {
+            match self
+                .mempool
+                .receipt(&id)
+                .map(|receipt| receipt.status.clone())
+            {
+                Some(Status::Included { height, block_hash }) => {
+                    let block = self.app.get_by_height(height).await?;
+                    if block.is_some_and(|block| block.hash == block_hash) {
+                        let confirmed = self.app.height() - height;
+                        if confirmed >= confirmations.max(1) {
+                            return Ok(Confirmed {
+                                id,
+                                height,
+                                block_hash,
+                                confirmations: confirmed,
+                            });
+                        }
+                    } else {
+                        log::info!("Transaction {} left the chain, submitting it again", id);
+                        id = self.submit_tracked(fee, payload.clone()).await?;
+                    }
+                }
+                Some(Status::Replaced { by }) => return Err(Error::Replaced { id, by }),
+                Some(Status::Pending) if self.mempool.contains(&id) => {}
+                _ => {
+                    log::info!("Transaction {} was dropped, submitting it again", id);
+                    id = self.submit_tracked(fee, payload.clone()).await?;
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout { id });
+            }
+            // Confirmations come from the blocks on top, so keep mining even once the
+            // mempool is empty.
+            self.seal().await?;
+        }
+    }
+
+    // submit_tracked submits the payload like submit, keeping a receipt of it under its id.
+    async fn submit_tracked(&mut self, fee: u64, payload: Bytes) -> Result<String, Error> {
+        let tx = self.wallet.build(&self.app, &self.mempool, fee, payload);
+        if !self
+            .mempool
+            .insert_correlated(tx.clone(), tx.id.clone(), self.app.nonces())
+        {
+            return Err(Error::Rejected { id: tx.id });
+        }
+        Ok(tx.id)
+    }
+
+    // mine seals the pending transactions into a block and appends it, creating the genesis
+    // block first if the chain is empty. It returns None if there was nothing to mine.
+    pub async fn mine(&mut self) -> Result<Option<Block>, storage::Error> {
+        if self.app.height() == 0 {
+            self.app.genesis().await?;
+        }
+        if self.mempool.is_empty() {
+            return Ok(None);
+        }
+        self.seal().await
+    }
+
+    // seal mines a block with whatever is pending, which may be nothing but the coinbase.
+    async fn seal(&mut self) -> Result<Option<Block>, storage::Error> {
+        let template = miner::Template::new(&self.app, &self.mempool);
+        let block = task::spawn_blocking(move || template.mine()).await;
+        if !self.app.try_add_block(block.clone()).await? {
+            return Ok(None);
+        }
+        self.mempool.remove_included(&block);
+        Ok(Some(block))
+    }
+}
+
+// Confirmed is a transaction that reached the requested number of confirmations.
+#[derive(Debug, Clone)]
+pub struct Confirmed {
+    pub id: String,
+    pub height: usize,
+    pub block_hash: String,
+    pub confirmations: usize,
+}
+
+// Error is returned when a transaction submitted with submit_and_wait doesn't get confirmed.
+#[derive(Debug)]
+pub enum Error {
+    // Rejected means the mempool turned the transaction away; see the rejects for why.
+    Rejected { id: String },
+    Replaced { id: String, by: String },
+    Timeout { id: String },
+    Storage(storage::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Rejected { id } => write!(f, "transaction {} was rejected by the mempool", id),
+            Error::Replaced { id, by } => write!(f, "transaction {} was replaced by {}", id, by),
+            Error::Timeout { id } => write!(f, "transaction {} was not confirmed in time", id),
+            Error::Storage(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        Error::Storage(e)
+    }
+}
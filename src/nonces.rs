@@ -0,0 +1,114 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::app::{Block, Transaction};
+
+// Nonces are the nonces each sender has used on the chain. A sender's transaction can only be
+// mined once per nonce, so neither a mined transaction gossiped again nor one that was
+// replaced in the mempool can make it into a block next to the one that took its nonce.
+#[derive(Debug, Clone, Default)]
+pub struct Nonces {
+    used: HashMap<String, BTreeSet<u64>>,
+}
+
+impl Nonces {
+    // apply records the nonces used by the transactions of the block.
+    pub fn apply(&mut self, block: &Block) {
+        for tx in sequenced(block) {
+            self.used
+                .entry(tx.sender.clone())
+                .or_default()
+                .insert(tx.nonce);
+        }
+    }
+
+    // forget undoes apply, for a block of ours that a reorg rewinds.
+    pub fn forget(&mut self, block: &Block) {
+        for tx in sequenced(block) {
+            if let Some(used) = self.used.get_mut(&tx.sender) {
+                used.remove(&tx.nonce);
+                if used.is_empty() {
+                    self.used.remove(&tx.sender);
+                }
+            }
+        }
+    }
+
+    // highest returns the highest nonce the sender used.
+    pub fn highest(&self, sender: &str) -> Option<u64> {
+        self.used.get(sender)?.last().copied()
+    }
+
+    pub fn is_used(&self, tx: &Transaction) -> bool {
+        self.used
+            .get(&tx.sender)
+            .is_some_and(|used| used.contains(&tx.nonce))
+    }
+
+    // check returns why the block can't follow the blocks these nonces were used in, if one
+    // of its transactions takes a nonce of its sender that is already used.
+    pub fn check(&self, block: &Block) -> Result<(), String> {
+        let mut taken = HashSet::new();
+        for tx in sequenced(block) {
+            if self.is_used(tx) || !taken.insert((&tx.sender, tx.nonce)) {
+                return Err(format!(
+                    "transaction {} reuses nonce {} of {}",
+                    tx.id, tx.nonce, tx.sender
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// sequenced returns the transactions of the block whose nonces follow their sender's.
+// Coinbase nonces are block heights, not a sequence of the miner's.
+fn sequenced(block: &Block) -> impl Iterator<Item = &Transaction> {
+    block
+        .transactions
+        .iter()
+        .filter(|tx| tx.coinbase().is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use libp2p::identity::Keypair;
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        Block {
+            height: 1,
+            hash: String::new(),
+            previous_hash: String::new(),
+            timestamp: 0,
+            transactions,
+            nonce: 0,
+            version: 0,
+        }
+    }
+
+    fn tx(keys: &Keypair, nonce: u64, fee: u64) -> Transaction {
+        Transaction::new(keys, nonce, fee, Bytes::from_static(b"payload"))
+    }
+
+    #[test]
+    fn nonces_are_used_once() {
+        let keys = Keypair::generate_ed25519();
+        let mut nonces = Nonces::default();
+        let mined = block(vec![tx(&keys, 0, 10), tx(&keys, 2, 10)]);
+        assert_eq!(nonces.check(&mined), Ok(()));
+        nonces.apply(&mined);
+        assert_eq!(nonces.highest(&tx(&keys, 0, 0).sender), Some(2));
+
+        // A replacement paying a higher fee takes the same nonce, so it can't be mined too.
+        assert!(nonces.check(&block(vec![tx(&keys, 0, 20)])).is_err());
+        assert_eq!(nonces.check(&block(vec![tx(&keys, 1, 10)])), Ok(()));
+        assert!(nonces
+            .check(&block(vec![tx(&keys, 3, 10), tx(&keys, 3, 20)]))
+            .is_err());
+
+        nonces.forget(&mined);
+        assert_eq!(nonces.highest(&tx(&keys, 0, 0).sender), None);
+        assert_eq!(nonces.check(&mined), Ok(()));
+    }
+}
@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::app::{self, App, Header, Transaction};
+use crate::history::HISTORY_BATCH;
+use crate::merkle::{self, Step};
+use crate::payload::Payload;
+use crate::storage;
+
+// Proof shows that a document digest was anchored in a block. It carries the block header
+// rather than the block, and a Merkle path from the anchoring transaction to the header's
+// transactions root, so it can be checked without access to the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proof {
+    pub header: Header,
+    pub transaction: Transaction,
+    pub path: Vec<Step>,
+}
+
+impl Proof {
+    // verify checks that the proof anchors `digest` in a block with valid proof of work.
+    pub fn verify(&self, digest: &str) -> Result<(), String> {
+        match Payload::decode(&self.transaction.payload) {
+            Some(Payload::Notary(notarization)) if notarization.digest == digest => {}
+            _ => return Err("transaction does not anchor the digest".to_string()),
+        }
+        if !self.transaction.is_valid() {
+            return Err("transaction id does not match its contents".to_string());
+        }
+        if !self.header.is_valid() {
+            return Err("block header is invalid".to_string());
+        }
+        let mut id = [0; 32];
+        let mut root = [0; 32];
+        hex::decode_to_slice(&self.transaction.id, &mut id).map_err(|e| e.to_string())?;
+        hex::decode_to_slice(&self.header.transactions_root, &mut root)
+            .map_err(|e| e.to_string())?;
+        if !merkle::verify(&id, &self.path, &root) {
+            return Err("transaction is not in the block".to_string());
+        }
+        Ok(())
+    }
+}
+
+// digest_file returns the hex encoded SHA-256 digest of the file's contents.
+pub fn digest_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// prove walks the first `until` blocks of the chain for the first transaction anchoring
+// `digest` and returns a proof of its inclusion.
+pub async fn prove(app: &App, digest: &str, until: usize) -> Result<Option<Proof>, storage::Error> {
+    let mut start = 0;
+    while start < until {
+        for block in app.range(start, (start + HISTORY_BATCH).min(until)).await? {
+            let anchored = block.transactions.iter().position(|tx| {
+                matches!(Payload::decode(&tx.payload),
+                    Some(Payload::Notary(notarization)) if notarization.digest == digest)
+            });
+            if let Some(index) = anchored {
+                let ids = app::transaction_ids(&block.transactions);
+                let path = merkle::proof(&ids, index).expect("index is in the block");
+                return Ok(Some(Proof {
+                    header: Header::of(&block),
+                    transaction: block.transactions[index].clone(),
+                    path,
+                }));
+            }
+        }
+        start += HISTORY_BATCH;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::NodeBuilder;
+    use crate::payload::{ChatMessage, Notarization};
+
+    #[async_std::test]
+    async fn notarized_file_can_be_proven() {
+        let path = std::env::temp_dir().join(format!("mchain-notary-{}", std::process::id()));
+        std::fs::write(&path, b"the document").unwrap();
+        let digest = digest_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(digest, hex::encode(Sha256::digest(b"the document")));
+
+        let mut node = NodeBuilder::new().build().await.unwrap();
+        let chat = |text: &str| {
+            Payload::Chat(ChatMessage {
+                topic: "chat".to_string(),
+                text: text.to_string(),
+            })
+        };
+        let notarization = Payload::Notary(Notarization {
+            digest: digest.clone(),
+            metadata: Some("contract".to_string()),
+        });
+        for payload in [chat("before"), notarization, chat("after")] {
+            node.submit(1, payload.encode()).await.unwrap();
+        }
+        node.mine().await.unwrap().unwrap();
+
+        let height = node.app.height();
+        let proof = prove(&node.app, &digest, height).await.unwrap().unwrap();
+        assert_eq!(proof.verify(&digest), Ok(()));
+        assert!(proof.verify(&"0".repeat(64)).is_err());
+
+        let mut tampered = proof.clone();
+        tampered.path.pop();
+        assert!(tampered.verify(&digest).is_err());
+
+        // The proof is checked without the chain, so it has to survive being passed around.
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: Proof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.verify(&digest), Ok(()));
+
+        assert!(prove(&node.app, &digest, 1).await.unwrap().is_none());
+        assert!(prove(&node.app, &"0".repeat(64), height)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
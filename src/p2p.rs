@@ -1,34 +1,255 @@
-use libp2p::floodsub;
+use libp2p::floodsub::{self, FloodsubMessage};
+use libp2p::identify;
+use libp2p::identity::{ed25519, Keypair, PublicKey};
+use libp2p::ping;
+use libp2p::request_response::{RequestResponse, RequestResponseEvent};
 use libp2p::NetworkBehaviour;
+use libp2p::PeerId;
 use libp2p::Swarm;
-use log;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use sha2::{Digest, Sha256};
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
 
-use crate::app;
+use crate::admin::{AdminCodec, AdminRequest, AdminResponse};
+use crate::files::{ChunkCodec, ChunkRequest, ChunkResponse};
+use crate::gossip::{Gossip, GossipEvent};
+use crate::telemetry::SpanContext;
+use crate::{app, metrics, sync, wire};
 
-// KEYS is the private key of the local node.
-pub static KEYS: Lazy<libp2p::identity::Keypair> =
-    Lazy::new(|| libp2p::identity::Keypair::generate_ed25519());
+// IDENTITY, when set before KEYS is first used, holds the node's keys, e.g. ones derived from
+// a seed or loaded from the data directory.
+static IDENTITY: OnceCell<Keypair> = OnceCell::new();
+
+// KEYS is the private key of the local node, generated at random unless set beforehand.
+pub static KEYS: Lazy<Keypair> = Lazy::new(|| {
+    IDENTITY
+        .get()
+        .cloned()
+        .unwrap_or_else(Keypair::generate_ed25519)
+});
 
 // PEER_ID is used to identify a client on the network.
 pub static PEER_ID: Lazy<libp2p::PeerId> = Lazy::new(|| libp2p::PeerId::from(KEYS.public()));
 
-// We initialize two topics (i.e. "channels") that we will use to broadcast messages to all
+// set_keys makes `keys` the node's keys. It has to be called before the keys are first used.
+fn set_keys(keys: Keypair) -> Result<(), String> {
+    if Lazy::get(&KEYS).is_some() {
+        return Err("keys are already in use".to_string());
+    }
+    IDENTITY
+        .set(keys)
+        .map_err(|_| "keys are already set".to_string())
+}
+
+// seed_keys derives the node's keys from `seed`, so the same seed gives the same peer id
+// and wallet address on every run. It is meant for tests and tutorials: anyone who knows the
+// seed holds the keys. It has to be called before the keys are first used.
+pub fn seed_keys(seed: &str) -> Result<(), String> {
+    let secret = ed25519::SecretKey::from_bytes(Sha256::digest(seed.as_bytes()))
+        .expect("any 32 bytes are a key");
+    set_keys(Keypair::Ed25519(secret.into()))
+}
+
+// load_keys makes the keys kept at `path` the node's keys, so it keeps its peer id and
+// wallet address across restarts. If there are none yet, new keys are generated and saved
+// there. It has to be called before the keys are first used.
+pub fn load_keys(path: &Path) -> Result<(), Box<dyn Error>> {
+    let keys = match fs::read(path) {
+        Ok(encoded) => Keypair::from_protobuf_encoding(&encoded)
+            .map_err(|e| format!("invalid keys in {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keys = Keypair::generate_ed25519();
+            save_keys(path, &keys)?;
+            keys
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(set_keys(keys)?)
+}
+
+// rotate_keys replaces the keys kept at `path` with new ones, returning the peer id they
+// give. KEYS is set once per run, so the node only takes them up once it is restarted.
+pub fn rotate_keys(path: &Path) -> Result<PeerId, Box<dyn Error>> {
+    let keys = Keypair::generate_ed25519();
+    save_keys(path, &keys)?;
+    Ok(PeerId::from(keys.public()))
+}
+
+// save_keys writes the keys to `path`, readable by the current user only. They are written
+// next to it first, so the keys there are never left half overwritten.
+fn save_keys(path: &Path, keys: &Keypair) -> Result<(), Box<dyn Error>> {
+    let temp = path.with_extension("new");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&temp)?
+        .write_all(&keys.to_protobuf_encoding()?)?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+// We initialize topics (i.e. "channels") that we will use to broadcast messages to all
 // connected peers. This methodology uses the floodsub protocol, which is a simple pub/sub
-// protocol that broadcasts messages to all connected peers.
+// protocol that broadcasts messages to all connected peers. Topic names are scoped to our
+// chain id so unrelated networks on the same LAN don't see each other's messages.
+
+// topic returns the topic `name` of our chain, i.e. "mchain/<chain_id>/<name>".
+fn topic(name: &str) -> floodsub::Topic {
+    floodsub::Topic::new(format!("mchain/{}/{}", app::chain_id(), name))
+}
 
 // CHAIN_TOP can be subscribed to in order to send our local blockchain to other nodes.
-pub static CHAIN_TOP: Lazy<floodsub::Topic> = Lazy::new(|| floodsub::Topic::new("chains"));
+pub static CHAIN_TOP: Lazy<floodsub::Topic> = Lazy::new(|| topic("chains"));
 
 // BLOCK_TOP is usd to broadcast and receive new blocks.
-pub static BLOCK_TOP: Lazy<floodsub::Topic> = Lazy::new(|| floodsub::Topic::new("blocks"));
+pub static BLOCK_TOP: Lazy<floodsub::Topic> = Lazy::new(|| topic("blocks"));
+
+// TX_TOP is used to broadcast submitted transactions to every mempool.
+pub static TX_TOP: Lazy<floodsub::Topic> = Lazy::new(|| topic("transactions"));
+
+// SYNC_TOP is used to request and serve ranges of blocks while catching up with the network.
+pub static SYNC_TOP: Lazy<floodsub::Topic> = Lazy::new(|| topic("sync"));
+
+// STATUS_TOP carries the periodic status heartbeats of every node.
+pub static STATUS_TOP: Lazy<floodsub::Topic> = Lazy::new(|| topic("status"));
+
+// PROTOCOL_PREFIX starts the protocol version we announce over identify. The rest of the
+// version is the hash of our genesis block, which peers compare against their own, followed by
+// the hash of the genesis parameters it was mined from.
+const PROTOCOL_PREFIX: &str = "mchain/";
 
+// protocol_version is announced to every peer we connect to.
+pub fn protocol_version() -> String {
+    let genesis = app::genesis_hash();
+    format!(
+        "{}{}/{}",
+        PROTOCOL_PREFIX,
+        genesis,
+        app::genesis_params_hash()
+    )
+}
+
+// genesis_of returns the genesis hash announced in a peer's protocol version, or None if the
+// peer is not an mchain node.
+pub fn genesis_of(protocol_version: &str) -> Option<&str> {
+    let version = protocol_version.strip_prefix(PROTOCOL_PREFIX)?;
+    version.split('/').next()
+}
+
+// ARCHIVAL_SUFFIX ends the agent version of archival nodes, which keep the history of the
+// chain's state at every height and so can answer queries other nodes have pruned.
+const ARCHIVAL_SUFFIX: &str = " (archival)";
+
+// agent_version is announced to every peer we connect to, along with the protocol version.
+pub fn agent_version(archival: bool) -> String {
+    let version = format!("mchain/v{}", env!("CARGO_PKG_VERSION"));
+    if archival {
+        version + ARCHIVAL_SUFFIX
+    } else {
+        version
+    }
+}
+
+// is_archival returns whether a peer's agent version announces an archival node.
+pub fn is_archival(agent_version: &str) -> bool {
+    agent_version.ends_with(ARCHIVAL_SUFFIX)
+}
+
+// ChainResponse carries the most recent blocks of `responder`'s chain to `receiver`. It is
+// signed with the responder's identity key, so a peer relaying or spoofing it can't pass off a
+// chain of its own as someone else's.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainResponse {
     pub blocks: Vec<app::Block>,
     pub receiver: String,
+    pub responder: String,
+    pub height: usize,
+    pub tip: Option<String>,
+    // public_key is the responder's key in its protobuf encoding, and signature covers
+    // everything else in the response; both are hex encoded.
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl ChainResponse {
+    // new signs our most recent blocks for the receiver.
+    pub fn new(blocks: Vec<app::Block>, receiver: String) -> Self {
+        let mut response = Self {
+            height: blocks.last().map_or(0, |block| block.height + 1),
+            tip: blocks.last().map(|block| block.hash.clone()),
+            blocks,
+            receiver,
+            responder: PEER_ID.to_string(),
+            public_key: hex::encode(KEYS.public().to_protobuf_encoding()),
+            signature: String::new(),
+        };
+        let signature = KEYS
+            .sign(&response.signed_bytes())
+            .expect("ed25519 keys can sign");
+        response.signature = hex::encode(signature);
+        response
+    }
+
+    // signed_bytes is what the signature covers. Block hashes commit to the rest of the
+    // blocks, which are checked against them when the chain is validated.
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        for field in [&self.receiver, &self.responder] {
+            hasher.update((field.len() as u32).to_be_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.update((self.height as u64).to_be_bytes());
+        for block in &self.blocks {
+            hasher.update(block.hash.as_bytes());
+        }
+        hasher.finalize().to_vec()
+    }
+
+    // verify checks that the response was signed by the peer it claims to come from, and
+    // that the tip it announces is that of the chain it carries. The blocks are a window of
+    // consecutive heights ending at the tip, which needn't start at the genesis block.
+    pub fn verify(&self) -> Result<PeerId, String> {
+        let responder: PeerId = self
+            .responder
+            .parse()
+            .map_err(|_| format!("invalid responder {:?}", self.responder))?;
+        let public_key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| PublicKey::from_protobuf_encoding(&key).ok())
+            .ok_or("invalid public key")?;
+        if PeerId::from(&public_key) != responder {
+            return Err(format!("public key does not belong to {}", responder));
+        }
+        let signature = hex::decode(&self.signature).map_err(|_| "invalid signature")?;
+        if !public_key.verify(&self.signed_bytes(), &signature) {
+            return Err(format!("signature of {} does not match", responder));
+        }
+        let first = self.blocks.first().map_or(0, |block| block.height);
+        let contiguous = self
+            .blocks
+            .iter()
+            .enumerate()
+            .all(|(i, block)| block.height == first + i);
+        let tip = self.blocks.last().map(|block| &block.hash);
+        if !contiguous || self.height != first + self.blocks.len() || self.tip.as_ref() != tip {
+            return Err(format!(
+                "tip announced by {} does not match its chain",
+                responder
+            ));
+        }
+        Ok(responder)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,11 +262,89 @@ pub enum Event {
     Init,
 }
 
+// Status is the heartbeat a node publishes on STATUS_TOP, so its peers know how far along
+// it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub height: usize,
+    pub tip: Option<String>,
+    // version is the release of mchain the node runs.
+    pub version: String,
+    pub mempool_size: usize,
+    // timestamp is the sender's clock when it published the status, as a unix timestamp.
+    pub timestamp: i64,
+    // timestamp_ms is the same in milliseconds, which older nodes don't send.
+    #[serde(default)]
+    pub timestamp_ms: Option<i64>,
+}
+
+impl Status {
+    pub fn new(height: usize, tip: Option<String>, mempool_size: usize) -> Self {
+        let now = chrono::Utc::now();
+        Self {
+            height,
+            tip,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            mempool_size,
+            timestamp: now.timestamp(),
+            timestamp_ms: Some(now.timestamp_millis()),
+        }
+    }
+}
+
+// BlockAnnouncement is a new block broadcast on BLOCK_TOP, stamped with when it was sent so
+// receivers can tell how long it took to reach them. The fields of the block are inlined, so
+// nodes expecting a bare block still read it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockAnnouncement {
+    #[serde(flatten)]
+    pub block: app::Block,
+    // sent_at_ms is the sender's clock when it published the block, in unix milliseconds.
+    // Older nodes don't stamp their blocks.
+    #[serde(default)]
+    pub sent_at_ms: Option<i64>,
+}
+
+impl BlockAnnouncement {
+    pub fn new(block: app::Block) -> Self {
+        Self {
+            block,
+            sent_at_ms: Some(chrono::Utc::now().timestamp_millis()),
+        }
+    }
+}
+
+// SyncMessage is exchanged on SYNC_TOP. Requests are addressed to a single peer through
+// `receiver`, and every other peer ignores them.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncMessage {
+    // RangeRequest asks `receiver` for at most `limit` blocks starting at index `start`.
+    // traceparent is the span of the request, which the receiver continues when serving it.
+    RangeRequest {
+        receiver: String,
+        start: usize,
+        limit: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        traceparent: Option<String>,
+    },
+    // RangeResponse carries the requested blocks along with the responder's chain length.
+    RangeResponse {
+        receiver: String,
+        start: usize,
+        height: usize,
+        blocks: Vec<app::Block>,
+    },
+}
+
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "AppBehaviorEvent")]
 pub struct AppBehavior {
     pub mdns: libp2p::mdns::Mdns,
-    pub floodsub: floodsub::Floodsub,
+    pub floodsub: Gossip,
+    pub ping: ping::Behaviour,
+    pub identify: identify::Identify,
+    pub chunks: RequestResponse<ChunkCodec>,
+    pub admin: RequestResponse<AdminCodec>,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -53,6 +352,15 @@ pub struct AppBehavior {
 pub enum AppBehaviorEvent {
     Mdns(libp2p::mdns::MdnsEvent),
     Floodsub(floodsub::FloodsubEvent),
+    // Message is a pubsub message, along with the connected peer that relayed it to us.
+    Message {
+        relayer: PeerId,
+        message: FloodsubMessage,
+    },
+    Ping(ping::Event),
+    Identify(Box<identify::IdentifyEvent>),
+    Chunks(RequestResponseEvent<ChunkRequest, ChunkResponse>),
+    Admin(RequestResponseEvent<AdminRequest, AdminResponse>),
 }
 
 impl From<libp2p::mdns::MdnsEvent> for AppBehaviorEvent {
@@ -61,9 +369,159 @@ impl From<libp2p::mdns::MdnsEvent> for AppBehaviorEvent {
     }
 }
 
-impl From<floodsub::FloodsubEvent> for AppBehaviorEvent {
-    fn from(event: floodsub::FloodsubEvent) -> Self {
-        Self::Floodsub(event)
+impl From<GossipEvent> for AppBehaviorEvent {
+    fn from(event: GossipEvent) -> Self {
+        match event {
+            // Every inbound message passes through here, which makes it the one place to dump
+            // them for --debug-wire.
+            GossipEvent::Message { relayer, message } => {
+                wire::record_inbound(&message);
+                Self::Message { relayer, message }
+            }
+            GossipEvent::Other(event) => Self::Floodsub(event),
+        }
+    }
+}
+
+impl From<ping::Event> for AppBehaviorEvent {
+    fn from(event: ping::Event) -> Self {
+        Self::Ping(event)
+    }
+}
+
+impl From<RequestResponseEvent<ChunkRequest, ChunkResponse>> for AppBehaviorEvent {
+    fn from(event: RequestResponseEvent<ChunkRequest, ChunkResponse>) -> Self {
+        Self::Chunks(event)
+    }
+}
+
+impl From<RequestResponseEvent<AdminRequest, AdminResponse>> for AppBehaviorEvent {
+    fn from(event: RequestResponseEvent<AdminRequest, AdminResponse>) -> Self {
+        Self::Admin(event)
+    }
+}
+
+impl From<identify::IdentifyEvent> for AppBehaviorEvent {
+    fn from(event: identify::IdentifyEvent) -> Self {
+        Self::Identify(Box::new(event))
+    }
+}
+
+// Message is a kind of pubsub message, published as JSON on its own topic. Several kinds can
+// share a topic.
+pub trait Message: DeserializeOwned + 'static {
+    fn topic() -> &'static floodsub::Topic;
+}
+
+impl Message for BlockAnnouncement {
+    fn topic() -> &'static floodsub::Topic {
+        &BLOCK_TOP
+    }
+}
+
+impl Message for app::Transaction {
+    fn topic() -> &'static floodsub::Topic {
+        &TX_TOP
+    }
+}
+
+impl Message for SyncMessage {
+    fn topic() -> &'static floodsub::Topic {
+        &SYNC_TOP
+    }
+}
+
+impl Message for ChainResponse {
+    fn topic() -> &'static floodsub::Topic {
+        &CHAIN_TOP
+    }
+}
+
+impl Message for LocalChainRequest {
+    fn topic() -> &'static floodsub::Topic {
+        &CHAIN_TOP
+    }
+}
+
+impl Message for Status {
+    fn topic() -> &'static floodsub::Topic {
+        &STATUS_TOP
+    }
+}
+
+// Handled is the outcome of a message handler, which may have to wait on storage. An error
+// stops the node, so handlers only return the errors the event loop would not survive.
+pub type Handled<'a> = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>> + 'a>>;
+
+// Handler handles a message from a peer, given the node state C it works on.
+pub type Handler<C, M> = for<'a> fn(&'a mut C, PeerId, M) -> Handled<'a>;
+
+// Decoder decodes the data of a message and runs the handler of its kind, or returns None if
+// the data is not a message of that kind.
+type Decoder<C> = Box<dyn for<'a> Fn(&'a mut C, PeerId, &[u8]) -> Option<Handled<'a>>>;
+
+struct Route<C> {
+    topic: &'static floodsub::Topic,
+    decode: Decoder<C>,
+}
+
+// Router hands every pubsub message to the handler registered for its kind, so new kinds of
+// messages don't need changes to the event loop.
+pub struct Router<C> {
+    routes: Vec<Route<C>>,
+}
+
+impl<C> Default for Router<C> {
+    fn default() -> Self {
+        Self { routes: vec![] }
+    }
+}
+
+impl<C: 'static> Router<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // on registers the handler of messages of kind M. Kinds sharing a topic are tried in the
+    // order they were registered.
+    pub fn on<M: Message>(&mut self, handler: Handler<C, M>) -> &mut Self {
+        self.routes.push(Route {
+            topic: M::topic(),
+            decode: Box::new(move |ctx, source, data| {
+                let message = serde_json::from_slice::<M>(data).ok()?;
+                Some(handler(ctx, source, message))
+            }),
+        });
+        self
+    }
+
+    // topics returns every topic a handler is registered for, to subscribe to.
+    pub fn topics(&self) -> Vec<floodsub::Topic> {
+        let mut topics: Vec<floodsub::Topic> = vec![];
+        for route in &self.routes {
+            if !topics.contains(route.topic) {
+                topics.push(route.topic.clone());
+            }
+        }
+        topics
+    }
+
+    // dispatch runs the handler of the message, returning false if no handler could decode it.
+    pub async fn dispatch(
+        &self,
+        ctx: &mut C,
+        message: &FloodsubMessage,
+    ) -> Result<bool, Box<dyn Error>> {
+        for route in &self.routes {
+            if !message.topics.contains(route.topic) {
+                continue;
+            }
+            if let Some(handled) = (route.decode)(ctx, message.source, &message.data) {
+                handled.await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 }
 
@@ -81,3 +539,196 @@ pub fn get_peers(swarm: &Swarm<AppBehavior>) -> Vec<String> {
 pub fn print_peers(swarm: &Swarm<AppBehavior>) {
     get_peers(swarm).iter().for_each(|p| log::info!("{}", p));
 }
+
+// publish serializes the message as JSON and broadcasts it on the given topic.
+pub fn publish<T: Serialize>(swarm: &mut Swarm<AppBehavior>, topic: &floodsub::Topic, message: &T) {
+    match serde_json::to_vec(message) {
+        Ok(data) => publish_raw(swarm, topic, data),
+        Err(e) => log::error!("could not serialize message: {}", e),
+    }
+}
+
+// publish_raw broadcasts already encoded data on the given topic. Floodsub drops messages
+// published while no peer is connected, so blocks, chains and transactions are queued
+// instead until a peer subscribes to their topic.
+pub fn publish_raw(swarm: &mut Swarm<AppBehavior>, topic: &floodsub::Topic, data: Vec<u8>) {
+    if swarm.connected_peers().next().is_none() && is_queued(topic) {
+        queue(topic, data);
+        return;
+    }
+    wire::record_outbound(topic, &data);
+    swarm.behaviour_mut().floodsub.publish(topic.clone(), data);
+}
+
+// MAX_QUEUED_PUBLISHES bounds the publishes held back while no peer is connected; the oldest
+// are dropped first.
+const MAX_QUEUED_PUBLISHES: usize = 1024;
+
+// Queued is a message waiting to be published.
+struct Queued {
+    topic: floodsub::Topic,
+    data: Vec<u8>,
+}
+
+// QUEUED holds the publishes made while no peer was connected, oldest first.
+static QUEUED: Lazy<Mutex<VecDeque<Queued>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// is_queued reports whether publishes on the topic are worth delivering late. Status
+// heartbeats and sync messages are stale by the time a peer connects.
+fn is_queued(topic: &floodsub::Topic) -> bool {
+    *topic == *BLOCK_TOP || *topic == *CHAIN_TOP || *topic == *TX_TOP
+}
+
+fn queue(topic: &floodsub::Topic, data: Vec<u8>) {
+    let mut queued = QUEUED.lock().expect("publish queue lock is not poisoned");
+    if queued.len() == MAX_QUEUED_PUBLISHES {
+        if let Some(dropped) = queued.pop_front() {
+            log::warn!(
+                "Publish queue is full, dropping a message on {}",
+                dropped.topic.id()
+            );
+            metrics::DROPPED_PUBLISHES
+                .with_label_values(&[topic_name(&dropped.topic)])
+                .inc();
+        }
+    }
+    log::debug!("No peers connected, queueing a message on {}", topic.id());
+    queued.push_back(Queued {
+        topic: topic.clone(),
+        data,
+    });
+    metrics::QUEUED_PUBLISHES.set(queued.len() as i64);
+}
+
+// flush publishes the messages queued on the topic, now that a peer subscribed to it.
+pub fn flush(swarm: &mut Swarm<AppBehavior>, topic: &floodsub::Topic) {
+    let ready: Vec<Vec<u8>> = {
+        let mut queued = QUEUED.lock().expect("publish queue lock is not poisoned");
+        let (ready, rest): (VecDeque<Queued>, VecDeque<Queued>) =
+            queued.drain(..).partition(|q| q.topic == *topic);
+        *queued = rest;
+        metrics::QUEUED_PUBLISHES.set(queued.len() as i64);
+        ready.into_iter().map(|q| q.data).collect()
+    };
+    if !ready.is_empty() {
+        log::info!(
+            "Publishing {} queued messages on {}",
+            ready.len(),
+            topic.id()
+        );
+    }
+    for data in ready {
+        publish_raw(swarm, topic, data);
+    }
+}
+
+// topic_name returns the last segment of the topic, e.g. "blocks".
+fn topic_name(topic: &floodsub::Topic) -> &str {
+    topic.id().rsplit('/').next().unwrap_or_default()
+}
+
+// message_kind names the type of a pubsub message, for diagnostics.
+pub fn message_kind(topic: &floodsub::Topic, data: &[u8]) -> &'static str {
+    if *topic == *BLOCK_TOP {
+        "Block"
+    } else if *topic == *SYNC_TOP {
+        match serde_json::from_slice::<SyncMessage>(data) {
+            Ok(SyncMessage::RangeRequest { .. }) => "RangeRequest",
+            Ok(SyncMessage::RangeResponse { .. }) => "RangeResponse",
+            Err(_) => "invalid",
+        }
+    } else if *topic == *CHAIN_TOP {
+        if serde_json::from_slice::<ChainResponse>(data).is_ok() {
+            "ChainResponse"
+        } else if serde_json::from_slice::<LocalChainRequest>(data).is_ok() {
+            "LocalChainRequest"
+        } else {
+            "invalid"
+        }
+    } else if *topic == *TX_TOP {
+        "Transaction"
+    } else if *topic == *STATUS_TOP {
+        "Status"
+    } else {
+        "unknown"
+    }
+}
+
+// request_range asks a single peer for the blocks starting at `start`, as part of the trace
+// of the given span if any.
+pub fn request_range(
+    swarm: &mut Swarm<AppBehavior>,
+    peer: &PeerId,
+    start: usize,
+    trace: Option<&SpanContext>,
+) {
+    let request = SyncMessage::RangeRequest {
+        receiver: peer.to_string(),
+        start,
+        limit: sync::RANGE_LIMIT,
+        traceparent: trace.map(SpanContext::traceparent),
+    };
+    publish(swarm, &SYNC_TOP, &request);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocks(heights: std::ops::Range<usize>) -> Vec<app::Block> {
+        heights
+            .map(|height| app::Block {
+                height,
+                hash: format!("{:064x}", height),
+                previous_hash: String::new(),
+                timestamp: 0,
+                transactions: vec![],
+                nonce: 0,
+                version: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chain_response_of_a_recent_window_verifies() {
+        let response = ChainResponse::new(blocks(1500..2524), "receiver".to_string());
+        assert_eq!(response.height, 2524);
+        assert_eq!(response.verify(), Ok(*PEER_ID));
+        assert!(ChainResponse::new(vec![], "receiver".to_string())
+            .verify()
+            .is_ok());
+    }
+
+    #[test]
+    fn chain_response_with_a_gap_is_refused() {
+        let mut chain = blocks(10..20);
+        chain.remove(5);
+        assert!(ChainResponse::new(chain, "receiver".to_string())
+            .verify()
+            .is_err());
+
+        let mut response = ChainResponse::new(blocks(10..20), "receiver".to_string());
+        response.height = 10;
+        response.signature = hex::encode(KEYS.sign(&response.signed_bytes()).unwrap());
+        assert!(response.verify().is_err());
+    }
+
+    #[test]
+    fn rotated_keys_replace_the_saved_ones() {
+        let dir = std::env::temp_dir().join(format!("mchain-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys");
+        let old = rotate_keys(&path).unwrap();
+        let new = rotate_keys(&path).unwrap();
+        let saved = Keypair::from_protobuf_encoding(&fs::read(&path).unwrap()).unwrap();
+        assert_ne!(old, new);
+        assert_eq!(PeerId::from(saved.public()), new);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
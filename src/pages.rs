@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Block, Transaction};
+use crate::snapshot::View;
+use crate::storage;
+
+// MAX_PAGE_SIZE bounds how many items a single page holds, whatever the caller asks for.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+// Page is one page of a listing. `next` is an opaque cursor to pass back for the following
+// page, or None once the listing reached the tip.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<String>,
+}
+
+// TransactionEntry is a transaction along with the block it was included in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionEntry {
+    pub height: usize,
+    pub block_hash: String,
+    pub transaction: Transaction,
+    // cid is the CID of the transaction's payload, set when the node has content addressing
+    // enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
+}
+
+// Cursor is where a listing resumes: the height of the next block and the index of the next
+// transaction in it. It is handed out hex encoded, so callers don't come to depend on its
+// layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cursor {
+    pub height: usize,
+    pub index: usize,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&(self.height as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.index as u64).to_be_bytes());
+        hex::encode(bytes)
+    }
+
+    pub fn decode(token: &str) -> Result<Self, String> {
+        let bytes: [u8; 16] = hex::decode(token)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("invalid cursor {:?}", token))?;
+        let (height, index) = bytes.split_at(8);
+        let read = |b: &[u8]| u64::from_be_bytes(b.try_into().expect("8 bytes")) as usize;
+        Ok(Self {
+            height: read(height),
+            index: read(index),
+        })
+    }
+}
+
+// blocks returns up to `limit` blocks starting at the cursor, in chain order.
+pub async fn blocks(
+    view: &View<'_>,
+    cursor: Cursor,
+    limit: usize,
+) -> Result<Page<Block>, storage::Error> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let end = cursor.height.saturating_add(limit);
+    let items = view.range(cursor.height, end).await?;
+    let next = (end < view.height()).then(|| {
+        Cursor {
+            height: end,
+            index: 0,
+        }
+        .encode()
+    });
+    Ok(Page { items, next })
+}
+
+// transactions returns up to `limit` mined transactions starting at the cursor, in chain
+// order.
+pub async fn transactions(
+    view: &View<'_>,
+    mut cursor: Cursor,
+    limit: usize,
+) -> Result<Page<TransactionEntry>, storage::Error> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let mut items = vec![];
+    while items.len() < limit && cursor.height < view.height() {
+        // Blocks hold any number of transactions, so read a few at a time.
+        let blocks = view.range(cursor.height, cursor.height + 16).await?;
+        if blocks.is_empty() {
+            break;
+        }
+        for block in blocks {
+            for tx in block.transactions.iter().skip(cursor.index) {
+                if items.len() == limit {
+                    return Ok(Page {
+                        items,
+                        next: Some(cursor.encode()),
+                    });
+                }
+                items.push(TransactionEntry {
+                    height: block.height,
+                    block_hash: block.hash.clone(),
+                    transaction: tx.clone(),
+                    cid: None,
+                });
+                cursor.index += 1;
+            }
+            cursor = Cursor {
+                height: block.height + 1,
+                index: 0,
+            };
+        }
+    }
+    let next = (cursor.height < view.height()).then(|| cursor.encode());
+    Ok(Page { items, next })
+}
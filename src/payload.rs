@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+// Payload is the typed content of a transaction. It is encoded as JSON tagged with its
+// type, e.g. {"type":"chat","topic":"chat","text":"hi"}. Transactions whose payload does
+// not decode are still valid, they just carry opaque data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Payload {
+    Chat(ChatMessage),
+    Name(NameClaim),
+    Notary(Notarization),
+    File(FileManifest),
+    Coinbase(Coinbase),
+}
+
+// ChatMessage is a line of text posted to a chat topic.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub topic: String,
+    pub text: String,
+}
+
+// NameClaim registers, or renews, a human readable name for an address or peer id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameClaim {
+    pub name: String,
+    pub address: String,
+}
+
+// Notarization anchors the digest of a document, proving it existed when the block was mined.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notarization {
+    // digest is the hex encoded SHA-256 digest of the document.
+    pub digest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+// FileManifest anchors a file by the hashes of its chunks, in order. The chunks themselves
+// are exchanged between peers and checked against these hashes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileManifest {
+    pub name: String,
+    pub size: u64,
+    pub chunks: Vec<String>,
+}
+
+// Coinbase pays the miner of a block. It is only valid as the first transaction of a block,
+// and may claim at most the block reward plus the fees of the other transactions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coinbase {
+    pub amount: u64,
+}
+
+impl Payload {
+    pub fn encode(&self) -> Bytes {
+        serde_json::to_vec(self)
+            .expect("payloads serialize to JSON")
+            .into()
+    }
+
+    // decode returns None for payloads that are not one of ours.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    // kind is the type tag of the payload.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::Chat(_) => "chat",
+            Payload::Name(_) => "name",
+            Payload::Notary(_) => "notary",
+            Payload::File(_) => "file",
+            Payload::Coinbase(_) => "coinbase",
+        }
+    }
+
+    // key is what the payload is about within its application: the topic of a chat message,
+    // a claimed name, a notarized digest or a file name.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Payload::Chat(message) => Some(&message.topic),
+            Payload::Name(claim) => Some(&claim.name),
+            Payload::Notary(notarization) => Some(&notarization.digest),
+            Payload::File(manifest) => Some(&manifest.name),
+            Payload::Coinbase(_) => None,
+        }
+    }
+}
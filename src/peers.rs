@@ -0,0 +1,457 @@
+use chrono::prelude::*;
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::p2p::Status;
+
+// RTT_WEIGHT is how much a new ping sample moves a peer's smoothed round-trip time.
+const RTT_WEIGHT: f64 = 0.25;
+
+// Score adjustments applied to peers based on how they behave during sync.
+pub const SCORE_USEFUL_RESPONSE: i64 = 1;
+pub const SCORE_PING_FAILURE: i64 = -5;
+pub const SCORE_TIMEOUT: i64 = -10;
+pub const SCORE_INVALID_BLOCKS: i64 = -25;
+
+// MIN_SYNC_SCORE is the score below which a peer is no longer asked for blocks.
+pub const MIN_SYNC_SCORE: i64 = -50;
+
+// MIN_CLOCK_PEERS is how many connected peers have to report their time before our clock is
+// judged against theirs.
+pub const MIN_CLOCK_PEERS: usize = 3;
+
+// PeerInfo is what we know about a single connected peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerInfo {
+    // rtt is the smoothed round-trip time measured by the ping protocol.
+    pub rtt: Option<Duration>,
+    // score goes up for useful responses and down for failures and bad data.
+    pub score: i64,
+    // height is the chain length last reported by the peer, if it has told us.
+    pub height: Option<usize>,
+    // address is the remote address of our connection to the peer.
+    pub address: Option<Multiaddr>,
+    // status is the last heartbeat the peer published.
+    pub status: Option<Status>,
+    // clock_offset is how many seconds our clock was ahead of the peer's when its last
+    // heartbeat arrived.
+    pub clock_offset: Option<i64>,
+    // clock_offset_ms is the same in milliseconds, if the peer's heartbeats are that precise.
+    pub clock_offset_ms: Option<i64>,
+    // archival is set if the peer announced over identify that it keeps the whole history.
+    pub archival: bool,
+    // window_start and window_messages count the gossip messages received from the peer in
+    // the current one second rate limiting window.
+    window_start: Option<Instant>,
+    window_messages: u32,
+}
+
+// Ban keeps a peer from connecting to us until it expires, or forever if `until` is None.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub peer_id: String,
+    // until is the unix timestamp at which the ban is lifted.
+    pub until: Option<i64>,
+    pub reason: String,
+}
+
+impl Ban {
+    fn is_active(&self) -> bool {
+        self.until
+            .is_none_or(|until| Utc::now().timestamp() < until)
+    }
+}
+
+// PeerRecord is what is persisted about a peer, so it survives restarts and is shared by the
+// nodes using the same database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub peer_id: String,
+    pub score: i64,
+    // address and last_seen are where and when (a unix timestamp) we last saw the peer.
+    pub address: Option<String>,
+    pub last_seen: Option<i64>,
+    pub ban: Option<Ban>,
+}
+
+// Reputation is what we remember about a peer while it is not connected.
+#[derive(Debug, Clone, Default)]
+struct Reputation {
+    score: i64,
+    address: Option<String>,
+    last_seen: Option<i64>,
+}
+
+// PeerManager keeps track of connected peers, ranks them as sync sources, and enforces the
+// ban list.
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    // peers holds the peers we have a connection to. They are added when a connection is
+    // established, and what peers report is only recorded while they are in here.
+    peers: HashMap<PeerId, PeerInfo>,
+    // reputations outlive connections, so a peer that misbehaved keeps its score when it
+    // reconnects.
+    reputations: HashMap<PeerId, Reputation>,
+    bans: HashMap<PeerId, Ban>,
+    // incompatible holds peers that announced a different genesis block. They belong to
+    // another network, so we don't connect to them again.
+    incompatible: HashSet<PeerId>,
+}
+
+impl PeerManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // restore takes in the persisted records: the bans they hold replace ours, and the
+    // reputations of peers that are not connected are updated. It returns the connected peers
+    // that turn out to be banned, which the caller should disconnect.
+    pub fn restore(&mut self, records: Vec<PeerRecord>) -> Vec<PeerId> {
+        self.bans.clear();
+        for record in records {
+            let Ok(peer) = record.peer_id.parse::<PeerId>() else {
+                continue;
+            };
+            if let Some(ban) = record.ban.filter(Ban::is_active) {
+                self.bans.insert(peer, ban);
+            }
+            if !self.peers.contains_key(&peer) {
+                let reputation = Reputation {
+                    score: record.score,
+                    address: record.address,
+                    last_seen: record.last_seen,
+                };
+                self.reputations.insert(peer, reputation);
+            }
+        }
+        let banned: Vec<PeerId> = self
+            .peers
+            .keys()
+            .filter(|peer| self.bans.contains_key(peer))
+            .copied()
+            .collect();
+        for peer in &banned {
+            self.remove_peer(peer);
+        }
+        banned
+    }
+
+    // records returns what should be persisted about every peer we know of.
+    pub fn records(&self) -> Vec<PeerRecord> {
+        let now = Utc::now().timestamp();
+        let mut records: HashMap<PeerId, PeerRecord> = self
+            .reputations
+            .iter()
+            .map(|(peer, reputation)| {
+                let record = PeerRecord {
+                    peer_id: peer.to_string(),
+                    score: reputation.score,
+                    address: reputation.address.clone(),
+                    last_seen: reputation.last_seen,
+                    ban: None,
+                };
+                (*peer, record)
+            })
+            .collect();
+        for (peer, info) in &self.peers {
+            let record = PeerRecord {
+                peer_id: peer.to_string(),
+                score: info.score,
+                address: info.address.as_ref().map(|addr| addr.to_string()),
+                last_seen: Some(now),
+                ban: None,
+            };
+            records.insert(*peer, record);
+        }
+        for (peer, ban) in self.bans.iter().filter(|(_, ban)| ban.is_active()) {
+            let record = records.entry(*peer).or_insert_with(|| PeerRecord {
+                peer_id: peer.to_string(),
+                score: 0,
+                address: None,
+                last_seen: None,
+                ban: None,
+            });
+            record.ban = Some(ban.clone());
+        }
+        records.into_values().collect()
+    }
+
+    // ban adds the peer to the ban list for `duration`, or permanently if it is None.
+    pub fn ban(&mut self, peer: PeerId, duration: Option<Duration>, reason: String) {
+        let until = duration.map(|d| Utc::now().timestamp() + d.as_secs() as i64);
+        log::warn!("Banning {} until {:?}: {}", peer, until, reason);
+        self.bans.insert(
+            peer,
+            Ban {
+                peer_id: peer.to_string(),
+                until,
+                reason,
+            },
+        );
+        self.remove_peer(&peer);
+    }
+
+    // unban lifts the ban on the peer, returning false if it was not banned.
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        let removed = self.bans.remove(peer).is_some();
+        if removed {
+            log::info!("Unbanned {}", peer);
+        }
+        removed
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.get(peer).is_some_and(Ban::is_active)
+    }
+
+    pub fn mark_incompatible(&mut self, peer: PeerId) {
+        self.incompatible.insert(peer);
+    }
+
+    pub fn is_incompatible(&self, peer: &PeerId) -> bool {
+        self.incompatible.contains(peer)
+    }
+
+    // bans returns the bans that are still in effect.
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans
+            .values()
+            .filter(|ban| ban.is_active())
+            .cloned()
+            .collect()
+    }
+
+    // add_peer starts tracking a connected peer, picking up the score it had before.
+    pub fn add_peer(&mut self, peer: PeerId, address: Multiaddr) {
+        let reputations = &mut self.reputations;
+        let info = self.peers.entry(peer).or_insert_with(|| PeerInfo {
+            score: reputations.remove(&peer).map_or(0, |r| r.score),
+            ..PeerInfo::default()
+        });
+        info.address = Some(address);
+    }
+
+    // remove_peer stops tracking a peer that disconnected, remembering its reputation.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        if let Some(info) = self.peers.remove(peer) {
+            let reputation = Reputation {
+                score: info.score,
+                address: info.address.map(|addr| addr.to_string()),
+                last_seen: Some(Utc::now().timestamp()),
+            };
+            self.reputations.insert(*peer, reputation);
+        }
+    }
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerInfo)> {
+        self.peers.iter()
+    }
+
+    // find_by_address returns the peer we are connected to at the given address.
+    pub fn find_by_address(&self, address: &Multiaddr) -> Option<PeerId> {
+        self.peers
+            .iter()
+            .find(|(_, info)| info.address.as_ref() == Some(address))
+            .map(|(peer, _)| *peer)
+    }
+
+    // record_rtt folds a new ping measurement into the peer's smoothed round-trip time.
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        let Some(info) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        info.rtt = Some(match info.rtt {
+            Some(avg) => avg.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT),
+            None => rtt,
+        });
+    }
+
+    // record_status keeps the peer's latest heartbeat, which also tells us its height.
+    // Heartbeats relayed from peers we aren't connected to are ignored.
+    pub fn record_status(&mut self, peer: PeerId, status: Status) {
+        let Some(info) = self.peers.get_mut(&peer) else {
+            return;
+        };
+        info.height = Some(status.height);
+        let now = Utc::now();
+        info.clock_offset = Some(now.timestamp() - status.timestamp);
+        info.clock_offset_ms = status.timestamp_ms.map(|ms| now.timestamp_millis() - ms);
+        info.status = Some(status);
+    }
+
+    // clock_skew estimates how far our clock is ahead of the network's, as the median of the
+    // offsets measured against each connected peer. It is None until at least
+    // MIN_CLOCK_PEERS of them reported their time, so a peer or two can't stop us mining.
+    pub fn clock_skew(&self) -> Option<i64> {
+        let mut offsets: Vec<i64> = self
+            .peers
+            .values()
+            .filter_map(|info| info.clock_offset)
+            .collect();
+        if offsets.len() < MIN_CLOCK_PEERS {
+            return None;
+        }
+        offsets.sort_unstable();
+        offsets.get(offsets.len() / 2).copied()
+    }
+
+    // propagation_delay estimates how long a message the peer sent at `sent_at_ms` took to
+    // reach us. The peer's clock offset is measured from its heartbeats, whose own transit
+    // time is taken to be half the round trip time, the way NTP does.
+    pub fn propagation_delay(&self, peer: &PeerId, sent_at_ms: i64) -> Option<Duration> {
+        let info = self.peers.get(peer)?;
+        let transit = info.rtt.map_or(0, |rtt| rtt.as_millis() as i64 / 2);
+        let skew = info.clock_offset_ms? - transit;
+        let delay = Utc::now().timestamp_millis() - sent_at_ms - skew;
+        Some(Duration::from_millis(delay.max(0) as u64))
+    }
+
+    // set_archival records whether the peer keeps the whole history of the chain's state.
+    pub fn set_archival(&mut self, peer: PeerId, archival: bool) {
+        if let Some(info) = self.peers.get_mut(&peer) {
+            info.archival = archival;
+        }
+    }
+
+    // archival_peers returns the peers that can answer queries deeper than our history.
+    pub fn archival_peers(&self) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, info)| info.archival)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    pub fn record_height(&mut self, peer: PeerId, height: usize) {
+        if let Some(info) = self.peers.get_mut(&peer) {
+            info.height = Some(height);
+        }
+    }
+
+    // adjust_score changes the peer's score, or its reputation if it disconnected since.
+    pub fn adjust_score(&mut self, peer: PeerId, delta: i64) {
+        let score = match self.peers.get_mut(&peer) {
+            Some(info) => &mut info.score,
+            None => &mut self.reputations.entry(peer).or_default().score,
+        };
+        *score = score.saturating_add(delta);
+    }
+
+    // allow_message counts a gossip message from the peer and returns false once it has sent
+    // more than `limit` messages within a second. A limit of 0 disables rate limiting.
+    pub fn allow_message(&mut self, peer: &PeerId, limit: u32) -> bool {
+        let info = match self.peers.get_mut(peer) {
+            Some(info) if limit > 0 => info,
+            _ => return true,
+        };
+        let now = Instant::now();
+        if info
+            .window_start
+            .is_none_or(|start| now.duration_since(start) >= Duration::from_secs(1))
+        {
+            info.window_start = Some(now);
+            info.window_messages = 0;
+        }
+        info.window_messages += 1;
+        info.window_messages <= limit
+    }
+
+    // sync_peers returns up to `n` peers that are ahead of `height`, ordered from the best
+    // sync source to the worst: highest score first, then lowest latency.
+    pub fn sync_peers(&self, height: usize, n: usize) -> Vec<PeerId> {
+        let mut candidates: Vec<(&PeerId, &PeerInfo)> = self
+            .peers
+            .iter()
+            .filter(|(_, info)| info.score >= MIN_SYNC_SCORE)
+            .filter(|(_, info)| info.height.is_some_and(|h| h > height))
+            .collect();
+
+        candidates.sort_by(|(_, a), (_, b)| {
+            b.score.cmp(&a.score).then_with(|| {
+                a.rtt
+                    .unwrap_or(Duration::MAX)
+                    .cmp(&b.rtt.unwrap_or(Duration::MAX))
+            })
+        });
+
+        candidates
+            .into_iter()
+            .take(n)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(height: usize) -> Status {
+        Status::new(height, None, 0)
+    }
+
+    fn address() -> Multiaddr {
+        "/ip4/127.0.0.1/tcp/4001".parse().expect("valid address")
+    }
+
+    #[test]
+    fn reports_of_unconnected_peers_are_ignored() {
+        let mut peers = PeerManager::new();
+        let peer = PeerId::random();
+        peers.record_status(peer, status(10));
+        peers.record_rtt(peer, Duration::from_millis(10));
+        peers.record_height(peer, 10);
+        peers.set_archival(peer, true);
+        assert!(peers.get(&peer).is_none());
+        assert!(peers.archival_peers().is_empty());
+        assert_eq!(peers.clock_skew(), None);
+    }
+
+    #[test]
+    fn reports_of_connected_peers_are_recorded() {
+        let mut peers = PeerManager::new();
+        let peer = PeerId::random();
+        peers.add_peer(peer, address());
+        peers.record_status(peer, status(10));
+        assert_eq!(peers.get(&peer).and_then(|info| info.height), Some(10));
+    }
+
+    #[test]
+    fn score_of_a_disconnected_peer_goes_to_its_reputation() {
+        let mut peers = PeerManager::new();
+        let peer = PeerId::random();
+        peers.adjust_score(peer, SCORE_INVALID_BLOCKS);
+        assert!(peers.get(&peer).is_none());
+
+        peers.add_peer(peer, address());
+        assert_eq!(
+            peers.get(&peer).map(|info| info.score),
+            Some(SCORE_INVALID_BLOCKS)
+        );
+    }
+
+    #[test]
+    fn clock_skew_needs_enough_connected_peers() {
+        let mut peers = PeerManager::new();
+        let mut status = status(0);
+        status.timestamp -= 100;
+        for _ in 0..MIN_CLOCK_PEERS {
+            assert_eq!(peers.clock_skew(), None);
+            let peer = PeerId::random();
+            peers.add_peer(peer, address());
+            peers.record_status(peer, status.clone());
+        }
+        let skew = peers.clock_skew().expect("enough peers reported");
+        assert!((100..=101).contains(&skew));
+
+        // Offsets of peers that disconnected no longer count.
+        let peer = *peers.iter().next().expect("a peer is connected").0;
+        peers.remove_peer(&peer);
+        assert_eq!(peers.clock_skew(), None);
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+use crate::app::Block;
+use crate::telemetry::{Kind, Span};
+
+// MAX_RECEIPTS bounds how many receipts are kept; the oldest are forgotten first.
+const MAX_RECEIPTS: usize = 10_000;
+
+// Status is where a submitted transaction is in the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Status {
+    Pending,
+    Included { height: usize, block_hash: String },
+    // Replaced means a transaction with the same sender and nonce took its place, either in
+    // the mempool or in a block.
+    Replaced { by: String },
+}
+
+// Receipt follows a transaction submitted through the API, under the correlation id the
+// caller submitted it with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    pub correlation_id: String,
+    pub transaction_id: String,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+// Receipts tracks the transactions that were submitted with a correlation id, and logs every
+// step they take with it. Each transaction is also traced by a span that lasts from the
+// mempool until it is included or replaced.
+#[derive(Debug, Default)]
+pub struct Receipts {
+    receipts: HashMap<String, Receipt>,
+    // correlations maps the id of each tracked transaction to its correlation id.
+    correlations: HashMap<String, String>,
+    // order holds every correlation id once, oldest first, for eviction.
+    order: VecDeque<String>,
+    // spans holds the span of each transaction that is still pending, by correlation id.
+    spans: HashMap<String, Span>,
+}
+
+impl Receipts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // track starts following the transaction, which just entered the mempool.
+    pub fn track(&mut self, correlation_id: String, transaction_id: String) {
+        log::info!(
+            "Transaction {} [correlation {}] entered the mempool",
+            transaction_id,
+            correlation_id
+        );
+        if self.receipts.contains_key(&correlation_id) {
+            // The correlation id was used before, so it now follows this transaction instead.
+            self.forget(&correlation_id, "correlation id reused");
+            self.order.retain(|id| *id != correlation_id);
+        } else if self.order.len() == MAX_RECEIPTS {
+            if let Some(evicted) = self.order.pop_front() {
+                self.forget(&evicted, "receipt evicted");
+            }
+        }
+        let mut span = Span::start("transaction.receipt", Kind::Internal);
+        span.set("transaction.id", transaction_id.clone());
+        span.set("transaction.correlation_id", correlation_id.clone());
+        self.spans.insert(correlation_id.clone(), span);
+        self.correlations
+            .insert(transaction_id.clone(), correlation_id.clone());
+        self.order.push_back(correlation_id.clone());
+        self.receipts.insert(
+            correlation_id.clone(),
+            Receipt {
+                correlation_id,
+                transaction_id,
+                status: Status::Pending,
+            },
+        );
+    }
+
+    // forget drops the receipt of the correlation id, ending its span if it is still pending.
+    fn forget(&mut self, correlation_id: &str, reason: &str) {
+        if let Some(receipt) = self.receipts.remove(correlation_id) {
+            self.correlations.remove(&receipt.transaction_id);
+        }
+        if let Some(mut span) = self.spans.remove(correlation_id) {
+            span.fail(reason);
+            span.end();
+        }
+    }
+
+    // included records that the transaction made it into the block.
+    pub fn included(&mut self, transaction_id: &str, block: &Block) {
+        let status = Status::Included {
+            height: block.height,
+            block_hash: block.hash.clone(),
+        };
+        if let Some(mut span) = self.update(transaction_id, status) {
+            span.set("block.height", block.height);
+            span.set("block.hash", block.hash.clone());
+            span.end();
+        }
+    }
+
+    // replaced records that another transaction took the place of this one.
+    pub fn replaced(&mut self, transaction_id: &str, by: &str) {
+        let status = Status::Replaced { by: by.to_string() };
+        if let Some(mut span) = self.update(transaction_id, status) {
+            span.set("transaction.replaced_by", by);
+            span.end();
+        }
+    }
+
+    // update sets the status of the transaction's receipt, returning its span if it was still
+    // pending.
+    fn update(&mut self, transaction_id: &str, status: Status) -> Option<Span> {
+        let correlation_id = self.correlations.get(transaction_id)?;
+        let receipt = self.receipts.get_mut(correlation_id)?;
+        log::info!(
+            "Transaction {} [correlation {}] is now {:?}",
+            transaction_id,
+            receipt.correlation_id,
+            status
+        );
+        receipt.status = status;
+        self.spans.remove(correlation_id)
+    }
+
+    pub fn get(&self, correlation_id: &str) -> Option<&Receipt> {
+        self.receipts.get(correlation_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_correlation_id_follows_the_new_transaction() {
+        let mut receipts = Receipts::new();
+        receipts.track("order".to_string(), "a".to_string());
+        receipts.track("order".to_string(), "b".to_string());
+        assert_eq!(receipts.order.len(), 1);
+        assert_eq!(receipts.spans.len(), 1);
+        assert_eq!(
+            receipts.get("order").map(|r| r.transaction_id.as_str()),
+            Some("b")
+        );
+
+        // The first transaction no longer updates the receipt.
+        receipts.replaced("a", "c");
+        assert_eq!(
+            receipts.get("order").map(|r| &r.status),
+            Some(&Status::Pending)
+        );
+    }
+
+    #[test]
+    fn oldest_receipt_is_evicted() {
+        let mut receipts = Receipts::new();
+        for i in 0..=MAX_RECEIPTS {
+            receipts.track(i.to_string(), format!("tx-{}", i));
+        }
+        assert!(receipts.get("0").is_none());
+        assert!(receipts.get("1").is_some());
+        assert_eq!(receipts.order.len(), MAX_RECEIPTS);
+        assert_eq!(receipts.correlations.len(), MAX_RECEIPTS);
+        assert_eq!(receipts.spans.len(), MAX_RECEIPTS);
+    }
+
+    #[test]
+    fn span_ends_with_the_receipt() {
+        let mut receipts = Receipts::new();
+        receipts.track("order".to_string(), "a".to_string());
+        receipts.replaced("a", "b");
+        assert!(receipts.spans.is_empty());
+        assert_eq!(
+            receipts.get("order").map(|r| &r.status),
+            Some(&Status::Replaced {
+                by: "b".to_string()
+            })
+        );
+    }
+}
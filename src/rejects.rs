@@ -0,0 +1,79 @@
+use async_std::channel::{self, Receiver, Sender};
+use chrono::prelude::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{Block, Transaction};
+
+// QUEUE_SIZE is how many rejects can wait to be persisted before new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+// MAX_LISTED is the most rejects a single query returns.
+pub const MAX_LISTED: usize = 100;
+
+// QUEUE carries rejects from where they happen to whoever persists them. When nobody does,
+// e.g. in an embedded node, it fills up and further rejects are only logged.
+static QUEUE: Lazy<(Sender<Reject>, Receiver<Reject>)> = Lazy::new(|| channel::bounded(QUEUE_SIZE));
+
+// Subject is what was rejected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "data", rename_all = "lowercase")]
+pub enum Subject {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+impl Subject {
+    // id returns the block hash or transaction id.
+    pub fn id(&self) -> &str {
+        match self {
+            Subject::Block(block) => &block.hash,
+            Subject::Transaction(tx) => &tx.id,
+        }
+    }
+}
+
+// Reject is a block or transaction the node turned away, along with why. They are kept so
+// users can find out why their data didn't make it on chain after the logs are gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reject {
+    pub id: String,
+    pub reason: String,
+    pub rejected_at: i64,
+    #[serde(flatten)]
+    pub subject: Subject,
+}
+
+// Query selects the rejects returned by /rejects, most recent first.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Query {
+    // kind is either "block" or "transaction".
+    pub kind: Option<String>,
+    pub id: Option<String>,
+    // sender keeps the transactions sent by this peer id.
+    pub sender: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: usize,
+}
+
+fn default_limit() -> usize {
+    MAX_LISTED
+}
+
+// record queues the subject to be persisted as rejected for the given reason.
+pub fn record(subject: Subject, reason: impl Into<String>) {
+    let reject = Reject {
+        id: subject.id().to_string(),
+        reason: reason.into(),
+        rejected_at: Utc::now().timestamp(),
+        subject,
+    };
+    if QUEUE.0.try_send(reject).is_err() {
+        log::debug!("Reject queue is full, not persisting reject");
+    }
+}
+
+// recorded returns the stream of rejects waiting to be persisted.
+pub fn recorded() -> Receiver<Reject> {
+    QUEUE.1.clone()
+}
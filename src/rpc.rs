@@ -0,0 +1,283 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::app::{self, App, Block, Transaction};
+use crate::fees;
+use crate::mempool::Mempool;
+use crate::miner;
+
+// The error codes of the JSON-RPC 2.0 specification.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+// ZERO_HASH stands in for hashes that aren't hex, such as the parent of the genesis block.
+const ZERO_HASH: &str = "0x0000000000000000000000000000000000000000000000000000000000000000";
+
+// Call is a single JSON-RPC request. Ethereum tooling sends these, so the shim answers the
+// read-only subset below with mchain blocks dressed up as Ethereum ones:
+//
+// - web3_clientVersion, net_version and eth_chainId identify the node and its chain.
+// - eth_blockNumber returns the height of the tip.
+// - eth_getBlockByNumber and eth_getBlockByHash return a block, with either the hashes or
+//   the contents of its transactions.
+// - mchain_estimateFee suggests a fee for next block inclusion, the same estimate as GET
+//   /v1/fees.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Call {
+    #[serde(default)]
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+    // id is echoed back; requests without one are notifications, which get no response.
+    #[serde(default)]
+    pub id: Option<Value>,
+}
+
+// Batch is the body of a JSON-RPC request, which may hold several calls.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Batch {
+    Many(Vec<Call>),
+    One(Call),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    pub id: Value,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Response {
+    pub fn ok(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    pub fn error(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            result: None,
+            error: Some(RpcError {
+                code,
+                message: message.into(),
+            }),
+            id,
+        }
+    }
+}
+
+// handle answers a call from the node's chain and mempool.
+pub async fn handle(app: &App, mempool: &Mempool, call: Call) -> Response {
+    let id = call.id.clone().unwrap_or(Value::Null);
+    if call.jsonrpc != "2.0" {
+        return Response::error(id, INVALID_REQUEST, "jsonrpc must be \"2.0\"");
+    }
+    match answer(app, mempool, &call).await {
+        Ok(result) => Response::ok(id, result),
+        Err((code, message)) => Response::error(id, code, message),
+    }
+}
+
+async fn answer(app: &App, mempool: &Mempool, call: &Call) -> Result<Value, (i64, String)> {
+    match call.method.as_str() {
+        "web3_clientVersion" => Ok(json!(format!("mchain/v{}", env!("CARGO_PKG_VERSION")))),
+        "net_version" => Ok(json!(chain_id().to_string())),
+        "eth_chainId" => Ok(json!(quantity(chain_id()))),
+        "eth_blockNumber" => Ok(json!(quantity(tip_height(app)?))),
+        "eth_getBlockByNumber" => {
+            let height = match param(call, 0)? {
+                Value::String(tag) => block_number(app, tag)?,
+                _ => return Err(invalid_params("block number must be a string")),
+            };
+            let block = app.get_by_height(height as usize).await.map_err(internal)?;
+            Ok(block.map_or(Value::Null, |block| {
+                encode_block(&block, full_transactions(call))
+            }))
+        }
+        "eth_getBlockByHash" => {
+            let hash = match param(call, 0)? {
+                Value::String(hash) => hash.trim_start_matches("0x").to_lowercase(),
+                _ => return Err(invalid_params("block hash must be a string")),
+            };
+            let block = app.get_by_hash(&hash).await.map_err(internal)?;
+            Ok(block.map_or(Value::Null, |block| {
+                encode_block(&block, full_transactions(call))
+            }))
+        }
+        "mchain_estimateFee" => Ok(json!(fees::estimate(app, mempool))),
+        method => Err((
+            METHOD_NOT_FOUND,
+            format!("method {} is not supported", method),
+        )),
+    }
+}
+
+fn param(call: &Call, index: usize) -> Result<&Value, (i64, String)> {
+    call.params
+        .get(index)
+        .ok_or_else(|| invalid_params(format!("missing parameter {}", index)))
+}
+
+// full_transactions is the second parameter of the eth_getBlockBy* methods, which asks for
+// the transactions themselves rather than their hashes.
+fn full_transactions(call: &Call) -> bool {
+    call.params.get(1).and_then(Value::as_bool).unwrap_or(false)
+}
+
+fn invalid_params(message: impl Into<String>) -> (i64, String) {
+    (INVALID_PARAMS, message.into())
+}
+
+fn internal(e: impl std::fmt::Display) -> (i64, String) {
+    (INTERNAL_ERROR, e.to_string())
+}
+
+// chain_id reads the mchain chain id, the hex prefix of the genesis hash, as a number.
+fn chain_id() -> u64 {
+    u64::from_str_radix(app::chain_id(), 16).unwrap_or_default()
+}
+
+fn tip_height(app: &App) -> Result<u64, (i64, String)> {
+    app.tip()
+        .map(|tip| tip.height as u64)
+        .ok_or_else(|| internal("the chain has no blocks yet"))
+}
+
+// block_number resolves a block tag or hex quantity to a height. Blocks are never final on
+// mchain, so only the tags that don't promise finality are supported.
+fn block_number(app: &App, tag: &str) -> Result<u64, (i64, String)> {
+    match tag {
+        "latest" | "pending" => tip_height(app),
+        "earliest" => Ok(0),
+        _ => tag
+            .strip_prefix("0x")
+            .and_then(|digits| u64::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| invalid_params(format!("invalid block number {:?}", tag))),
+    }
+}
+
+fn quantity(n: u64) -> String {
+    format!("{:#x}", n)
+}
+
+// hash prefixes an mchain hash with 0x, or returns the zero hash for those that aren't hex.
+fn hash(hash: &str) -> String {
+    if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        format!("0x{}", hash)
+    } else {
+        ZERO_HASH.to_string()
+    }
+}
+
+// address maps a peer id onto an Ethereum address, the last 20 bytes of its sha256, so
+// tools that expect one can tell senders apart.
+fn address(peer_id: &str) -> String {
+    let digest = Sha256::digest(peer_id.as_bytes());
+    format!("0x{}", hex::encode(&digest[12..]))
+}
+
+fn encode_block(block: &Block, full: bool) -> Value {
+    let miner = block
+        .transactions
+        .first()
+        .filter(|tx| tx.coinbase().is_some())
+        .map(|tx| address(&tx.sender))
+        .unwrap_or_else(|| address(""));
+    let transactions: Vec<Value> = block
+        .transactions
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| {
+            if full {
+                encode_transaction(block, index, tx)
+            } else {
+                json!(hash(&tx.id))
+            }
+        })
+        .collect();
+    let transactions_root = hex::encode(app::transactions_root(&block.transactions));
+    json!({
+        "number": quantity(block.height as u64),
+        "hash": hash(&block.hash),
+        "parentHash": hash(&block.previous_hash),
+        "nonce": format!("0x{:016x}", block.nonce),
+        "timestamp": quantity(block.timestamp as u64),
+        "size": quantity(miner::block_size(block) as u64),
+        "miner": miner,
+        "transactionsRoot": format!("0x{}", transactions_root),
+        "difficulty": "0x0",
+        "gasLimit": "0x0",
+        "gasUsed": "0x0",
+        "extraData": "0x",
+        "uncles": [],
+        "transactions": transactions,
+    })
+}
+
+// encode_transaction maps a transaction onto an Ethereum one: its fee is the gas price and
+// its payload the input, and it moves no value.
+fn encode_transaction(block: &Block, index: usize, tx: &Transaction) -> Value {
+    json!({
+        "hash": hash(&tx.id),
+        "nonce": quantity(tx.nonce),
+        "blockHash": hash(&block.hash),
+        "blockNumber": quantity(block.height as u64),
+        "transactionIndex": quantity(index as u64),
+        "from": address(&tx.sender),
+        "to": null,
+        "value": "0x0",
+        "gas": "0x0",
+        "gasPrice": quantity(tx.fee),
+        "input": format!("0x{}", hex::encode(&tx.payload)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::node::NodeBuilder;
+
+    fn call(method: &str) -> Call {
+        Call {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params: vec![],
+            id: Some(json!(1)),
+        }
+    }
+
+    #[async_std::test]
+    async fn fee_estimate_matches_the_fees_endpoint() {
+        let mut node = NodeBuilder::new().build().await.unwrap();
+        node.app.genesis().await.unwrap();
+        let response = handle(&node.app, &node.mempool, call("mchain_estimateFee")).await;
+        let expected = fees::estimate(&node.app, &node.mempool);
+        assert_eq!(response.result, Some(json!(expected)));
+
+        let response = handle(&node.app, &node.mempool, call("mchain_unknown")).await;
+        assert_eq!(
+            response.error.map(|error| error.code),
+            Some(METHOD_NOT_FOUND)
+        );
+    }
+}
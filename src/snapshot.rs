@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::app::{App, Block};
+use crate::storage;
+
+// Snapshots pin the chain for readers that page through it over several requests, e.g. to
+// export every block while new ones arrive. A snapshot holds the height and tip of the chain
+// when it was opened; reads through it stop at that height, and blocks a reorg replaces
+// below it are kept in memory, so they read the same chain from the first page to the last.
+
+// SNAPSHOT_TTL is how long a snapshot is kept after it was last read through.
+pub const SNAPSHOT_TTL: Duration = Duration::from_secs(300);
+
+// MAX_SNAPSHOTS bounds how many snapshots are open at once, since each may hold on to the
+// blocks reorgs replaced.
+pub const MAX_SNAPSHOTS: usize = 64;
+
+// Pin is what a reader is told about the snapshot it opened.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pin {
+    pub id: String,
+    // height is the number of blocks visible through the snapshot.
+    pub height: usize,
+    pub tip: Option<String>,
+}
+
+#[derive(Debug)]
+struct Snapshot {
+    pin: Pin,
+    // replaced holds the blocks of the pinned chain that a reorg has since replaced, by
+    // height.
+    replaced: BTreeMap<usize, Block>,
+    last_read: Instant,
+}
+
+// Snapshots holds the snapshots open on a chain.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    snapshots: HashMap<String, Snapshot>,
+    ids: RandomState,
+    next_id: u64,
+}
+
+impl Snapshots {
+    // open pins the chain as it is now, with `height` blocks ending at `tip`.
+    pub fn open(&mut self, height: usize, tip: Option<String>) -> Result<Pin, String> {
+        self.expire();
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(format!(
+                "{} snapshots are open already; close one or wait for it to expire",
+                MAX_SNAPSHOTS
+            ));
+        }
+        let mut hasher = self.ids.build_hasher();
+        hasher.write_u64(self.next_id);
+        self.next_id += 1;
+        let pin = Pin {
+            id: format!("{:016x}", hasher.finish()),
+            height,
+            tip,
+        };
+        let snapshot = Snapshot {
+            pin: pin.clone(),
+            replaced: BTreeMap::new(),
+            last_read: Instant::now(),
+        };
+        self.snapshots.insert(pin.id.clone(), snapshot);
+        Ok(pin)
+    }
+
+    // close releases a snapshot, returning whether it was open.
+    pub fn close(&mut self, id: &str) -> bool {
+        self.snapshots.remove(id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    // preserve keeps the blocks of the chain a reorg is about to replace, in chain order,
+    // for the snapshots that can still see them.
+    pub fn preserve(&mut self, replaced: &[Block]) {
+        for snapshot in self.snapshots.values_mut() {
+            for block in replaced.iter().filter(|b| b.height < snapshot.pin.height) {
+                snapshot
+                    .replaced
+                    .entry(block.height)
+                    .or_insert_with(|| block.clone());
+            }
+        }
+    }
+
+    // touch keeps the snapshot open for another SNAPSHOT_TTL, returning false if it expired.
+    fn touch(&mut self, id: &str) -> bool {
+        self.expire();
+        match self.snapshots.get_mut(id) {
+            Some(snapshot) => {
+                snapshot.last_read = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expire(&mut self) {
+        self.snapshots
+            .retain(|_, snapshot| snapshot.last_read.elapsed() < SNAPSHOT_TTL);
+    }
+}
+
+// View reads the chain either live or through a snapshot.
+pub struct View<'a> {
+    app: &'a App,
+    snapshot: Option<&'a Snapshot>,
+}
+
+impl<'a> View<'a> {
+    // live reads the chain as it is at the time of every read.
+    pub fn live(app: &'a App) -> Self {
+        Self {
+            app,
+            snapshot: None,
+        }
+    }
+
+    // pinned reads the chain through the snapshot with the given id, or returns None if
+    // there is no such snapshot or it expired.
+    pub fn pinned(app: &'a mut App, id: &str) -> Option<Self> {
+        if !app.snapshots.touch(id) {
+            return None;
+        }
+        let app = &*app;
+        Some(Self {
+            app,
+            snapshot: app.snapshots.snapshots.get(id),
+        })
+    }
+
+    // height returns the number of blocks visible through the view.
+    pub fn height(&self) -> usize {
+        self.snapshot
+            .map_or(self.app.height(), |snapshot| snapshot.pin.height)
+    }
+
+    // range returns the visible blocks with a height in start..end, in height order.
+    pub async fn range(&self, start: usize, end: usize) -> Result<Vec<Block>, storage::Error> {
+        let Some(snapshot) = self.snapshot else {
+            return self.app.range(start, end).await;
+        };
+        let end = end.min(snapshot.pin.height);
+        if start >= end {
+            return Ok(vec![]);
+        }
+        let mut blocks: BTreeMap<usize, Block> = self
+            .app
+            .range(start, end)
+            .await?
+            .into_iter()
+            .map(|block| (block.height, block))
+            .collect();
+        for (height, block) in snapshot.replaced.range(start..end) {
+            blocks.insert(*height, block.clone());
+        }
+        Ok(blocks.into_values().collect())
+    }
+}
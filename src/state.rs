@@ -0,0 +1,84 @@
+use crate::app::{App, Block};
+use crate::history::HISTORY_BATCH;
+use crate::storage;
+
+// HISTORY_BLOCKS is how many of the most recent heights nodes keep the history of their state
+// for, unless they are archival. Queries of the state as of an older height are left to
+// archival nodes.
+pub const HISTORY_BLOCKS: usize = 1024;
+
+// State is application state derived from the chain, like the name registry. It is built
+// by applying every block in chain order.
+pub trait State: Default {
+    fn apply(&mut self, block: &Block);
+
+    // prune forgets what is only needed to read the state as of the first `horizon` blocks
+    // of the chain, leaving the state as of later heights unchanged.
+    fn prune(&mut self, _horizon: usize) {}
+}
+
+// history_horizon returns the number of blocks below which a node no longer keeps the
+// history of its state: none for archival nodes, and all but the last HISTORY_BLOCKS
+// otherwise.
+pub fn history_horizon(app: &App, archival: bool) -> usize {
+    if archival {
+        0
+    } else {
+        app.height().saturating_sub(HISTORY_BLOCKS)
+    }
+}
+
+// Replay keeps a State up to date with the chain. It only applies the blocks added since the
+// last sync, and starts over from the genesis block if the chain was reorganized under it.
+#[derive(Debug, Default)]
+pub struct Replay<S> {
+    state: S,
+    // height and tip are the number of blocks applied so far and the hash of the last one.
+    height: usize,
+    tip: Option<String>,
+}
+
+impl<S: State> Replay<S> {
+    pub fn new() -> Self {
+        Self {
+            state: S::default(),
+            height: 0,
+            tip: None,
+        }
+    }
+
+    // sync applies the blocks added to the chain since the last call and returns the
+    // up-to-date state.
+    pub async fn sync(&mut self, app: &App) -> Result<&S, storage::Error> {
+        if let Some(tip) = &self.tip {
+            let block = app.get_by_height(self.height - 1).await?;
+            if block.is_none_or(|block| block.hash != *tip) {
+                log::info!("Chain was reorganized, replaying state from genesis");
+                *self = Self::new();
+            }
+        }
+
+        while self.height < app.height() {
+            let blocks = app.range(self.height, self.height + HISTORY_BATCH).await?;
+            if blocks.is_empty() {
+                break;
+            }
+            for block in &blocks {
+                // A gap in the stored chain holds the state back until it is repaired, as
+                // blocks have to be applied in order.
+                if block.height != self.height {
+                    return Ok(&self.state);
+                }
+                self.state.apply(block);
+                self.height = block.height + 1;
+                self.tip = Some(block.hash.clone());
+            }
+        }
+        Ok(&self.state)
+    }
+
+    // prune forgets the history of the state as of the first `horizon` blocks.
+    pub fn prune(&mut self, horizon: usize) {
+        self.state.prune(horizon);
+    }
+}
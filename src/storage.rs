@@ -0,0 +1,431 @@
+use async_std::channel::Receiver;
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    error::ErrorKind,
+    options::{
+        CreateCollectionOptions, FindOneOptions, FindOptions, IndexOptions, ReplaceOptions,
+        UpdateOptions,
+    },
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Mutex;
+
+use crate::app::Block;
+use crate::peers::{Ban, PeerRecord};
+use crate::rejects::{self, Reject};
+
+// Error is returned when the chain can't be read from or written to storage.
+#[derive(Debug)]
+pub enum Error {
+    Mongo(mongodb::error::Error),
+    // Other is for storage implementations outside this crate.
+    Other(Box<dyn error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Mongo(e) => write!(f, "mongodb: {}", e),
+            Error::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<mongodb::error::Error> for Error {
+    fn from(e: mongodb::error::Error) -> Self {
+        Error::Mongo(e)
+    }
+}
+
+// Storage persists the chain. App only keeps the most recent blocks in memory and reads
+// older ones back through this trait.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // put stores the block at its height, replacing any block already stored there.
+    async fn put(&self, block: &Block) -> Result<(), Error>;
+    async fn get_by_height(&self, height: usize) -> Result<Option<Block>, Error>;
+    async fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, Error>;
+    // range returns the stored blocks with a height in start..end, in height order.
+    async fn range(&self, start: usize, end: usize) -> Result<Vec<Block>, Error>;
+    // height returns the number of stored blocks, i.e. the height of the tip plus one.
+    async fn height(&self) -> Result<usize, Error>;
+    // truncate removes every block at or above the given height.
+    async fn truncate(&self, height: usize) -> Result<(), Error>;
+    // gaps returns the ranges of heights below the tip that have no block stored, in height
+    // order. A complete chain has none.
+    async fn gaps(&self) -> Result<Vec<Range<usize>>, Error>;
+}
+
+// gaps_in returns the ranges missing from the stored heights, given in ascending order.
+fn gaps_in(heights: impl IntoIterator<Item = usize>) -> Vec<Range<usize>> {
+    let mut gaps = vec![];
+    let mut next = 0;
+    for height in heights {
+        if height > next {
+            gaps.push(next..height);
+        }
+        next = height + 1;
+    }
+    gaps
+}
+
+// MemoryStorage keeps the chain in a map, for nodes that don't need to persist it.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    blocks: Mutex<BTreeMap<usize, Block>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, block: &Block) -> Result<(), Error> {
+        let mut blocks = self.blocks.lock().expect("lock is not poisoned");
+        blocks.insert(block.height, block.clone());
+        Ok(())
+    }
+
+    async fn get_by_height(&self, height: usize) -> Result<Option<Block>, Error> {
+        let blocks = self.blocks.lock().expect("lock is not poisoned");
+        Ok(blocks.get(&height).cloned())
+    }
+
+    // get_by_hash scans the chain, which is fine for the small chains this is meant for.
+    async fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, Error> {
+        let blocks = self.blocks.lock().expect("lock is not poisoned");
+        Ok(blocks.values().find(|block| block.hash == hash).cloned())
+    }
+
+    async fn range(&self, start: usize, end: usize) -> Result<Vec<Block>, Error> {
+        let blocks = self.blocks.lock().expect("lock is not poisoned");
+        Ok(blocks.range(start..end).map(|(_, b)| b.clone()).collect())
+    }
+
+    async fn height(&self) -> Result<usize, Error> {
+        let blocks = self.blocks.lock().expect("lock is not poisoned");
+        Ok(blocks.keys().next_back().map_or(0, |height| height + 1))
+    }
+
+    async fn truncate(&self, height: usize) -> Result<(), Error> {
+        let mut blocks = self.blocks.lock().expect("lock is not poisoned");
+        blocks.split_off(&height);
+        Ok(())
+    }
+
+    async fn gaps(&self) -> Result<Vec<Range<usize>>, Error> {
+        let blocks = self.blocks.lock().expect("lock is not poisoned");
+        Ok(gaps_in(blocks.keys().copied()))
+    }
+}
+
+// MongoStorage keeps the chain in a MongoDB collection with one document per block, indexed
+// by height and hash.
+#[derive(Debug, Clone)]
+pub struct MongoStorage {
+    blocks: Collection<Block>,
+}
+
+impl MongoStorage {
+    // new uses the "blocks" collection of the database, creating its indexes if needed.
+    pub async fn new(db: &Database) -> Result<Self, Error> {
+        let blocks = db.collection::<Block>("blocks");
+        let unique = IndexOptions::builder().unique(true).build();
+        blocks
+            .create_indexes(
+                vec![
+                    IndexModel::builder()
+                        .keys(doc! {"height": 1})
+                        .options(unique)
+                        .build(),
+                    IndexModel::builder().keys(doc! {"hash": 1}).build(),
+                ],
+                None,
+            )
+            .await?;
+        Ok(Self { blocks })
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    async fn put(&self, block: &Block) -> Result<(), Error> {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.blocks
+            .replace_one(doc! {"height": block.height as i64}, block, options)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_by_height(&self, height: usize) -> Result<Option<Block>, Error> {
+        Ok(self
+            .blocks
+            .find_one(doc! {"height": height as i64}, None)
+            .await?)
+    }
+
+    async fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, Error> {
+        Ok(self.blocks.find_one(doc! {"hash": hash}, None).await?)
+    }
+
+    async fn range(&self, start: usize, end: usize) -> Result<Vec<Block>, Error> {
+        let filter = doc! {"height": {"$gte": start as i64, "$lt": end as i64}};
+        let options = FindOptions::builder().sort(doc! {"height": 1}).build();
+        Ok(self
+            .blocks
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn height(&self) -> Result<usize, Error> {
+        let options = FindOneOptions::builder().sort(doc! {"height": -1}).build();
+        let tip = self.blocks.find_one(None, options).await?;
+        Ok(tip.map_or(0, |tip| tip.height + 1))
+    }
+
+    async fn truncate(&self, height: usize) -> Result<(), Error> {
+        self.blocks
+            .delete_many(doc! {"height": {"$gte": height as i64}}, None)
+            .await?;
+        Ok(())
+    }
+
+    // gaps only reads the heights, which the height index covers.
+    async fn gaps(&self) -> Result<Vec<Range<usize>>, Error> {
+        let options = FindOptions::builder()
+            .projection(doc! {"height": 1, "_id": 0})
+            .sort(doc! {"height": 1})
+            .build();
+        let heights: Vec<Document> = self
+            .blocks
+            .clone_with_type::<Document>()
+            .find(None, options)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(gaps_in(
+            heights
+                .iter()
+                .filter_map(|doc| doc.get_i64("height").ok())
+                .map(|height| height as usize),
+        ))
+    }
+}
+
+// MongoPeers persists what we know about peers to the "peers" collection, one document per
+// peer, so nodes sharing the database also share bans and reputations. Every node writes its
+// own fields of a document, so nodes saving at the same time don't undo each other.
+#[derive(Debug, Clone)]
+pub struct MongoPeers {
+    peers: Collection<StoredPeer>,
+    // node is the peer id of this node, which keys the score it gives every peer.
+    node: String,
+}
+
+// StoredPeer is the document of a peer: the score every node gives it, and the longest ban
+// any of them imposed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredPeer {
+    peer_id: String,
+    #[serde(default)]
+    scores: HashMap<String, i64>,
+    address: Option<String>,
+    last_seen: Option<i64>,
+    // ban lasts until PERMANENT if it never expires, so the longest ban is always the highest.
+    ban: Option<Ban>,
+}
+
+// PERMANENT is when a ban that never expires is stored to end.
+const PERMANENT: i64 = i64::MAX;
+
+impl MongoPeers {
+    pub async fn new(db: &Database, node: String) -> Result<Self, Error> {
+        let peers = db.collection::<StoredPeer>("peers");
+        let unique = IndexOptions::builder().unique(true).build();
+        peers
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"peer_id": 1})
+                    .options(unique)
+                    .build(),
+                None,
+            )
+            .await?;
+        // Older nodes stored a null ban, which the fields of a ban can't be set on.
+        peers
+            .update_many(doc! {"ban": null}, doc! {"$unset": {"ban": ""}}, None)
+            .await?;
+        Ok(Self { peers, node })
+    }
+
+    // load returns every peer, with the score this node gives it, or the lowest score another
+    // node gives it if this node has none.
+    pub async fn load(&self) -> Result<Vec<PeerRecord>, Error> {
+        let stored: Vec<StoredPeer> = self.peers.find(None, None).await?.try_collect().await?;
+        Ok(stored
+            .into_iter()
+            .map(|peer| PeerRecord {
+                score: match peer.scores.get(&self.node) {
+                    Some(score) => *score,
+                    None => peer.scores.values().copied().min().unwrap_or(0),
+                },
+                peer_id: peer.peer_id,
+                address: peer.address,
+                last_seen: peer.last_seen,
+                ban: peer.ban.map(|ban| Ban {
+                    until: ban.until.filter(|until| *until != PERMANENT),
+                    ..ban
+                }),
+            })
+            .collect())
+    }
+
+    // save writes this node's score of every peer, and extends the bans it imposed, leaving
+    // the scores and bans of the other nodes alone.
+    pub async fn save(&self, records: &[PeerRecord]) -> Result<(), Error> {
+        let options = UpdateOptions::builder().upsert(true).build();
+        for record in records {
+            let mut set = doc! { format!("scores.{}", self.node): record.score };
+            let mut max = Document::new();
+            if let Some(address) = &record.address {
+                set.insert("address", address);
+            }
+            if let Some(last_seen) = record.last_seen {
+                max.insert("last_seen", last_seen);
+            }
+            if let Some(ban) = &record.ban {
+                set.insert("ban.peer_id", &ban.peer_id);
+                set.insert("ban.reason", &ban.reason);
+                max.insert("ban.until", ban.until.unwrap_or(PERMANENT));
+            }
+            let mut update = doc! { "$set": set };
+            if !max.is_empty() {
+                update.insert("$max", max);
+            }
+            self.peers
+                .update_one(doc! {"peer_id": &record.peer_id}, update, options.clone())
+                .await?;
+        }
+        Ok(())
+    }
+
+    // unban lifts the ban on the peer for every node.
+    pub async fn unban(&self, peer_id: &str) -> Result<(), Error> {
+        self.peers
+            .update_one(
+                doc! {"peer_id": peer_id},
+                doc! {"$unset": {"ban": ""}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+// MAX_REJECTS_BYTES and MAX_REJECTS cap the "rejects" collection; MongoDB drops the oldest
+// documents to make room for new ones.
+const MAX_REJECTS_BYTES: u64 = 64 * 1024 * 1024;
+const MAX_REJECTS: u64 = 10_000;
+
+// NAMESPACE_EXISTS is the MongoDB error code for creating a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+fn already_exists(e: &mongodb::error::Error) -> bool {
+    matches!(&*e.kind, ErrorKind::Command(c) if c.code == NAMESPACE_EXISTS)
+}
+
+// MongoRejects persists rejected blocks and transactions to the capped "rejects" collection.
+#[derive(Debug, Clone)]
+pub struct MongoRejects {
+    rejects: Collection<Reject>,
+}
+
+impl MongoRejects {
+    // new creates the capped collection unless it already exists.
+    pub async fn new(db: &Database) -> Result<Self, Error> {
+        let options = CreateCollectionOptions::builder()
+            .capped(true)
+            .size(MAX_REJECTS_BYTES)
+            .max(MAX_REJECTS)
+            .build();
+        match db.create_collection("rejects", options).await {
+            Err(e) if !already_exists(&e) => return Err(e.into()),
+            _ => {}
+        }
+        Ok(Self {
+            rejects: db.collection::<Reject>("rejects"),
+        })
+    }
+
+    // persist stores the rejects as they are recorded, until the stream ends.
+    pub async fn persist(self, recorded: Receiver<Reject>) {
+        while let Ok(reject) = recorded.recv().await {
+            if let Err(e) = self.rejects.insert_one(&reject, None).await {
+                log::error!("could not persist reject of {}: {}", reject.id, e);
+            }
+        }
+    }
+
+    // list returns the rejects matching the query, most recent first.
+    pub async fn list(&self, query: &rejects::Query) -> Result<Vec<Reject>, Error> {
+        let mut filter = doc! {};
+        if let Some(kind) = &query.kind {
+            filter.insert("kind", kind);
+        }
+        if let Some(id) = &query.id {
+            filter.insert("id", id);
+        }
+        if let Some(sender) = &query.sender {
+            filter.insert("data.sender", sender);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! {"$natural": -1})
+            .limit(query.limit.min(rejects::MAX_LISTED) as i64)
+            .build();
+        Ok(self
+            .rejects
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_chain_has_no_gaps() {
+        assert_eq!(gaps_in([]), vec![]);
+        assert_eq!(gaps_in([0]), vec![]);
+        assert_eq!(gaps_in(0..5), vec![]);
+    }
+
+    #[test]
+    fn gaps_are_the_missing_ranges() {
+        assert_eq!(gaps_in([2]), vec![0..2]);
+        assert_eq!(gaps_in([0, 1, 4, 5, 9]), vec![2..4, 6..9]);
+        assert_eq!(gaps_in([1, 3]), vec![0..1, 2..3]);
+    }
+
+    #[test]
+    fn nothing_is_missing_past_the_tip() {
+        assert_eq!(gaps_in([0, 1, 2]).last(), None);
+        assert_eq!(gaps_in([0, 2]), vec![1..2]);
+    }
+}
@@ -0,0 +1,187 @@
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::app::Block;
+use crate::peers::PeerManager;
+use crate::telemetry::{Kind, Span, SpanContext};
+
+// RANGE_LIMIT is the maximum number of blocks requested from a peer at once.
+pub const RANGE_LIMIT: usize = 64;
+
+// MAX_PARALLEL_REQUESTS is how many ranges we download at the same time, each from a
+// different peer.
+pub const MAX_PARALLEL_REQUESTS: usize = 4;
+
+// REQUEST_TIMEOUT is how long we wait for a range response before trying another peer.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// InFlight is a range request that has been sent but not yet answered. Its span ends when
+// it is answered, times out or the peer goes away.
+#[derive(Debug)]
+struct InFlight {
+    peer: PeerId,
+    sent: Instant,
+    span: Span,
+}
+
+// Sync splits the blocks we are missing into ranges, downloads them from several peers at
+// once, and hands them back in chain order so they can be validated and applied.
+#[derive(Debug, Default)]
+pub struct Sync {
+    // in_flight maps the start of every requested range to the request.
+    in_flight: BTreeMap<usize, InFlight>,
+    // downloaded holds ranges that arrived before the ranges preceding them.
+    downloaded: BTreeMap<usize, (PeerId, Vec<Block>)>,
+}
+
+impl Sync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // next_requests assigns the missing ranges after `height` to the best idle peers that
+    // have them, returning each peer along with the start of the range to request from it and
+    // the span of the request, which the peer continues when serving it.
+    pub fn next_requests(
+        &mut self,
+        peers: &PeerManager,
+        height: usize,
+    ) -> Vec<(PeerId, usize, SpanContext)> {
+        let busy: HashSet<PeerId> = self.in_flight.values().map(|req| req.peer).collect();
+        let idle = peers
+            .sync_peers(height, MAX_PARALLEL_REQUESTS + busy.len())
+            .into_iter()
+            .filter(|peer| !busy.contains(peer));
+
+        let mut requests = Vec::new();
+        let mut start = height;
+        for peer in idle {
+            if self.in_flight.len() >= MAX_PARALLEL_REQUESTS {
+                break;
+            }
+            start = self.next_missing(start);
+            let peer_height = peers.get(&peer).and_then(|info| info.height).unwrap_or(0);
+            if start >= peer_height {
+                continue;
+            }
+            let mut span = Span::start("sync.range", Kind::Client);
+            span.set("sync.peer", peer.to_string());
+            span.set("sync.start", start);
+            requests.push((peer, start, span.context()));
+            self.in_flight.insert(
+                start,
+                InFlight {
+                    peer,
+                    sent: Instant::now(),
+                    span,
+                },
+            );
+            start += RANGE_LIMIT;
+        }
+        requests
+    }
+
+    // next_missing returns the first range start at or after `start` that is neither being
+    // downloaded nor waiting to be applied.
+    fn next_missing(&self, mut start: usize) -> usize {
+        loop {
+            if self.in_flight.contains_key(&start) {
+                start += RANGE_LIMIT;
+            } else if let Some((_, blocks)) = self.downloaded.get(&start) {
+                start += blocks.len().max(1);
+            } else {
+                return start;
+            }
+        }
+    }
+
+    // complete marks the request to `peer` for the range at `start` as answered, returning
+    // false if we were not waiting on that peer for that range.
+    pub fn complete(&mut self, peer: &PeerId, start: usize) -> bool {
+        match self.in_flight.get(&start) {
+            Some(req) if req.peer == *peer => {
+                if let Some(req) = self.in_flight.remove(&start) {
+                    req.span.end();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // insert stores a downloaded range until every range before it has been applied.
+    pub fn insert(&mut self, peer: PeerId, start: usize, blocks: Vec<Block>) {
+        if !blocks.is_empty() {
+            self.downloaded.insert(start, (peer, blocks));
+        }
+    }
+
+    // pop_ready returns the downloaded blocks that directly follow `height`, along with the
+    // peer that sent them. Ranges that overlap blocks we already have are trimmed.
+    pub fn pop_ready(&mut self, height: usize) -> Option<(PeerId, Vec<Block>)> {
+        while let Some(entry) = self.downloaded.first_entry() {
+            let start = *entry.key();
+            if start > height {
+                return None;
+            }
+            let (peer, blocks) = entry.remove();
+            if start + blocks.len() > height {
+                return Some((peer, blocks[height - start..].to_vec()));
+            }
+        }
+        None
+    }
+
+    // reset drops every downloaded range, e.g. after discovering that they belong to a
+    // chain that diverges from ours.
+    pub fn reset(&mut self) {
+        self.downloaded.clear();
+    }
+
+    // expire drops the requests that have timed out and returns the peers that failed to
+    // answer them.
+    pub fn expire(&mut self) -> Vec<PeerId> {
+        let expired: Vec<usize> = self
+            .in_flight
+            .iter()
+            .filter(|(_, req)| req.sent.elapsed() >= REQUEST_TIMEOUT)
+            .map(|(start, _)| *start)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|start| self.in_flight.remove(&start))
+            .map(|mut req| {
+                req.span.fail("timed out");
+                req.span.end();
+                req.peer
+            })
+            .collect()
+    }
+
+    // in_flight returns how many range requests are waiting for an answer.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    // downloaded returns how many ranges are waiting for earlier ranges to be applied.
+    pub fn downloaded(&self) -> usize {
+        self.downloaded.len()
+    }
+
+    // forget drops the outstanding requests sent to a peer that went away.
+    pub fn forget(&mut self, peer: &PeerId) {
+        let gone: Vec<usize> = self
+            .in_flight
+            .iter()
+            .filter(|(_, req)| req.peer == *peer)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in gone {
+            if let Some(mut req) = self.in_flight.remove(&start) {
+                req.span.fail("peer went away");
+                req.span.end();
+            }
+        }
+    }
+}
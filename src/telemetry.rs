@@ -0,0 +1,487 @@
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+use prometheus::proto::{MetricFamily, MetricType};
+use serde_json::{json, Value};
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// MAX_PENDING_SPANS is how many finished spans are kept for the next export. Spans finished
+// while the buffer is full are dropped.
+const MAX_PENDING_SPANS: usize = 4096;
+
+// SCOPE names the instrumentation in what is exported.
+const SCOPE: &str = "mchain";
+
+// ENABLED is set once an exporter runs; until then spans are discarded when they end.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// FINISHED holds the spans that ended since the last export.
+static FINISHED: Lazy<Mutex<Vec<Span>>> = Lazy::new(|| Mutex::new(vec![]));
+
+// STARTED is when the process started, which is when the cumulative metrics started counting.
+static STARTED: Lazy<SystemTime> = Lazy::new(SystemTime::now);
+
+// IDS seeds the trace and span ids, which only need to be unique rather than unpredictable.
+static IDS: Lazy<RandomState> = Lazy::new(RandomState::new);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn random_u64() -> u64 {
+    let mut hasher = IDS.build_hasher();
+    hasher.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+// SpanContext identifies a span across nodes. It travels in the W3C traceparent format, in
+// the traceparent header of API requests and in the sync messages exchanged with peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+}
+
+impl SpanContext {
+    fn new(trace_id: [u8; 16]) -> Self {
+        Self {
+            trace_id,
+            span_id: random_u64().to_be_bytes(),
+        }
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-01",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id)
+        )
+    }
+
+    // parse reads a traceparent, returning None if it is malformed. Versions after 00 may
+    // append fields, which are ignored.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let [version, trace_id, span_id, flags, rest @ ..] = parts.as_slice() else {
+            return None;
+        };
+        let byte = |field: &str| field.len() == 2 && hex::decode(field).is_ok();
+        if !byte(version) || *version == "ff" || (*version == "00" && !rest.is_empty()) {
+            return None;
+        }
+        if !byte(flags) {
+            return None;
+        }
+        let trace_id: [u8; 16] = hex::decode(trace_id).ok()?.try_into().ok()?;
+        let span_id: [u8; 8] = hex::decode(span_id).ok()?.try_into().ok()?;
+        (trace_id != [0; 16] && span_id != [0; 8]).then_some(Self { trace_id, span_id })
+    }
+}
+
+// Kind tells a tracing backend whether a span serves a request, makes one, or neither.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+// Span is a timed operation, such as an API request or a range requested from a peer. It
+// is exported once it ends.
+#[derive(Debug)]
+pub struct Span {
+    context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: Kind,
+    start: SystemTime,
+    end: Option<SystemTime>,
+    attributes: Vec<(String, Value)>,
+    error: Option<String>,
+}
+
+impl Span {
+    // start begins a span of a new trace.
+    pub fn start(name: impl Into<String>, kind: Kind) -> Self {
+        let mut trace_id = [0; 16];
+        trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+        trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+        Self::with_context(SpanContext::new(trace_id), None, name, kind)
+    }
+
+    // child_of begins a span of the parent's trace, e.g. one started by a peer.
+    pub fn child_of(parent: &SpanContext, name: impl Into<String>, kind: Kind) -> Self {
+        let context = SpanContext::new(parent.trace_id);
+        Self::with_context(context, Some(parent.span_id), name, kind)
+    }
+
+    fn with_context(
+        context: SpanContext,
+        parent: Option<[u8; 8]>,
+        name: impl Into<String>,
+        kind: Kind,
+    ) -> Self {
+        Self {
+            context,
+            parent,
+            name: name.into(),
+            kind,
+            start: SystemTime::now(),
+            end: None,
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<Value>) {
+        self.attributes.push((key.to_string(), value.into()));
+    }
+
+    // fail marks the span as failed for the given reason.
+    pub fn fail(&mut self, reason: impl Into<String>) {
+        self.error = Some(reason.into());
+    }
+
+    // end finishes the span and queues it for export.
+    pub fn end(mut self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+        self.end = Some(SystemTime::now());
+        let mut finished = FINISHED
+            .lock()
+            .expect("finished spans lock is not poisoned");
+        if finished.len() < MAX_PENDING_SPANS {
+            finished.push(self);
+        } else {
+            log::debug!(
+                "Too many spans waiting to be exported, dropping {}",
+                self.name
+            );
+        }
+    }
+}
+
+// Exporter ships spans and metrics to a telemetry backend.
+#[async_trait]
+pub trait Exporter: Send + Sync {
+    async fn export(
+        &self,
+        spans: Vec<Span>,
+        metrics: Vec<MetricFamily>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+}
+
+// run exports the spans that ended and the current value of every metric each interval,
+// until the process exits.
+pub async fn run(exporter: impl Exporter, interval: Duration) {
+    Lazy::force(&STARTED);
+    ENABLED.store(true, Ordering::Relaxed);
+    loop {
+        async_std::task::sleep(interval).await;
+        let spans = std::mem::take(&mut *FINISHED.lock().expect("finished spans lock"));
+        if let Err(e) = exporter.export(spans, prometheus::gather()).await {
+            log::warn!("Could not export telemetry: {}", e);
+        }
+    }
+}
+
+// OtlpExporter sends spans and metrics to an OpenTelemetry collector, or a backend such as
+// Grafana Tempo or Jaeger, with OTLP over HTTP in its JSON encoding.
+pub struct OtlpExporter {
+    endpoint: String,
+    resource: Value,
+}
+
+impl OtlpExporter {
+    // new exports to the OTLP endpoint at `endpoint`, e.g. "http://localhost:4318", naming
+    // the node with `service_name` and its peer id.
+    pub fn new(endpoint: &str, service_name: &str, peer_id: &str) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            resource: json!({
+                "attributes": [
+                    attribute("service.name", &json!(service_name)),
+                    attribute("service.instance.id", &json!(peer_id)),
+                    attribute("service.version", &json!(env!("CARGO_PKG_VERSION"))),
+                ],
+            }),
+        }
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let res = surf::post(format!("{}{}", self.endpoint, path))
+            .body_json(body)?
+            .await?;
+        if !res.status().is_success() {
+            return Err(format!("{} answered {}", path, res.status()).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Exporter for OtlpExporter {
+    async fn export(
+        &self,
+        spans: Vec<Span>,
+        metrics: Vec<MetricFamily>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !spans.is_empty() {
+            let spans: Vec<Value> = spans.iter().map(encode_span).collect();
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": self.resource,
+                    "scopeSpans": [{ "scope": { "name": SCOPE }, "spans": spans }],
+                }],
+            });
+            self.post("/v1/traces", &body).await?;
+        }
+        let now = SystemTime::now();
+        let metrics: Vec<Value> = metrics
+            .iter()
+            .filter_map(|family| encode_metric(family, *STARTED, now))
+            .collect();
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": self.resource,
+                "scopeMetrics": [{ "scope": { "name": SCOPE }, "metrics": metrics }],
+            }],
+        });
+        self.post("/v1/metrics", &body).await
+    }
+}
+
+// OTLP encodes 64 bit integers as strings in JSON, so they survive parsers that read every
+// number as a double.
+fn nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_nanos().to_string()
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn encode_span(span: &Span) -> Value {
+    let attributes: Vec<Value> = span
+        .attributes
+        .iter()
+        .map(|(key, value)| attribute(key, value))
+        .collect();
+    let status = match &span.error {
+        Some(reason) => json!({ "code": 2, "message": reason }),
+        None => json!({ "code": 0 }),
+    };
+    json!({
+        "traceId": hex::encode(span.context.trace_id),
+        "spanId": hex::encode(span.context.span_id),
+        "parentSpanId": span.parent.map(hex::encode).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end.unwrap_or(span.start)),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+// encode_metric maps a Prometheus metric family onto an OTLP metric, counted since `start`:
+// counters become cumulative sums, gauges gauges and histograms histograms. Summaries, which
+// mchain doesn't use, are left out.
+fn encode_metric(family: &MetricFamily, start: SystemTime, now: SystemTime) -> Option<Value> {
+    let (start, now) = (nanos(start), nanos(now));
+    let points = family.get_metric().iter().map(|metric| {
+        let attributes: Vec<Value> = metric
+            .get_label()
+            .iter()
+            .map(|label| attribute(label.get_name(), &json!(label.get_value())))
+            .collect();
+        let mut point = json!({
+            "attributes": attributes,
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+        });
+        let fields = match family.get_field_type() {
+            MetricType::COUNTER => json!({ "asDouble": metric.get_counter().get_value() }),
+            MetricType::GAUGE => json!({ "asDouble": metric.get_gauge().get_value() }),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                // Prometheus counts cumulatively, OTLP per bucket, with a last bucket for
+                // everything above the highest bound.
+                let mut counts = vec![];
+                let mut bounds = vec![];
+                let mut below = 0;
+                for bucket in histogram.get_bucket() {
+                    if bucket.get_upper_bound().is_finite() {
+                        counts.push((bucket.get_cumulative_count() - below).to_string());
+                        bounds.push(bucket.get_upper_bound());
+                        below = bucket.get_cumulative_count();
+                    }
+                }
+                counts.push((histogram.get_sample_count() - below).to_string());
+                json!({
+                    "count": histogram.get_sample_count().to_string(),
+                    "sum": histogram.get_sample_sum(),
+                    "bucketCounts": counts,
+                    "explicitBounds": bounds,
+                })
+            }
+            _ => json!({}),
+        };
+        if let (Value::Object(point), Value::Object(fields)) = (&mut point, fields) {
+            point.extend(fields);
+        }
+        point
+    });
+    let points: Vec<Value> = points.collect();
+    let data = match family.get_field_type() {
+        MetricType::COUNTER => json!({
+            "sum": {
+                "dataPoints": points,
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+            },
+        }),
+        MetricType::GAUGE => json!({ "gauge": { "dataPoints": points } }),
+        MetricType::HISTOGRAM => json!({
+            "histogram": { "dataPoints": points, "aggregationTemporality": 2 },
+        }),
+        _ => return None,
+    };
+    let mut metric = json!({
+        "name": family.get_name(),
+        "description": family.get_help(),
+    });
+    if let (Value::Object(metric), Value::Object(data)) = (&mut metric, data) {
+        metric.extend(data);
+    }
+    Some(metric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+    use prometheus::{Histogram, HistogramOpts};
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn traceparent_round_trips() {
+        let context = SpanContext::parse(TRACEPARENT).unwrap();
+        assert_eq!(
+            hex::encode(context.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(hex::encode(context.span_id), "b7ad6b7169203331");
+        assert_eq!(context.traceparent(), TRACEPARENT);
+
+        let span = Span::child_of(&context, "child", Kind::Internal);
+        let child = SpanContext::parse(&span.context().traceparent()).unwrap();
+        assert_eq!(child, span.context());
+        assert_eq!(child.trace_id, context.trace_id);
+    }
+
+    #[test]
+    fn malformed_traceparents_are_refused() {
+        for traceparent in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "0-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+            "00-0af7651916cd43dd8448eb211c80319x-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-1",
+        ] {
+            assert_eq!(SpanContext::parse(traceparent), None, "{:?}", traceparent);
+        }
+        // Later versions may carry more fields.
+        let traceparent = "cc-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra";
+        assert!(SpanContext::parse(traceparent).is_some());
+    }
+
+    #[test]
+    fn span_is_encoded_as_otlp() {
+        let parent = SpanContext::parse(TRACEPARENT).unwrap();
+        let mut span = Span::child_of(&parent, "sync.range", Kind::Client);
+        span.context.span_id = [1, 2, 3, 4, 5, 6, 7, 8];
+        span.start = UNIX_EPOCH + Duration::from_millis(1500);
+        span.end = Some(UNIX_EPOCH + Duration::from_millis(2500));
+        span.set("peer", "12D3KooW");
+        span.set("blocks", 32);
+        span.set("ratio", 0.5);
+        span.set("complete", false);
+        span.fail("timed out");
+        assert_eq!(
+            encode_span(&span),
+            json!({
+                "traceId": "0af7651916cd43dd8448eb211c80319c",
+                "spanId": "0102030405060708",
+                "parentSpanId": "b7ad6b7169203331",
+                "name": "sync.range",
+                "kind": 3,
+                "startTimeUnixNano": "1500000000",
+                "endTimeUnixNano": "2500000000",
+                "attributes": [
+                    { "key": "peer", "value": { "stringValue": "12D3KooW" } },
+                    { "key": "blocks", "value": { "intValue": "32" } },
+                    { "key": "ratio", "value": { "doubleValue": 0.5 } },
+                    { "key": "complete", "value": { "boolValue": false } },
+                ],
+                "status": { "code": 2, "message": "timed out" },
+            })
+        );
+    }
+
+    #[test]
+    fn histogram_is_encoded_with_per_bucket_counts() {
+        let opts = HistogramOpts::new("latency_seconds", "How long it took")
+            .const_label("kind", "block")
+            .buckets(vec![0.1, 1.0]);
+        let histogram = Histogram::with_opts(opts).unwrap();
+        for value in [0.05, 0.5, 0.7, 3.0] {
+            histogram.observe(value);
+        }
+        let family = &histogram.collect()[0];
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let now = UNIX_EPOCH + Duration::from_secs(2);
+        assert_eq!(
+            encode_metric(family, start, now),
+            Some(json!({
+                "name": "latency_seconds",
+                "description": "How long it took",
+                "histogram": {
+                    "aggregationTemporality": 2,
+                    "dataPoints": [{
+                        "attributes": [
+                            { "key": "kind", "value": { "stringValue": "block" } },
+                        ],
+                        "startTimeUnixNano": "1000000000",
+                        "timeUnixNano": "2000000000",
+                        "count": "4",
+                        "sum": 4.25,
+                        "bucketCounts": ["1", "2", "1"],
+                        "explicitBounds": [0.1, 1.0],
+                    }],
+                },
+            }))
+        );
+    }
+}
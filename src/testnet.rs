@@ -0,0 +1,137 @@
+use async_std::task;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+use mchain::genesis::Genesis;
+
+// BOOTSTRAP_DELAY gives the first node time to start listening before the others dial it.
+const BOOTSTRAP_DELAY: Duration = Duration::from_secs(2);
+
+// POLL_INTERVAL is how often the launcher checks whether the nodes exited or it was told to
+// stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// Nodes holds the nodes that are still running, and kills them when dropped, so they don't
+// outlive the launcher when it fails or is stopped.
+struct Nodes(Vec<(u16, Child)>);
+
+impl Nodes {
+    // reap forgets the nodes that exited, reporting how.
+    fn reap(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut running = vec![];
+        for (i, mut child) in self.0.drain(..) {
+            match child.try_wait()? {
+                Some(status) => println!("Node {} exited: {}", i, status),
+                None => running.push((i, child)),
+            }
+        }
+        self.0 = running;
+        Ok(())
+    }
+}
+
+impl Drop for Nodes {
+    fn drop(&mut self) {
+        for (i, child) in &mut self.0 {
+            if child.kill().is_ok() {
+                let _ = child.wait();
+                println!("Stopped node {}", i);
+            }
+        }
+    }
+}
+
+// run starts `nodes` child nodes on consecutive peer and API ports, starting at `ports`,
+// all sharing a freshly written genesis file, with every node after the first bootstrapping
+// from it. With a seed, the genesis file is seeded with it and node i with "<seed>-<i>", so
+// every run gives the same genesis block and peer ids. It returns once they all exited, or
+// kills them and returns when interrupted or terminated.
+pub async fn run(
+    nodes: u16,
+    dir: &Path,
+    (p2p_port, api_port): (u16, u16),
+    mongo_uri: &str,
+    seed: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if p2p_port.checked_add(nodes).is_none() || api_port.checked_add(nodes).is_none() {
+        return Err(format!(
+            "not enough ports above {} for {} nodes",
+            p2p_port.min(api_port),
+            nodes
+        )
+        .into());
+    }
+    fs::create_dir_all(dir)?;
+    let genesis = dir.join("genesis.toml");
+    let params = Genesis {
+        seed: seed.map(str::to_string),
+        ..Genesis::default()
+    };
+    fs::write(&genesis, toml::to_string(&params)?)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    let exe = env::current_exe()?;
+    let bootstrap = format!("/ip4/127.0.0.1/tcp/{}", p2p_port);
+    let mut children = Nodes(vec![]);
+    for i in 0..nodes {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let node_dir = dir.join(format!("node-{}", i));
+        fs::create_dir_all(&node_dir)?;
+
+        let mut command = Command::new(&exe);
+        command
+            .arg("--listen")
+            .arg(format!("/ip4/127.0.0.1/tcp/{}", p2p_port + i))
+            .arg("--api-addr")
+            .arg(format!("127.0.0.1:{}", api_port + i))
+            .arg("--mongo-uri")
+            .arg(mongo_uri)
+            .arg("--mongo-db")
+            .arg(format!("testnet-{}", i))
+            .arg("--genesis")
+            .arg(&genesis)
+            .arg("--data-dir")
+            .arg(&node_dir)
+            .stdin(Stdio::null());
+        if let Some(seed) = seed {
+            command.arg("--seed").arg(format!("{}-{}", seed, i));
+        }
+        if i > 0 {
+            command.arg(&bootstrap);
+        }
+
+        let child = command
+            .spawn()
+            .map_err(|e| format!("could not start node {}: {}", i, e))?;
+        children.0.push((i, child));
+        println!(
+            "Started node {} with peer port {} and API http://127.0.0.1:{}",
+            i,
+            p2p_port + i,
+            api_port + i
+        );
+        if i == 0 {
+            task::sleep(BOOTSTRAP_DELAY).await;
+        }
+    }
+
+    while !children.0.is_empty() && !stop.load(Ordering::Relaxed) {
+        children.reap()?;
+        task::sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
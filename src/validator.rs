@@ -0,0 +1,54 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::app::Transaction;
+
+// PayloadValidator lets an application built on mchain veto transactions it does not
+// understand. Validators run when a transaction is admitted to the mempool and again for
+// every transaction of a block we validate, so a payload rejected here never makes it into
+// our chain.
+pub trait PayloadValidator: Send + Sync {
+    // validate returns the reason the transaction is rejected, if any.
+    fn validate(&self, tx: &Transaction) -> Result<(), String>;
+}
+
+impl<F> PayloadValidator for F
+where
+    F: Fn(&Transaction) -> Result<(), String> + Send + Sync,
+{
+    fn validate(&self, tx: &Transaction) -> Result<(), String> {
+        self(tx)
+    }
+}
+
+// Validators is the set of registered payload validators. It is cheap to clone so the
+// chain and the mempool can share the same set.
+#[derive(Clone, Default)]
+pub struct Validators {
+    validators: Vec<Arc<dyn PayloadValidator>>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<V: PayloadValidator + 'static>(&mut self, validator: V) {
+        self.validators.push(Arc::new(validator));
+    }
+
+    // check runs every validator in registration order and stops at the first rejection.
+    pub fn check(&self, tx: &Transaction) -> Result<(), String> {
+        self.validators
+            .iter()
+            .try_for_each(|validator| validator.validate(tx))
+    }
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validators")
+            .field("len", &self.validators.len())
+            .finish()
+    }
+}
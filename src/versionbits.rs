@@ -0,0 +1,419 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::app::Block;
+
+// Version bits let miners signal that they are ready for a change of the consensus rules,
+// such as a new payload type, before the change takes effect. Every deployment of a change
+// claims a bit of the block version. The chain is split into windows of blocks, and once
+// enough blocks of a window signal for a deployment, it locks in and becomes active a window
+// later, when every node enforcing it can count on most of the miners doing the same.
+
+// TOP_BITS marks a version as carrying signals, as opposed to blocks that predate them, whose
+// version is 0. TOP_MASK selects the bits it occupies.
+pub const TOP_BITS: u32 = 0x2000_0000;
+const TOP_MASK: u32 = 0xe000_0000;
+
+// MAX_BIT bounds the bits a deployment may claim, which leaves the top bits alone.
+pub const MAX_BIT: u8 = 28;
+
+// VERSIONED_HEADERS is the deployment that, once active, requires every block to carry the
+// top bits, which retires the header encoding of the blocks that predate version bits.
+pub const VERSIONED_HEADERS: &str = "versioned_headers";
+
+// is_versioned returns whether a block version carries signals.
+pub fn is_versioned(version: u32) -> bool {
+    version & TOP_MASK == TOP_BITS
+}
+
+// SIGNALED holds the deployments this node's miner signals readiness for.
+static SIGNALED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// signal sets the deployments the miner signals readiness for, by name.
+pub fn signal(names: &[String]) {
+    *SIGNALED.lock().expect("signaled lock is not poisoned") = names.iter().cloned().collect();
+}
+
+// Signaling holds the deployments of a network, as part of its genesis.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Signaling {
+    // window is how many blocks the signals are counted over.
+    pub window: usize,
+    pub deployments: Vec<Deployment>,
+}
+
+impl Default for Signaling {
+    fn default() -> Self {
+        Self {
+            window: 100,
+            deployments: vec![],
+        }
+    }
+}
+
+impl Signaling {
+    // check returns why the deployments can't be evaluated, if they can't.
+    pub fn check(&self) -> Result<(), String> {
+        if self.window == 0 {
+            return Err("the signaling window must hold at least one block".to_string());
+        }
+        let mut bits = HashSet::new();
+        let mut names = HashSet::new();
+        for deployment in &self.deployments {
+            if deployment.bit > MAX_BIT {
+                return Err(format!(
+                    "deployment {} uses bit {}, but the highest is {}",
+                    deployment.name, deployment.bit, MAX_BIT
+                ));
+            }
+            if !bits.insert(deployment.bit) || !names.insert(&deployment.name) {
+                return Err(format!(
+                    "deployment {} shares its name or bit with another",
+                    deployment.name
+                ));
+            }
+            if deployment.threshold == 0 || deployment.threshold > self.window {
+                return Err(format!(
+                    "the threshold of deployment {} must be between 1 and the window of {}",
+                    deployment.name, self.window
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Deployment is a change of the consensus rules that miners signal for with a bit of the
+// block version. Signals are counted from the first window starting at or after
+// start_height; a deployment that hasn't locked in by timeout_height fails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deployment {
+    pub name: String,
+    pub bit: u8,
+    pub start_height: usize,
+    pub timeout_height: usize,
+    // threshold is how many blocks of a window have to signal for the deployment to lock in.
+    pub threshold: usize,
+}
+
+impl Deployment {
+    // signals returns whether a block with the given version signals for the deployment.
+    pub fn signals(&self, version: u32) -> bool {
+        is_versioned(version) && version & (1 << self.bit) != 0
+    }
+
+    // next returns the state of the window starting at `start`, given the state of the
+    // window before it and how many of its blocks signaled.
+    fn next(&self, state: State, start: usize, signals: usize) -> State {
+        match state {
+            State::Defined if start >= self.timeout_height => State::Failed,
+            State::Defined if start >= self.start_height => State::Started,
+            State::Started if signals >= self.threshold => State::LockedIn,
+            State::Started if start >= self.timeout_height => State::Failed,
+            State::LockedIn => State::Active,
+            state => state,
+        }
+    }
+}
+
+// State is where a deployment is in its lifecycle. It only changes between windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    // Defined deployments haven't reached their start height yet.
+    Defined,
+    // Started deployments count signals.
+    Started,
+    // LockedIn deployments reached the threshold and become active with the next window.
+    LockedIn,
+    // Active deployments are enforced.
+    Active,
+    // Failed deployments timed out before locking in.
+    Failed,
+}
+
+// Status is where a deployment stands for the next block.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub name: String,
+    pub bit: u8,
+    pub state: State,
+    // since is the height of the first block in the current state.
+    pub since: usize,
+    // signals is how many blocks of the current window signaled so far, out of threshold.
+    pub signals: usize,
+    pub threshold: usize,
+    pub window: usize,
+}
+
+// Tracker follows the state of every deployment as blocks are appended to the chain.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    signaling: Signaling,
+    // states holds the states of the deployments in every window so far, starting with the
+    // window of the genesis block.
+    states: Vec<Vec<State>>,
+    // signals counts the blocks of the current window signaling for each deployment.
+    signals: Vec<usize>,
+}
+
+impl Tracker {
+    pub fn new(signaling: Signaling) -> Self {
+        let count = signaling.deployments.len();
+        Self {
+            signaling,
+            states: vec![vec![State::Defined; count]],
+            signals: vec![0; count],
+        }
+    }
+
+    // is_empty returns whether the network has no deployments to track.
+    pub fn is_empty(&self) -> bool {
+        self.signaling.deployments.is_empty()
+    }
+
+    // observe counts the signals of the next block of the chain, moving on to the states of
+    // the next window once the block completes one. It returns the deployments whose state
+    // changed, along with their new state.
+    pub fn observe(&mut self, block: &Block) -> Vec<(String, State)> {
+        for (deployment, signals) in self.signaling.deployments.iter().zip(&mut self.signals) {
+            if deployment.signals(block.version) {
+                *signals += 1;
+            }
+        }
+        let next_height = block.height + 1;
+        if !next_height.is_multiple_of(self.signaling.window) {
+            return vec![];
+        }
+        let current = self.states.last().expect("there is a window of states");
+        let next: Vec<State> = self
+            .signaling
+            .deployments
+            .iter()
+            .zip(current)
+            .zip(&self.signals)
+            .map(|((deployment, &state), &signals)| deployment.next(state, next_height, signals))
+            .collect();
+        let changed = (self.signaling.deployments.iter().zip(current).zip(&next))
+            .filter(|((_, before), after)| before != after)
+            .map(|((deployment, _), after)| (deployment.name.clone(), *after))
+            .collect();
+        self.states.push(next);
+        self.signals.iter_mut().for_each(|signals| *signals = 0);
+        changed
+    }
+
+    // statuses returns where every deployment stands for the next block.
+    pub fn statuses(&self) -> Vec<Status> {
+        let current = self.states.last().expect("there is a window of states");
+        self.signaling
+            .deployments
+            .iter()
+            .enumerate()
+            .map(|(i, deployment)| {
+                let unchanged = self
+                    .states
+                    .iter()
+                    .rev()
+                    .take_while(|states| states[i] == current[i])
+                    .count();
+                Status {
+                    name: deployment.name.clone(),
+                    bit: deployment.bit,
+                    state: current[i],
+                    since: (self.states.len() - unchanged) * self.signaling.window,
+                    signals: self.signals[i],
+                    threshold: deployment.threshold,
+                    window: self.signaling.window,
+                }
+            })
+            .collect()
+    }
+
+    // is_active returns whether the named deployment is enforced for the next block.
+    pub fn is_active(&self, name: &str) -> bool {
+        let current = self.states.last().expect("there is a window of states");
+        self.signaling
+            .deployments
+            .iter()
+            .zip(current)
+            .any(|(deployment, state)| deployment.name == name && *state == State::Active)
+    }
+
+    // version returns the version of the next block this node mines, which signals for the
+    // deployments it is ready for while they count signals or are locked in.
+    pub fn version(&self) -> u32 {
+        let signaled = SIGNALED.lock().expect("signaled lock is not poisoned");
+        let current = self.states.last().expect("there is a window of states");
+        self.signaling
+            .deployments
+            .iter()
+            .zip(current)
+            .filter(|(deployment, state)| {
+                matches!(state, State::Started | State::LockedIn)
+                    && signaled.contains(&deployment.name)
+            })
+            .fold(TOP_BITS, |version, (deployment, _)| {
+                version | (1 << deployment.bit)
+            })
+    }
+
+    // window_start returns the height of the first block of the window `height` is in.
+    pub fn window_start(&self, height: usize) -> usize {
+        height - height % self.signaling.window
+    }
+
+    // rewind forgets the blocks observed from `height` on, e.g. to follow another chain that
+    // branches off there. `before` holds the blocks from the start of the window up to
+    // `height`, which are counted again.
+    pub fn rewind(&mut self, height: usize, before: &[Block]) {
+        self.states.truncate(height / self.signaling.window + 1);
+        self.signals.iter_mut().for_each(|signals| *signals = 0);
+        for block in before {
+            self.observe(block);
+        }
+    }
+
+    // reset forgets every block observed, e.g. before observing the blocks of another chain.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.signaling.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: usize = 10;
+
+    fn tracker(start_height: usize, timeout_height: usize) -> Tracker {
+        Tracker::new(Signaling {
+            window: WINDOW,
+            deployments: vec![Deployment {
+                name: "names".to_string(),
+                bit: 1,
+                start_height,
+                timeout_height,
+                threshold: 8,
+            }],
+        })
+    }
+
+    // window observes the next window of blocks, `signaling` of which signal for the
+    // deployment, and returns the changes it caused.
+    fn window(tracker: &mut Tracker, signaling: usize) -> Vec<(String, State)> {
+        let start = (tracker.states.len() - 1) * WINDOW;
+        let mut changes = vec![];
+        for i in 0..WINDOW {
+            let version = if i < signaling { TOP_BITS | 1 << 1 } else { 0 };
+            let block = Block {
+                height: start + i,
+                hash: String::new(),
+                previous_hash: String::new(),
+                timestamp: 0,
+                transactions: vec![],
+                nonce: 0,
+                version,
+            };
+            changes.extend(tracker.observe(&block));
+        }
+        changes
+    }
+
+    fn state(tracker: &Tracker) -> State {
+        tracker.statuses()[0].state
+    }
+
+    #[test]
+    fn deployment_locks_in_then_activates() {
+        let mut tracker = tracker(10, 100);
+        assert_eq!(state(&tracker), State::Defined);
+        assert_eq!(
+            window(&mut tracker, 10),
+            vec![("names".into(), State::Started)]
+        );
+
+        // Signals before the deployment started don't count.
+        assert_eq!(window(&mut tracker, 7), vec![]);
+        assert_eq!(state(&tracker), State::Started);
+        assert_eq!(
+            window(&mut tracker, 8),
+            vec![("names".into(), State::LockedIn)]
+        );
+        assert!(!tracker.is_active("names"));
+
+        assert_eq!(
+            window(&mut tracker, 0),
+            vec![("names".into(), State::Active)]
+        );
+        assert!(tracker.is_active("names"));
+        assert_eq!(tracker.statuses()[0].since, 40);
+
+        assert_eq!(window(&mut tracker, 0), vec![]);
+        assert!(tracker.is_active("names"));
+    }
+
+    #[test]
+    fn deployment_fails_at_its_timeout() {
+        let mut tracker = tracker(0, 20);
+        assert_eq!(
+            window(&mut tracker, 0),
+            vec![("names".into(), State::Started)]
+        );
+        assert_eq!(
+            window(&mut tracker, 7),
+            vec![("names".into(), State::Failed)]
+        );
+        assert_eq!(window(&mut tracker, 10), vec![]);
+        assert_eq!(state(&tracker), State::Failed);
+    }
+
+    #[test]
+    fn deployment_that_times_out_before_it_starts_fails() {
+        let mut tracker = tracker(20, 10);
+        assert_eq!(
+            window(&mut tracker, 10),
+            vec![("names".into(), State::Failed)]
+        );
+    }
+
+    #[test]
+    fn signals_need_the_top_bits() {
+        let deployment = &tracker(0, 100).signaling.deployments[0];
+        assert!(deployment.signals(TOP_BITS | 1 << 1));
+        assert!(!deployment.signals(TOP_BITS | 1 << 2));
+        assert!(!deployment.signals(1 << 1));
+        assert!(!deployment.signals(0x4000_0000 | 1 << 1));
+    }
+
+    #[test]
+    fn reset_forgets_the_blocks_observed() {
+        let mut tracker = tracker(0, 100);
+        window(&mut tracker, 10);
+        window(&mut tracker, 10);
+        assert_eq!(state(&tracker), State::LockedIn);
+        tracker.reset();
+        assert_eq!(state(&tracker), State::Defined);
+        assert_eq!(tracker.statuses()[0].signals, 0);
+    }
+
+    #[test]
+    fn check_refuses_conflicting_deployments() {
+        let mut signaling = tracker(0, 100).signaling;
+        assert_eq!(signaling.check(), Ok(()));
+        signaling.deployments.push(signaling.deployments[0].clone());
+        assert!(signaling.check().is_err());
+        signaling.deployments[1].name = "other".to_string();
+        assert!(signaling.check().is_err());
+        signaling.deployments[1].bit = MAX_BIT + 1;
+        assert!(signaling.check().is_err());
+        signaling.deployments[1].bit = 2;
+        assert_eq!(signaling.check(), Ok(()));
+        signaling.deployments[1].threshold = WINDOW + 1;
+        assert!(signaling.check().is_err());
+    }
+}
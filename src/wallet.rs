@@ -0,0 +1,59 @@
+use bytes::Bytes;
+use libp2p::identity::Keypair;
+use libp2p::PeerId;
+
+use crate::app::{App, Transaction};
+use crate::mempool::Mempool;
+
+// Wallet builds the transactions of a single sender, picking the next unused nonce so several
+// transactions can be submitted in a row without replacing one another.
+#[derive(Debug)]
+pub struct Wallet {
+    // keys sign the transactions; the sender is their peer id.
+    keys: Keypair,
+    sender: String,
+}
+
+impl Wallet {
+    pub fn new(keys: Keypair) -> Self {
+        Self {
+            sender: PeerId::from(keys.public()).to_string(),
+            keys,
+        }
+    }
+
+    // next_nonce is one past the highest nonce the sender used, whether the transaction was
+    // mined or is still pending.
+    pub fn next_nonce(&self, app: &App, mempool: &Mempool) -> u64 {
+        let mined = app.nonces().highest(&self.sender);
+        let pending = mempool.highest_nonce(&self.sender);
+        mined.max(pending).map_or(0, |nonce| nonce + 1)
+    }
+
+    // build creates the sender's next transaction.
+    pub fn build(&self, app: &App, mempool: &Mempool, fee: u64, payload: Bytes) -> Transaction {
+        let nonce = self.next_nonce(app, mempool);
+        Transaction::new(&self.keys, nonce, fee, payload)
+    }
+
+    // build_batch creates the sender's next transactions, one per fee and payload, with
+    // consecutive nonces.
+    pub fn build_batch(
+        &self,
+        app: &App,
+        mempool: &Mempool,
+        items: Vec<(u64, Bytes)>,
+    ) -> Vec<Transaction> {
+        let next = self.next_nonce(app, mempool);
+        (next..)
+            .zip(items)
+            .map(|(nonce, (fee, payload))| Transaction::new(&self.keys, nonce, fee, payload))
+            .collect()
+    }
+
+    // replacement creates a transaction that takes the place of the sender's pending
+    // transaction with the same nonce, if the fee is high enough for the mempool to accept it.
+    pub fn replacement(&self, nonce: u64, fee: u64, payload: Bytes) -> Transaction {
+        Transaction::new(&self.keys, nonce, fee, payload)
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::prelude::*;
+use libp2p::floodsub::{FloodsubMessage, Topic};
+use libp2p::PeerId;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::p2p;
+
+// MAX_FILE_SIZE is the size at which the wire log is rotated.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+// MAX_ROTATED_FILES is how many rotated logs (wire.log.1, wire.log.2, ...) are kept.
+const MAX_ROTATED_FILES: usize = 5;
+
+// WIRE_LOG is the destination of --debug-wire, or None when the mode is off.
+static WIRE_LOG: Lazy<Mutex<Option<WireLog>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    In,
+    Out,
+}
+
+// Record is a single line of the wire log.
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    direction: Direction,
+    topics: Vec<&'a str>,
+    sender: String,
+    size: usize,
+    kind: &'static str,
+}
+
+// WireLog appends records to a file and rotates it once it grows past MAX_FILE_SIZE.
+struct WireLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl WireLog {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size + line.len() as u64 > MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // rotate shifts wire.log.N to wire.log.N+1, dropping the oldest, and starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..MAX_ROTATED_FILES).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+// enable starts dumping pubsub traffic to the file at `path`.
+pub fn enable(path: &Path) -> io::Result<()> {
+    let log = WireLog::open(path)?;
+    *WIRE_LOG.lock().expect("wire log lock is not poisoned") = Some(log);
+    log::info!("Dumping pubsub traffic to {}", path.display());
+    Ok(())
+}
+
+// rotate starts a new wire log file right away, returning false if the mode is off.
+pub fn rotate() -> io::Result<bool> {
+    let mut guard = WIRE_LOG.lock().expect("wire log lock is not poisoned");
+    match guard.as_mut() {
+        Some(log) => log.rotate().map(|_| true),
+        None => Ok(false),
+    }
+}
+
+// record_inbound logs a message received from a peer.
+pub fn record_inbound(message: &FloodsubMessage) {
+    record(
+        Direction::In,
+        &message.topics,
+        &message.source,
+        &message.data,
+    );
+}
+
+// record_outbound logs a message we are about to publish.
+pub fn record_outbound(topic: &Topic, data: &[u8]) {
+    record(
+        Direction::Out,
+        std::slice::from_ref(topic),
+        &p2p::PEER_ID,
+        data,
+    );
+}
+
+fn record(direction: Direction, topics: &[Topic], sender: &PeerId, data: &[u8]) {
+    let mut guard = WIRE_LOG.lock().expect("wire log lock is not poisoned");
+    let log = match guard.as_mut() {
+        Some(log) => log,
+        None => return,
+    };
+
+    let record = Record {
+        time: Utc::now().to_rfc3339(),
+        direction,
+        topics: topics.iter().map(|t| t.id()).collect(),
+        sender: sender.to_string(),
+        size: data.len(),
+        kind: topics
+            .first()
+            .map_or("unknown", |topic| p2p::message_kind(topic, data)),
+    };
+    let mut line = match serde_json::to_vec(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            log::error!("could not serialize wire record: {}", e);
+            return;
+        }
+    };
+    line.push(b'\n');
+    if let Err(e) = log.write(&line) {
+        log::error!("could not write wire log: {}", e);
+    }
+}